@@ -0,0 +1,93 @@
+// Hammers the global (single key) limiter from as many threads as the README load
+// has connections and compares the bucket stores. Prints a markdown table to paste
+// into the README, run it on a multi-core host.
+//
+//   cargo run --release -p gateway_core --example contention -- [threads] [checks per thread]
+use gateway_core::rate_limiter::{AlgorithmConfig, RateLimiter, bucket_store::StoreType};
+use std::{
+    sync::{
+        Arc, Barrier,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+fn run(name: &str, store: StoreType, threads: usize, checks: usize) {
+    // generous limits so most checks are allowed and write to the bucket
+    let limiter = RateLimiter::with_store(
+        AlgorithmConfig::Gcra {
+            burst: 100_000,
+            rate: 1_000_000,
+        },
+        store.build(),
+    );
+    let barrier = Arc::new(Barrier::new(threads + 1));
+    let allowed = Arc::new(AtomicU64::new(0));
+
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let limiter = limiter.clone();
+            let barrier = barrier.clone();
+            let allowed = allowed.clone();
+            thread::spawn(move || {
+                let mut latencies = Vec::with_capacity(checks);
+                barrier.wait();
+                for _ in 0..checks {
+                    let start = Instant::now();
+                    if limiter.check((), start).is_ok() {
+                        allowed.fetch_add(1, Ordering::Relaxed);
+                    }
+                    latencies.push(start.elapsed());
+                }
+                latencies
+            })
+        })
+        .collect();
+
+    barrier.wait();
+    let start = Instant::now();
+    let mut latencies: Vec<Duration> = handles
+        .into_iter()
+        .flat_map(|handle| handle.join().unwrap())
+        .collect();
+    let elapsed = start.elapsed();
+
+    latencies.sort_unstable();
+    let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p) as usize];
+    let total = threads * checks;
+
+    println!(
+        "| {name:<12} | {:>10.2}M/s | {:>9?} | {:>9?} | {:>9?} | {:>10} |",
+        total as f64 / elapsed.as_secs_f64() / 1e6,
+        percentile(0.50),
+        percentile(0.99),
+        latencies[latencies.len() - 1],
+        allowed.load(Ordering::Relaxed),
+    );
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let threads = args.next().and_then(|a| a.parse().ok()).unwrap_or(800);
+    let checks = args.next().and_then(|a| a.parse().ok()).unwrap_or(2_000);
+
+    let cores = thread::available_parallelism().map_or(1, |cores| cores.get());
+    if cores == 1 {
+        eprintln!("only one core: threads take turns, the numbers say nothing about contention");
+    }
+
+    println!("Setup: {threads} threads x {checks} checks on one key, {cores} cores");
+    println!();
+    println!("| Store        | Throughput    | p50       | p99       | max       | allowed    |");
+    println!("| ------------ | ------------- | --------- | --------- | --------- | ---------- |");
+    run("dashmap", StoreType::DashMap, threads, checks);
+    run("mutex", StoreType::Mutex, threads, checks);
+    run(
+        "sharded",
+        StoreType::Sharded { shards: 16 },
+        threads,
+        checks,
+    );
+    run("atomic_gcra", StoreType::AtomicGcra, threads, checks);
+}
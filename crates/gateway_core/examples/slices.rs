@@ -0,0 +1,91 @@
+// Compares the shared `check` path with per-worker budget slices on one hot key,
+// and how much of the budget each run actually admitted.
+//
+//   cargo run --release -p gateway_core --example slices -- [threads] [checks per thread]
+use gateway_core::rate_limiter::{AlgorithmConfig, RateLimiter, slices::SlicedLimiter};
+use std::{
+    sync::{
+        Arc, Barrier,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+const CAPACITY: u128 = 50_000;
+const REFILL_RATE: u128 = 2_000_000;
+
+fn run(name: &str, threads: usize, checks: usize, tolerance: Option<f64>) {
+    let shared = RateLimiter::new(AlgorithmConfig::TokenBucket {
+        burst: CAPACITY,
+        rate: REFILL_RATE,
+    });
+    let sliced = tolerance.map(|tolerance| {
+        SlicedLimiter::new(shared.clone(), threads, tolerance)
+            .unwrap()
+            .with_sync_interval(Duration::from_millis(10))
+    });
+    let barrier = Arc::new(Barrier::new(threads + 1));
+    let allowed = Arc::new(AtomicU64::new(0));
+
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let shared = shared.clone();
+            let mut worker = sliced.as_ref().map(|sliced| sliced.worker());
+            let barrier = barrier.clone();
+            let allowed = allowed.clone();
+            thread::spawn(move || {
+                let mut local = 0;
+                barrier.wait();
+                for _ in 0..checks {
+                    let now = Instant::now();
+                    let result = match worker.as_mut() {
+                        Some(worker) => worker.check((), now),
+                        None => shared.check((), now),
+                    };
+                    if result.is_ok() {
+                        local += 1;
+                    }
+                }
+                allowed.fetch_add(local, Ordering::Relaxed);
+            })
+        })
+        .collect();
+
+    // started before the release so no admitted check falls outside the window
+    let start = Instant::now();
+    barrier.wait();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    // the most a perfect limiter could have let through in that time
+    let total = (threads * checks) as f64;
+    let ideal = total.min(CAPACITY as f64 + REFILL_RATE as f64 * elapsed.as_secs_f64());
+    let allowed = allowed.load(Ordering::Relaxed) as f64;
+
+    println!(
+        "{name:<16} {:>12.0} checks/s   allowed {:>9}   {:>6.2}% of ideal",
+        total / elapsed.as_secs_f64(),
+        allowed,
+        allowed / ideal * 100.0,
+    );
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let threads = args.next().and_then(|a| a.parse().ok()).unwrap_or(12);
+    let checks = args.next().and_then(|a| a.parse().ok()).unwrap_or(200_000);
+
+    println!("{threads} threads x {checks} checks on one key, {CAPACITY} burst at {REFILL_RATE}/s");
+    run("check", threads, checks, None);
+    for tolerance in [0.01, 0.05, 0.2] {
+        run(
+            &format!("slices {:.0}%", tolerance * 100.0),
+            threads,
+            checks,
+            Some(tolerance),
+        );
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// Source of `now` for the limiters, swapped for a MockClock in tests so refill,
+// TTLs and retry_after can be checked without real sleeps
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    // wall-clock time since unix epoch, the portable time used by snapshots
+    fn unix_time(&self) -> Duration;
+}
+
+fn system_unix_time() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn unix_time(&self) -> Duration {
+        system_unix_time()
+    }
+}
+
+// tokio's view of time, tests that pause and auto-advance the runtime move the
+// limiters along with every sleep
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    fn unix_time(&self) -> Duration {
+        system_unix_time()
+    }
+}
+
+// Frozen clock that only moves when `advance` is called, clones share the same time
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    start: Instant,
+    start_unix: Duration,
+    elapsed_nanos: Arc<AtomicU64>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            start_unix: system_unix_time(),
+            elapsed_nanos: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    // starts at the given wall-clock time, for tests that depend on the calendar
+    pub fn at(unix_time: Duration) -> Self {
+        Self {
+            start_unix: unix_time,
+            ..Self::new()
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.elapsed_nanos
+            .fetch_add(by.as_nanos() as u64, Ordering::SeqCst);
+    }
+
+    fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.elapsed_nanos.load(Ordering::SeqCst))
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn unix_time(&self) -> Duration {
+        self.start_unix + self.elapsed()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn mock_clock_only_moves_on_advance() {
+        let clock = MockClock::new();
+        let shared = clock.clone();
+        let t0 = clock.now();
+        let wall0 = clock.unix_time();
+
+        assert_eq!(clock.now(), t0);
+
+        shared.advance(Duration::from_millis(1500));
+        assert_eq!(clock.now() - t0, Duration::from_millis(1500));
+        assert_eq!(clock.unix_time() - wall0, Duration::from_millis(1500));
+    }
+}
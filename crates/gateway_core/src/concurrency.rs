@@ -0,0 +1,7 @@
+pub mod adaptive_limiter;
+pub mod aimd;
+pub mod concurrency_limiter;
+pub mod gradient;
+pub mod limit_algorithm;
+pub use adaptive_limiter::{AdaptiveAlgorithmType, AdaptiveLimiter, AdaptivePermit};
+pub use concurrency_limiter::{ConcurrencyError, ConcurrencyLimiter, ConcurrencyPermit};
@@ -0,0 +1,191 @@
+use dashmap::DashMap;
+use std::{
+    hash::Hash,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::concurrency::{
+    aimd::Aimd,
+    concurrency_limiter::ConcurrencyError,
+    gradient::Gradient,
+    limit_algorithm::{LimitAlgorithm, Outcome, Sample},
+};
+
+#[derive(Clone)]
+pub enum AdaptiveAlgorithmType {
+    Aimd { latency_threshold: Duration },
+    Gradient,
+}
+
+struct UpstreamLimit {
+    algorithm: Box<dyn LimitAlgorithm>,
+    in_flight: usize,
+}
+
+// Learns the concurrency limit of every upstream (key) from the latency and
+// outcome of the calls made through it
+#[derive(Clone)]
+pub struct AdaptiveLimiter<K>
+where
+    K: Eq + Hash + Clone,
+{
+    upstreams: Arc<DashMap<K, UpstreamLimit>>,
+    algorithm: AdaptiveAlgorithmType,
+    initial_limit: usize,
+    min_limit: usize,
+    max_limit: usize,
+}
+
+// RAII permit for one upstream call, `release` feeds the sample back into the
+// algorithm while a plain drop (e.g. cancelled request) only frees the slot
+pub struct AdaptivePermit<K>
+where
+    K: Eq + Hash + Clone,
+{
+    upstreams: Arc<DashMap<K, UpstreamLimit>>,
+    key: K,
+    started: Instant,
+    in_flight: usize,
+    released: bool,
+}
+
+impl<K> AdaptiveLimiter<K>
+where
+    K: Eq + Hash + Clone,
+{
+    pub fn new(
+        algorithm: AdaptiveAlgorithmType,
+        initial_limit: usize,
+        min_limit: usize,
+        max_limit: usize,
+    ) -> Self {
+        Self {
+            upstreams: Arc::new(DashMap::new()),
+            algorithm,
+            initial_limit,
+            min_limit,
+            max_limit,
+        }
+    }
+
+    pub fn limit(&self, key: &K) -> usize {
+        self.upstreams
+            .get(key)
+            .map(|upstream| upstream.algorithm.limit())
+            .unwrap_or(self.initial_limit)
+    }
+
+    pub fn in_flight(&self, key: &K) -> usize {
+        self.upstreams
+            .get(key)
+            .map(|upstream| upstream.in_flight)
+            .unwrap_or(0)
+    }
+
+    pub fn try_acquire(&self, key: K, now: Instant) -> Result<AdaptivePermit<K>, ConcurrencyError> {
+        let mut upstream = self
+            .upstreams
+            .entry(key.clone())
+            .or_insert_with(|| UpstreamLimit {
+                algorithm: match &self.algorithm {
+                    AdaptiveAlgorithmType::Aimd { latency_threshold } => Box::new(Aimd::new(
+                        self.initial_limit,
+                        self.min_limit,
+                        self.max_limit,
+                        *latency_threshold,
+                    ))
+                        as Box<dyn LimitAlgorithm>,
+                    AdaptiveAlgorithmType::Gradient => Box::new(Gradient::new(
+                        self.initial_limit,
+                        self.min_limit,
+                        self.max_limit,
+                    ))
+                        as Box<dyn LimitAlgorithm>,
+                },
+                in_flight: 0,
+            });
+
+        let limit = upstream.algorithm.limit();
+        if upstream.in_flight >= limit {
+            return Err(ConcurrencyError {
+                limit,
+                in_flight: upstream.in_flight,
+            });
+        }
+        upstream.in_flight += 1;
+
+        Ok(AdaptivePermit {
+            upstreams: self.upstreams.clone(),
+            key,
+            started: now,
+            in_flight: upstream.in_flight,
+            released: false,
+        })
+    }
+}
+
+impl<K> AdaptivePermit<K>
+where
+    K: Eq + Hash + Clone,
+{
+    pub fn release(mut self, outcome: Outcome, now: Instant) {
+        self.released = true;
+
+        if let Some(mut upstream) = self.upstreams.get_mut(&self.key) {
+            upstream.in_flight = upstream.in_flight.saturating_sub(1);
+            upstream.algorithm.update(Sample {
+                latency: now.saturating_duration_since(self.started),
+                in_flight: self.in_flight,
+                outcome,
+            });
+        }
+    }
+}
+
+impl<K> Drop for AdaptivePermit<K>
+where
+    K: Eq + Hash + Clone,
+{
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+        if let Some(mut upstream) = self.upstreams.get_mut(&self.key) {
+            upstream.in_flight = upstream.in_flight.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn sheds_above_learned_limit() {
+        let limiter = AdaptiveLimiter::new(
+            AdaptiveAlgorithmType::Aimd {
+                latency_threshold: Duration::from_millis(100),
+            },
+            2,
+            1,
+            10,
+        );
+        let t0 = Instant::now();
+
+        let a = limiter.try_acquire("upstream", t0).unwrap();
+        let b = limiter.try_acquire("upstream", t0).unwrap();
+        assert!(limiter.try_acquire("upstream", t0).is_err());
+
+        // slow call -> limit backs off to 1
+        a.release(Outcome::Success, t0 + Duration::from_secs(2));
+        assert_eq!(limiter.limit(&"upstream"), 1);
+        assert!(limiter.try_acquire("upstream", t0).is_err());
+
+        // cancelled call frees the slot without touching the limit
+        drop(b);
+        assert_eq!(limiter.in_flight(&"upstream"), 0);
+        assert_eq!(limiter.limit(&"upstream"), 1);
+        assert!(limiter.try_acquire("upstream", t0).is_ok());
+    }
+}
@@ -0,0 +1,96 @@
+use std::time::Duration;
+
+use crate::concurrency::limit_algorithm::{LimitAlgorithm, Outcome, Sample};
+
+// Additive increase / multiplicative decrease -> grow by one while the limit is in use,
+// back off by `backoff_ratio` on errors or when latency crosses the threshold
+#[derive(Clone)]
+pub struct Aimd {
+    limit: usize,
+    min_limit: usize,
+    max_limit: usize,
+    backoff_ratio: f64,
+    latency_threshold: Duration,
+}
+
+impl LimitAlgorithm for Aimd {
+    fn limit(&self) -> usize {
+        self.limit
+    }
+    fn update(&mut self, sample: Sample) -> usize {
+        Aimd::update(self, sample)
+    }
+}
+
+impl Aimd {
+    pub fn new(
+        initial_limit: usize,
+        min_limit: usize,
+        max_limit: usize,
+        latency_threshold: Duration,
+    ) -> Self {
+        Self {
+            limit: initial_limit.clamp(min_limit, max_limit),
+            min_limit,
+            max_limit,
+            backoff_ratio: 0.9,
+            latency_threshold,
+        }
+    }
+
+    pub fn with_backoff_ratio(mut self, backoff_ratio: f64) -> Self {
+        self.backoff_ratio = backoff_ratio;
+        self
+    }
+
+    pub fn update(&mut self, sample: Sample) -> usize {
+        let overloaded =
+            sample.outcome == Outcome::Dropped || sample.latency > self.latency_threshold;
+
+        if overloaded {
+            let backed_off = (self.limit as f64 * self.backoff_ratio).floor() as usize;
+            self.limit = backed_off.max(self.min_limit);
+        } else if sample.in_flight * 2 >= self.limit {
+            // only grow when the current limit is actually being used
+            self.limit = (self.limit + 1).min(self.max_limit);
+        }
+
+        self.limit
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    fn sample(latency_ms: u64, in_flight: usize, outcome: Outcome) -> Sample {
+        Sample {
+            latency: Duration::from_millis(latency_ms),
+            in_flight,
+            outcome,
+        }
+    }
+
+    #[test]
+    pub fn grows_additively_under_load() {
+        let mut aimd = Aimd::new(10, 1, 12, Duration::from_millis(100));
+
+        assert_eq!(aimd.update(sample(10, 10, Outcome::Success)), 11);
+        assert_eq!(aimd.update(sample(10, 10, Outcome::Success)), 12);
+        // capped at max
+        assert_eq!(aimd.update(sample(10, 12, Outcome::Success)), 12);
+        // idle -> no growth
+        let mut idle = Aimd::new(10, 1, 100, Duration::from_millis(100));
+        assert_eq!(idle.update(sample(10, 1, Outcome::Success)), 10);
+    }
+
+    #[test]
+    pub fn backs_off_on_drop_and_slow_samples() {
+        let mut aimd = Aimd::new(20, 5, 100, Duration::from_millis(100)).with_backoff_ratio(0.5);
+
+        assert_eq!(aimd.update(sample(10, 20, Outcome::Dropped)), 10);
+        assert_eq!(aimd.update(sample(500, 10, Outcome::Success)), 5);
+        // never below min
+        assert_eq!(aimd.update(sample(10, 5, Outcome::Dropped)), 5);
+    }
+}
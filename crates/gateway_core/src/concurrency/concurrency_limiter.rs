@@ -0,0 +1,107 @@
+use dashmap::DashMap;
+use std::{hash::Hash, sync::Arc};
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ConcurrencyError {
+    pub limit: usize,
+    pub in_flight: usize,
+}
+
+// Caps the number of in-flight requests per key, unlike the rate limiters this
+// protects against slow requests piling up
+#[derive(Clone)]
+pub struct ConcurrencyLimiter<K>
+where
+    K: Eq + Hash + Clone,
+{
+    in_flight: Arc<DashMap<K, usize>>,
+    max_in_flight: usize,
+}
+
+// RAII permit, the in-flight slot is released on drop
+pub struct ConcurrencyPermit<K>
+where
+    K: Eq + Hash + Clone,
+{
+    in_flight: Arc<DashMap<K, usize>>,
+    key: K,
+}
+
+impl<K> ConcurrencyLimiter<K>
+where
+    K: Eq + Hash + Clone,
+{
+    pub fn new(max_in_flight: usize) -> Self {
+        Self {
+            in_flight: Arc::new(DashMap::new()),
+            max_in_flight,
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.max_in_flight
+    }
+
+    pub fn in_flight(&self, key: &K) -> usize {
+        self.in_flight.get(key).map(|count| *count).unwrap_or(0)
+    }
+
+    pub fn try_acquire(&self, key: K) -> Result<ConcurrencyPermit<K>, ConcurrencyError> {
+        let mut count = self.in_flight.entry(key.clone()).or_insert(0);
+        if *count >= self.max_in_flight {
+            return Err(ConcurrencyError {
+                limit: self.max_in_flight,
+                in_flight: *count,
+            });
+        }
+        *count += 1;
+
+        Ok(ConcurrencyPermit {
+            in_flight: self.in_flight.clone(),
+            key,
+        })
+    }
+}
+
+impl<K> Drop for ConcurrencyPermit<K>
+where
+    K: Eq + Hash + Clone,
+{
+    fn drop(&mut self) {
+        if let Some(mut count) = self.in_flight.get_mut(&self.key) {
+            *count = count.saturating_sub(1);
+        }
+        // idle keys don't keep an entry around
+        self.in_flight.remove_if(&self.key, |_, count| *count == 0);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn caps_in_flight_per_key() {
+        let limiter = ConcurrencyLimiter::new(2);
+
+        let a1 = limiter.try_acquire("a").unwrap();
+        let a2 = limiter.try_acquire("a").unwrap();
+        assert_eq!(
+            limiter.try_acquire("a").err(),
+            Some(ConcurrencyError {
+                limit: 2,
+                in_flight: 2
+            })
+        );
+        assert!(limiter.try_acquire("b").is_ok());
+
+        drop(a1);
+        assert_eq!(limiter.in_flight(&"a"), 1);
+        let a3 = limiter.try_acquire("a").unwrap();
+        assert_eq!(limiter.in_flight(&"a"), 2);
+
+        drop(a2);
+        drop(a3);
+        assert_eq!(limiter.in_flight(&"a"), 0);
+    }
+}
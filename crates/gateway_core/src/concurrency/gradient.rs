@@ -0,0 +1,122 @@
+use crate::concurrency::limit_algorithm::{LimitAlgorithm, Outcome, Sample};
+
+// Gradient limiter -> compares a slow moving average of latency against the latest
+// sample, the ratio shrinks the limit as soon as the upstream starts queueing
+#[derive(Clone)]
+pub struct Gradient {
+    estimated_limit: f64,
+    min_limit: usize,
+    max_limit: usize,
+    smoothing: f64,
+    // long term latency average in seconds, None until the first sample
+    long_rtt: Option<f64>,
+    long_window: f64,
+}
+
+impl LimitAlgorithm for Gradient {
+    fn limit(&self) -> usize {
+        Gradient::limit(self)
+    }
+    fn update(&mut self, sample: Sample) -> usize {
+        Gradient::update(self, sample)
+    }
+}
+
+impl Gradient {
+    pub fn new(initial_limit: usize, min_limit: usize, max_limit: usize) -> Self {
+        Self {
+            estimated_limit: initial_limit.clamp(min_limit, max_limit) as f64,
+            min_limit,
+            max_limit,
+            smoothing: 0.2,
+            long_rtt: None,
+            long_window: 600.0,
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.estimated_limit as usize
+    }
+
+    pub fn update(&mut self, sample: Sample) -> usize {
+        let rtt = sample.latency.as_secs_f64().max(f64::EPSILON);
+
+        let long_rtt = match self.long_rtt {
+            Some(long) => long + (rtt - long) / self.long_window,
+            None => rtt,
+        };
+        self.long_rtt = Some(long_rtt);
+
+        // don't grow when the limit isn't being used, the samples say nothing about it
+        if sample.outcome == Outcome::Success
+            && (sample.in_flight as f64) < self.estimated_limit / 2.0
+        {
+            return self.limit();
+        }
+
+        let gradient = match sample.outcome {
+            Outcome::Dropped => 0.5,
+            Outcome::Success => (long_rtt / rtt).clamp(0.5, 1.0),
+        };
+
+        // headroom so the limit can keep probing upwards
+        let queue_size = self.estimated_limit.sqrt();
+        let new_limit = self.estimated_limit * gradient + queue_size;
+
+        self.estimated_limit = (self.estimated_limit * (1.0 - self.smoothing)
+            + new_limit * self.smoothing)
+            .clamp(self.min_limit as f64, self.max_limit as f64);
+
+        self.limit()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn sample(latency_ms: u64, in_flight: usize, outcome: Outcome) -> Sample {
+        Sample {
+            latency: Duration::from_millis(latency_ms),
+            in_flight,
+            outcome,
+        }
+    }
+
+    #[test]
+    pub fn grows_while_latency_is_stable() {
+        let mut gradient = Gradient::new(10, 1, 1000);
+        for _ in 0..20 {
+            gradient.update(sample(50, gradient.limit(), Outcome::Success));
+        }
+        assert!(gradient.limit() > 10);
+    }
+
+    #[test]
+    pub fn shrinks_when_latency_rises() {
+        let mut gradient = Gradient::new(100, 1, 1000);
+        for _ in 0..50 {
+            gradient.update(sample(50, 100, Outcome::Success));
+        }
+        let before = gradient.limit();
+
+        for _ in 0..20 {
+            gradient.update(sample(1700, before, Outcome::Success));
+        }
+        assert!(gradient.limit() < before);
+    }
+
+    #[test]
+    pub fn respects_bounds() {
+        let mut gradient = Gradient::new(10, 5, 20);
+        for _ in 0..100 {
+            gradient.update(sample(10, 20, Outcome::Dropped));
+        }
+        assert_eq!(gradient.limit(), 5);
+        for _ in 0..1000 {
+            gradient.update(sample(10, 20, Outcome::Success));
+        }
+        assert_eq!(gradient.limit(), 20);
+    }
+}
@@ -0,0 +1,21 @@
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Success,
+    // upstream error, timeout or overload response
+    Dropped,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub latency: Duration,
+    // in-flight count when the request started, including itself
+    pub in_flight: usize,
+    pub outcome: Outcome,
+}
+
+pub trait LimitAlgorithm: Send + Sync {
+    fn limit(&self) -> usize;
+    fn update(&mut self, sample: Sample) -> usize;
+}
@@ -0,0 +1,6 @@
+pub mod key_extractor;
+pub mod key_template;
+pub mod rate_limit_layer;
+pub use key_extractor::{GlobalKey, KeyExtractor};
+pub use key_template::{KeyContext, KeyTemplate, TemplateError};
+pub use rate_limit_layer::{DeniedResponse, RateLimitLayer, RateLimitService, TooManyRequests};
@@ -0,0 +1,30 @@
+use http::Request;
+
+// Picks the limiter key of a request, None lets the request through unlimited
+pub trait KeyExtractor<B>: Send + Sync {
+    type Key;
+    fn extract(&self, request: &Request<B>) -> Option<Self::Key>;
+}
+
+impl<B, K, F> KeyExtractor<B> for F
+where
+    F: Fn(&Request<B>) -> Option<K> + Send + Sync,
+{
+    type Key = K;
+
+    fn extract(&self, request: &Request<B>) -> Option<K> {
+        self(request)
+    }
+}
+
+// every request shares one budget
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GlobalKey;
+
+impl<B> KeyExtractor<B> for GlobalKey {
+    type Key = ();
+
+    fn extract(&self, request: &Request<B>) -> Option<()> {
+        Some(())
+    }
+}
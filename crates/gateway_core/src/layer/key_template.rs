@@ -0,0 +1,296 @@
+use http::{HeaderMap, HeaderName, Method, Request};
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use crate::layer::key_extractor::KeyExtractor;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplateError {
+    Empty,
+    Unclosed(String),
+    UnknownSource(String),
+    InvalidHeader(String),
+}
+
+// where a placeholder takes its value from
+#[derive(Debug, Clone, PartialEq)]
+enum Source {
+    Ip,
+    Route,
+    Method,
+    Path,
+    Header(HeaderName),
+    Query(String),
+    // quoted fallback, e.g. {query:tenant|"public"}
+    Literal(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    // the first source that has a value wins
+    Value(Vec<Source>),
+}
+
+// A rate limit key built from the request, e.g. "{route}:{method}" or
+// "{header:x-api-key|ip}". Every placeholder lists one or more sources
+// separated by `|`, later ones are the fallback when earlier ones are missing
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyTemplate {
+    raw: String,
+    parts: Vec<Part>,
+}
+
+// What a template can read from a request. `route` is the request path unless the
+// caller knows better (e.g. the gateway's route name) and the ip is only known
+// when set or put into the request extensions as an IpAddr
+pub struct KeyContext<'a> {
+    ip: Option<IpAddr>,
+    route: &'a str,
+    path: &'a str,
+    method: &'a Method,
+    headers: &'a HeaderMap,
+    query: Option<&'a str>,
+}
+
+impl<'a> KeyContext<'a> {
+    pub fn new<B>(request: &'a Request<B>) -> Self {
+        Self {
+            ip: request.extensions().get::<IpAddr>().copied(),
+            route: request.uri().path(),
+            path: request.uri().path(),
+            method: request.method(),
+            headers: request.headers(),
+            query: request.uri().query(),
+        }
+    }
+
+    pub fn with_ip(mut self, ip: IpAddr) -> Self {
+        self.ip = Some(ip);
+        self
+    }
+
+    pub fn with_route(mut self, route: &'a str) -> Self {
+        self.route = route;
+        self
+    }
+
+    // e.g. the full path when a router has already stripped a prefix
+    pub fn with_path(mut self, path: &'a str) -> Self {
+        self.path = path;
+        self
+    }
+
+    // tagged with its source, so a header or query value can never pass for
+    // the client address or another source's value
+    fn value(&self, source: &Source) -> Option<String> {
+        match source {
+            Source::Ip => self.ip.map(|ip| format!("ip:{}", ip.to_canonical())),
+            Source::Route => Some(format!("route:{}", self.route)),
+            Source::Method => Some(format!("method:{}", self.method)),
+            Source::Path => Some(format!("path:{}", self.path)),
+            Source::Header(name) => self
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(|value| format!("h:{name}:{value}")),
+            Source::Query(name) => form_urlencoded::parse(self.query?.as_bytes())
+                .find(|(key, value)| key == name && !value.is_empty())
+                .map(|(_, value)| format!("q:{name}:{value}")),
+            // set by the operator, not the client
+            Source::Literal(value) => Some(value.clone()),
+        }
+    }
+}
+
+impl KeyTemplate {
+    // None when a placeholder has no value from any of its sources
+    pub fn render(&self, context: &KeyContext) -> Option<String> {
+        let mut key = String::new();
+
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => key.push_str(literal),
+                Part::Value(sources) => {
+                    let value = sources.iter().find_map(|source| context.value(source))?;
+                    key.push_str(&value);
+                }
+            }
+        }
+
+        Some(key)
+    }
+
+    // headers and query values can be credentials, e.g. an api key
+    pub fn reads_headers_or_query(&self) -> bool {
+        self.parts.iter().any(|part| match part {
+            Part::Literal(_) => false,
+            Part::Value(sources) => sources
+                .iter()
+                .any(|source| matches!(source, Source::Header(_) | Source::Query(_))),
+        })
+    }
+}
+
+impl FromStr for KeyTemplate {
+    type Err = TemplateError;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut rest = raw.trim();
+        if rest.is_empty() {
+            return Err(TemplateError::Empty);
+        }
+
+        while !rest.is_empty() {
+            let Some(start) = rest.find('{') else {
+                parts.push(Part::Literal(rest.to_string()));
+                break;
+            };
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| TemplateError::Unclosed(raw.to_string()))?;
+            let sources = rest[start + 1..start + end]
+                .split('|')
+                .map(parse_source)
+                .collect::<Result<_, _>>()?;
+            parts.push(Part::Value(sources));
+            rest = &rest[start + end + 1..];
+        }
+
+        Ok(Self {
+            raw: raw.trim().to_string(),
+            parts,
+        })
+    }
+}
+
+fn parse_source(raw: &str) -> Result<Source, TemplateError> {
+    let raw = raw.trim();
+    if let Some(literal) = raw.strip_prefix('"').and_then(|raw| raw.strip_suffix('"')) {
+        return Ok(Source::Literal(literal.to_string()));
+    }
+
+    match raw.split_once(':') {
+        Some(("header", name)) => HeaderName::from_str(name.trim())
+            .map(Source::Header)
+            .map_err(|_| TemplateError::InvalidHeader(name.to_string())),
+        Some(("query", name)) if !name.trim().is_empty() => {
+            Ok(Source::Query(name.trim().to_string()))
+        }
+        None if raw == "ip" => Ok(Source::Ip),
+        None if raw == "route" => Ok(Source::Route),
+        None if raw == "method" => Ok(Source::Method),
+        None if raw == "path" => Ok(Source::Path),
+        _ => Err(TemplateError::UnknownSource(raw.to_string())),
+    }
+}
+
+impl fmt::Display for KeyTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.raw)
+    }
+}
+
+// requests the template has no value for go through unlimited
+impl<B> KeyExtractor<B> for KeyTemplate {
+    type Key = String;
+
+    fn extract(&self, request: &Request<B>) -> Option<String> {
+        self.render(&KeyContext::new(request))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    fn request(uri: &str, api_key: Option<&str>) -> Request<()> {
+        let mut builder = Request::post(uri);
+        if let Some(api_key) = api_key {
+            builder = builder.header("x-api-key", api_key);
+        }
+        builder.body(()).unwrap()
+    }
+
+    fn render(template: &str, request: &Request<()>) -> Option<String> {
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let context = KeyContext::new(request).with_ip(ip).with_route("search");
+        template.parse::<KeyTemplate>().unwrap().render(&context)
+    }
+
+    #[test]
+    pub fn renders_sources_and_fallbacks() {
+        let keyed = request("/api/search/items?tenant=acme%20inc&page=2", Some("k1"));
+        let anonymous = request("/api/search/items?page=2", None);
+
+        assert_eq!(render("{ip}", &keyed).unwrap(), "ip:203.0.113.7");
+        assert_eq!(
+            render("{route}/{method}", &keyed).unwrap(),
+            "route:search/method:POST"
+        );
+        assert_eq!(
+            render("tenant/{query:tenant}/{path}", &keyed).unwrap(),
+            "tenant/q:tenant:acme inc/path:/api/search/items"
+        );
+        assert_eq!(
+            render("{header:X-Api-Key}", &keyed).unwrap(),
+            "h:x-api-key:k1"
+        );
+
+        // missing values fall through to the next source
+        assert_eq!(
+            render("{header:x-api-key|ip}", &anonymous).unwrap(),
+            "ip:203.0.113.7"
+        );
+        assert_eq!(
+            render("{query:tenant|\"public\"}/{route}", &anonymous).unwrap(),
+            "public/route:search"
+        );
+
+        // a header carrying an address is still a header value
+        let spoofed = request("/api/search", Some("203.0.113.7"));
+        assert_eq!(
+            render("{header:x-api-key|ip}", &spoofed).unwrap(),
+            "h:x-api-key:203.0.113.7"
+        );
+        assert_eq!(render("{header:x-api-key}", &anonymous), None);
+
+        // without a known ip there is nothing to key on
+        let template: KeyTemplate = "{ip}".parse().unwrap();
+        assert_eq!(template.render(&KeyContext::new(&anonymous)), None);
+    }
+
+    #[test]
+    pub fn knows_when_it_reads_headers_or_query() {
+        let reads = |raw: &str| raw.parse::<KeyTemplate>().unwrap().reads_headers_or_query();
+
+        assert!(!reads("{route}:{method}"));
+        assert!(!reads("{ip|\"anonymous\"}:{path}"));
+        assert!(reads("{header:x-api-key|ip}"));
+        assert!(reads("tenant-{query:tenant}"));
+    }
+
+    #[test]
+    pub fn rejects_bad_templates() {
+        assert_eq!("".parse::<KeyTemplate>(), Err(TemplateError::Empty));
+        assert!(matches!(
+            "{ip".parse::<KeyTemplate>(),
+            Err(TemplateError::Unclosed(_))
+        ));
+        assert!(matches!(
+            "{cookie:session}".parse::<KeyTemplate>(),
+            Err(TemplateError::UnknownSource(_))
+        ));
+        assert!(matches!(
+            "{header:bad header}".parse::<KeyTemplate>(),
+            Err(TemplateError::InvalidHeader(_))
+        ));
+        assert!("{query:}".parse::<KeyTemplate>().is_err());
+    }
+}
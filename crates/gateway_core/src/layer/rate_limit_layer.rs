@@ -0,0 +1,284 @@
+use http::{HeaderMap, HeaderValue, Request, Response, StatusCode, header::RETRY_AFTER};
+use std::{
+    future::Future,
+    hash::Hash,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tower::{Layer, Service};
+
+use crate::{
+    layer::key_extractor::KeyExtractor,
+    rate_limiter::{
+        RateLimiter,
+        algorithm::BucketState,
+        rate_limiter::{RateLimitError, RateLimitErrorKind},
+    },
+};
+
+// Builds the response for a request the limiter turned down
+pub trait DeniedResponse<B>: Send + Sync {
+    fn response(&self, error: &RateLimitError) -> Response<B>;
+}
+
+impl<B, F> DeniedResponse<B> for F
+where
+    F: Fn(&RateLimitError) -> Response<B> + Send + Sync,
+{
+    fn response(&self, error: &RateLimitError) -> Response<B> {
+        self(error)
+    }
+}
+
+// 429 with Retry-After and the RateLimit headers, empty body. Denylisted keys get a
+// 403 and costs over the capacity a 413, neither with a Retry-After
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TooManyRequests;
+
+impl<B> DeniedResponse<B> for TooManyRequests
+where
+    B: Default,
+{
+    fn response(&self, error: &RateLimitError) -> Response<B> {
+        let mut response = Response::new(B::default());
+        let status = match error.kind {
+            RateLimitErrorKind::Blocked => StatusCode::FORBIDDEN,
+            RateLimitErrorKind::CostExceedsCapacity => StatusCode::PAYLOAD_TOO_LARGE,
+            _ => StatusCode::TOO_MANY_REQUESTS,
+        };
+        *response.status_mut() = status;
+
+        let headers = response.headers_mut();
+        attach_headers(headers, &error.snapshot);
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            // whole seconds, rounded up so clients never retry too early
+            let seconds = error.retry_after.as_millis().div_ceil(1000);
+            headers.insert(RETRY_AFTER, HeaderValue::from(seconds as u64));
+        }
+        response
+    }
+}
+
+pub fn attach_headers(headers: &mut HeaderMap, snapshot: &BucketState) {
+    headers.insert("ratelimit-limit", HeaderValue::from(snapshot.limit as u64));
+    headers.insert(
+        "ratelimit-remaining",
+        HeaderValue::from(snapshot.remaining as u64),
+    );
+    headers.insert(
+        "ratelimit-reset",
+        HeaderValue::from(snapshot.reset_after.as_secs()),
+    );
+}
+
+// Rate limits any tower service (axum, tonic, hyper) with a RateLimiter, the key
+// comes from `extractor` and denied requests never reach the inner service
+pub struct RateLimitLayer<K, E, D = TooManyRequests>
+where
+    K: Eq + Hash,
+{
+    limiter: RateLimiter<K>,
+    extractor: Arc<E>,
+    denied: Arc<D>,
+}
+
+impl<K, E> RateLimitLayer<K, E>
+where
+    K: Eq + Hash,
+{
+    pub fn new(limiter: RateLimiter<K>, extractor: E) -> Self {
+        Self {
+            limiter,
+            extractor: Arc::new(extractor),
+            denied: Arc::new(TooManyRequests),
+        }
+    }
+}
+
+impl<K, E, D> RateLimitLayer<K, E, D>
+where
+    K: Eq + Hash,
+{
+    pub fn with_denied_response<R>(self, denied: R) -> RateLimitLayer<K, E, R> {
+        RateLimitLayer {
+            limiter: self.limiter,
+            extractor: self.extractor,
+            denied: Arc::new(denied),
+        }
+    }
+}
+
+impl<K, E, D> Clone for RateLimitLayer<K, E, D>
+where
+    K: Eq + Hash + Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            limiter: self.limiter.clone(),
+            extractor: self.extractor.clone(),
+            denied: self.denied.clone(),
+        }
+    }
+}
+
+impl<S, K, E, D> Layer<S> for RateLimitLayer<K, E, D>
+where
+    K: Eq + Hash + Clone,
+{
+    type Service = RateLimitService<S, K, E, D>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            inner,
+            limiter: self.limiter.clone(),
+            extractor: self.extractor.clone(),
+            denied: self.denied.clone(),
+        }
+    }
+}
+
+pub struct RateLimitService<S, K, E, D>
+where
+    K: Eq + Hash,
+{
+    inner: S,
+    limiter: RateLimiter<K>,
+    extractor: Arc<E>,
+    denied: Arc<D>,
+}
+
+impl<S, K, E, D> Clone for RateLimitService<S, K, E, D>
+where
+    S: Clone,
+    K: Eq + Hash + Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            limiter: self.limiter.clone(),
+            extractor: self.extractor.clone(),
+            denied: self.denied.clone(),
+        }
+    }
+}
+
+impl<S, K, E, D, ReqBody, ResBody> Service<Request<ReqBody>> for RateLimitService<S, K, E, D>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+    S::Error: 'static,
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    E: KeyExtractor<ReqBody, Key = K>,
+    D: DeniedResponse<ResBody>,
+    ResBody: Send + 'static,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<ResBody>, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<ReqBody>) -> Self::Future {
+        let Some(key) = self.extractor.extract(&request) else {
+            return Box::pin(self.inner.call(request));
+        };
+
+        match self.limiter.check(key, self.limiter.clock.now()) {
+            Ok(snapshot) => {
+                let response = self.inner.call(request);
+                Box::pin(async move {
+                    let mut response = response.await?;
+                    attach_headers(response.headers_mut(), &snapshot);
+                    Ok(response)
+                })
+            }
+            Err(error) => {
+                let response = self.denied.response(&error);
+                Box::pin(async move { Ok(response) })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::{clock::MockClock, layer::key_extractor::GlobalKey, rate_limiter::AlgorithmConfig};
+    use std::{convert::Infallible, time::Duration};
+    use tower::{ServiceBuilder, ServiceExt, service_fn};
+
+    async fn upstream(request: Request<String>) -> Result<Response<String>, Infallible> {
+        Ok(Response::new("upstream".to_string()))
+    }
+
+    fn request(api_key: Option<&str>) -> Request<String> {
+        let mut builder = Request::get("/");
+        if let Some(api_key) = api_key {
+            builder = builder.header("x-api-key", api_key);
+        }
+        builder.body(String::new()).unwrap()
+    }
+
+    #[tokio::test]
+    pub async fn denies_once_the_budget_is_spent() {
+        let clock = Arc::new(MockClock::new());
+        let limiter = RateLimiter::new(AlgorithmConfig::TokenBucket { burst: 2, rate: 1 })
+            .with_clock(clock.clone());
+        let service = ServiceBuilder::new()
+            .layer(RateLimitLayer::new(limiter, GlobalKey))
+            .service(service_fn(upstream));
+
+        let response = service.clone().oneshot(request(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["ratelimit-remaining"], "1");
+        assert_eq!(response.into_body(), "upstream");
+
+        assert!(service.clone().oneshot(request(None)).await.is_ok());
+
+        let response = service.clone().oneshot(request(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["retry-after"], "1");
+        assert_eq!(response.headers()["ratelimit-remaining"], "0");
+        assert_eq!(response.into_body(), "");
+
+        clock.advance(Duration::from_secs(1));
+        let response = service.oneshot(request(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    pub async fn custom_key_and_denied_response() {
+        let limiter = RateLimiter::new(AlgorithmConfig::TokenBucket { burst: 1, rate: 1 })
+            .with_clock(Arc::new(MockClock::new()));
+        let api_key = |request: &Request<String>| {
+            let value = request.headers().get("x-api-key")?;
+            Some(value.to_str().ok()?.to_string())
+        };
+        let denied = |error: &RateLimitError| {
+            let mut response = Response::new(format!("slow down, {:?}", error.kind));
+            *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+            response
+        };
+        let service = ServiceBuilder::new()
+            .layer(RateLimitLayer::new(limiter, api_key).with_denied_response(denied))
+            .service(service_fn(upstream));
+
+        for key in ["a", "b"] {
+            let response = service.clone().oneshot(request(Some(key))).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = service.clone().oneshot(request(Some("a"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.into_body(), "slow down, Limited");
+
+        // no key, no limit
+        for _ in 0..3 {
+            let response = service.clone().oneshot(request(None)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(response.headers().get("ratelimit-limit").is_none());
+        }
+    }
+}
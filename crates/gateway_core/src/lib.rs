@@ -1,2 +1,6 @@
 #![allow(dead_code, unused_variables, unused)]
+pub mod clock;
+pub mod concurrency;
+#[cfg(feature = "tower_layer")]
+pub mod layer;
 pub mod rate_limiter;
@@ -1,8 +1,32 @@
+pub mod acquire;
 pub mod algorithm;
+pub mod algorithm_config;
+pub mod atomic_gcra;
+pub mod atomic_store;
+pub mod bucket_store;
+pub mod composite;
+pub mod dashmap_store;
+pub mod fixed_window;
+pub mod gcra;
+pub mod heavy_hitters;
+pub mod key_limit;
+pub mod mutex_store;
+pub mod overrides;
+pub mod quota;
+#[allow(clippy::module_inception)]
 pub mod rate_limiter;
+#[cfg(feature = "redis_store")]
+pub mod redis_store;
+pub mod shaper;
+pub mod sharded_store;
+pub mod slices;
 pub mod sliding_counter;
 pub mod sliding_log;
+pub mod snapshot;
 pub mod token_bucket;
+pub use algorithm_config::AlgorithmConfig;
+pub use fixed_window::FixedWindow;
+pub use gcra::Gcra;
 pub use rate_limiter::RateLimiter;
 pub use token_bucket::TokenBucket;
 
@@ -14,3 +38,9 @@ pub type DefaultAlgorithm = SlidingLog;
 
 #[cfg(feature = "sliding_counter")]
 pub type DefaultAlgorithm = SlidingCounter;
+
+#[cfg(feature = "gcra")]
+pub type DefaultAlgorithm = Gcra;
+
+#[cfg(feature = "fixed_window")]
+pub type DefaultAlgorithm = FixedWindow;
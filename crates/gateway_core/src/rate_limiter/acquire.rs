@@ -0,0 +1,285 @@
+use dashmap::DashMap;
+use std::{
+    hash::Hash,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+use crate::rate_limiter::{
+    RateLimiter,
+    algorithm::BucketState,
+    rate_limiter::{RateLimitError, RateLimitErrorKind},
+};
+
+// floor for a wait, a denial with a rounded down retry_after would otherwise spin
+const MIN_WAIT: Duration = Duration::from_millis(1);
+
+pub enum AcquireError {
+    // waiting can't help, e.g. the cost is over capacity or the key is blocked
+    Rejected(RateLimitError),
+    // the permit would only be ready after the deadline
+    DeadlineExceeded { retry_after: Duration },
+}
+
+// One fair lock per key with waiters. tokio's Mutex hands itself over in arrival
+// order, so whoever holds it is the head of the line and the only one checking
+pub struct WaitQueues<K>
+where
+    K: Eq + Hash,
+{
+    queues: Arc<DashMap<K, Arc<Mutex<()>>>>,
+}
+
+// a place in line, the head of the line holds `guard`. Dropping it (e.g. a
+// cancelled acquire) leaves the line and frees the queue once nobody waits
+pub struct Turn<K>
+where
+    K: Eq + Hash,
+{
+    queues: Arc<DashMap<K, Arc<Mutex<()>>>>,
+    key: K,
+    queue: Option<Arc<Mutex<()>>>,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl<K> WaitQueues<K>
+where
+    K: Eq + Hash,
+{
+    pub fn new() -> Self {
+        Self {
+            queues: Arc::new(DashMap::new()),
+        }
+    }
+}
+
+impl<K> WaitQueues<K>
+where
+    K: Eq + Hash + Clone,
+{
+    // callers waiting on or holding the key's line
+    pub fn waiting(&self, key: &K) -> usize {
+        self.queues
+            .get(key)
+            .map_or(0, |queue| Arc::strong_count(&queue) - 1)
+    }
+
+    pub fn join(&self, key: K) -> Turn<K> {
+        let queue = self.queues.entry(key.clone()).or_default().clone();
+
+        Turn {
+            queues: self.queues.clone(),
+            key,
+            queue: Some(queue),
+            guard: None,
+        }
+    }
+}
+
+impl<K> Default for WaitQueues<K>
+where
+    K: Eq + Hash,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K> Clone for WaitQueues<K>
+where
+    K: Eq + Hash,
+{
+    fn clone(&self) -> Self {
+        Self {
+            queues: self.queues.clone(),
+        }
+    }
+}
+
+impl<K> Turn<K>
+where
+    K: Eq + Hash,
+{
+    // resolves once everyone who joined earlier has left the line
+    pub async fn wait(&mut self) {
+        // the queue moves into the guard, every caller holds it exactly once
+        if let Some(queue) = self.queue.take() {
+            self.guard = Some(queue.lock_owned().await);
+        }
+    }
+}
+
+impl<K> Drop for Turn<K>
+where
+    K: Eq + Hash,
+{
+    fn drop(&mut self) {
+        self.guard.take();
+        self.queue.take();
+        // only the map holds the queue -> nobody else is in line. A cancelled
+        // wait has dropped its copy with the lock future already
+        self.queues
+            .remove_if(&self.key, |_, queue| Arc::strong_count(queue) == 1);
+    }
+}
+
+impl<K> RateLimiter<K>
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+{
+    pub async fn acquire(&self, key: K) -> Result<BucketState, AcquireError> {
+        self.acquire_with_cost(key, 1, None).await
+    }
+
+    // Sleeps until the bucket allows `cost`, callers on the same key get their permits
+    // in the order they called in. With a deadline it gives up as soon as the permit
+    // can't be ready in time. Dropping the future cancels the wait and leaves the line
+    pub async fn acquire_with_cost(
+        &self,
+        key: K,
+        cost: u128,
+        deadline: Option<Instant>,
+    ) -> Result<BucketState, AcquireError> {
+        let mut turn = self.waiters.join(key.clone());
+
+        match deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(self.clock.now());
+                // still behind others at the deadline, their costs are unknown
+                if tokio::time::timeout(left, turn.wait()).await.is_err() {
+                    return Err(AcquireError::DeadlineExceeded {
+                        retry_after: Duration::ZERO,
+                    });
+                }
+            }
+            None => turn.wait().await,
+        }
+
+        loop {
+            let now = self.clock.now();
+            let err = match self.check_with_cost(key.clone(), now, cost) {
+                Ok(state) => return Ok(state),
+                Err(err) => err,
+            };
+
+            match err.kind {
+                RateLimitErrorKind::Limited | RateLimitErrorKind::StoreUnavailable => {}
+                _ => return Err(AcquireError::Rejected(err)),
+            }
+
+            if let Some(deadline) = deadline
+                && now
+                    .checked_add(err.retry_after)
+                    .is_none_or(|ready| ready > deadline)
+            {
+                return Err(AcquireError::DeadlineExceeded {
+                    retry_after: err.retry_after,
+                });
+            }
+
+            tokio::time::sleep(err.retry_after.max(MIN_WAIT)).await;
+        }
+    }
+
+    pub fn waiting(&self, key: &K) -> usize {
+        self.waiters.waiting(key)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::{
+        clock::{Clock, TokioClock},
+        rate_limiter::AlgorithmConfig,
+    };
+    use std::{future::poll_fn, task::Poll};
+
+    fn limiter(capacity: u128, refill_rate: u128) -> RateLimiter<&'static str> {
+        RateLimiter::new(AlgorithmConfig::TokenBucket {
+            burst: capacity,
+            rate: refill_rate,
+        })
+        .with_clock(Arc::new(TokioClock))
+    }
+
+    #[tokio::test(start_paused = true)]
+    pub async fn waits_for_the_refill() {
+        let limiter = limiter(2, 10);
+        let start = tokio::time::Instant::now();
+
+        for _ in 0..5 {
+            assert!(limiter.acquire("key").await.is_ok());
+        }
+        // 2 from the burst, 3 refilled at 100ms each
+        assert_eq!(start.elapsed(), Duration::from_millis(300));
+        assert_eq!(limiter.waiting(&"key"), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    pub async fn waiters_are_served_in_order() {
+        let limiter = limiter(1, 1);
+        let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+        assert!(limiter.acquire("key").await.is_ok());
+
+        let mut waiters = Vec::new();
+        for i in 0..4 {
+            let limiter = limiter.clone();
+            let order = order.clone();
+            waiters.push(tokio::spawn(async move {
+                limiter.acquire("key").await.ok().unwrap();
+                order.lock().unwrap().push(i);
+            }));
+            // let the waiter join the line before the next one
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(limiter.waiting(&"key"), 4);
+
+        for waiter in waiters {
+            waiter.await.unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), vec![0, 1, 2, 3]);
+        assert_eq!(limiter.waiting(&"key"), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    pub async fn deadline_and_cancellation() {
+        let limiter = limiter(1, 1);
+        assert!(limiter.acquire("key").await.is_ok());
+
+        let soon = TokioClock.now() + Duration::from_millis(500);
+        match limiter.acquire_with_cost("key", 1, Some(soon)).await {
+            Err(AcquireError::DeadlineExceeded { retry_after }) => {
+                assert_eq!(retry_after, Duration::from_secs(1))
+            }
+            _ => panic!("expected the deadline to be exceeded"),
+        }
+
+        // cancelled waiters leave the line, the next one isn't held up
+        let mut head = Box::pin(limiter.acquire("key"));
+        let mut behind = Box::pin(limiter.acquire("key"));
+        poll_fn(|cx| {
+            assert!(head.as_mut().poll(cx).is_pending());
+            assert!(behind.as_mut().poll(cx).is_pending());
+            Poll::Ready(())
+        })
+        .await;
+        assert_eq!(limiter.waiting(&"key"), 2);
+        drop(behind);
+        assert_eq!(limiter.waiting(&"key"), 1);
+        drop(head);
+        assert_eq!(limiter.waiting(&"key"), 0);
+
+        let start = tokio::time::Instant::now();
+        assert!(limiter.acquire("key").await.is_ok());
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+
+        match limiter.acquire_with_cost("key", 2, None).await {
+            Err(AcquireError::Rejected(err)) => {
+                assert_eq!(err.kind, RateLimitErrorKind::CostExceedsCapacity)
+            }
+            _ => panic!("expected a rejection"),
+        }
+    }
+}
@@ -1,8 +1,11 @@
+use serde::{Deserialize, Serialize};
 use std::time::{Duration, Instant};
 
 pub enum AllowResult {
     Allowed,
     Denied { retry_after: Duration },
+    // cost is bigger than the whole bucket so waiting will never help
+    CostExceedsCapacity { cost: u128, capacity: u128 },
 }
 
 //adding a snapshot
@@ -12,9 +15,45 @@ pub struct BucketState {
     pub reset_after: Duration,
 }
 
+// Instant-free copy of a bucket for snapshots, every timestamp is kept as its
+// age (or, for GCRA, its lead) relative to when the snapshot was taken
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SavedBucket {
+    TokenBucket {
+        tokens: u128,
+        since_refill: Duration,
+    },
+    SlidingLog {
+        entry_ages: Vec<Duration>,
+    },
+    SlidingCounter {
+        since_window_start: Duration,
+        current: u128,
+        previous: u128,
+    },
+    Gcra {
+        tat_ahead: Duration,
+    },
+    FixedWindow {
+        window_index: u128,
+        count: u128,
+    },
+}
+
 pub trait RateLimitAlgorithm: Send + Sync {
-    fn allow(&mut self, now: Instant) -> AllowResult;
+    fn allow(&mut self, now: Instant) -> AllowResult {
+        self.allow_n(now, 1)
+    }
+    fn allow_n(&mut self, now: Instant, cost: u128) -> AllowResult;
     fn state(&self, now: Instant) -> BucketState;
+    // state as of `now` with pending refill and expiry applied, takes nothing
+    fn peek(&self, now: Instant) -> BucketState;
     fn last_seen(&self) -> Instant;
     fn set_last_seen(&mut self, now: Instant);
+    fn save(&self, now: Instant) -> SavedBucket;
+    // `taken_at` is the snapshot time mapped onto this process's clock,
+    // false when the saved state doesn't belong to this algorithm
+    fn load(&mut self, saved: &SavedBucket, taken_at: Instant) -> bool;
+    // gives back `cost` units charged by an allow_n that has to be undone
+    fn refund(&mut self, now: Instant, cost: u128);
 }
@@ -0,0 +1,283 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::rate_limiter::rate_limiter::AlgorithmType;
+
+// A validated limit for one algorithm. Rate based algorithms refill `rate` tokens
+// a second up to `burst`, window based ones allow `limit` requests per `window`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AlgorithmConfig {
+    TokenBucket { burst: u128, rate: u128 },
+    Gcra { burst: u128, rate: u128 },
+    SlidingLog { limit: u128, window: Duration },
+    SlidingCounter { limit: u128, window: Duration },
+    FixedWindow { limit: u128, window: Duration },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlgorithmConfigError {
+    MissingCapacity,
+    ZeroCapacity,
+    MissingRate,
+    ZeroRate,
+    MissingWindow,
+    ZeroWindow,
+    // the redis scripts count windows in whole milliseconds
+    FractionalWindow(Duration),
+    // a rate given to a window based algorithm
+    UnexpectedRate,
+    // a window given to a rate based algorithm
+    UnexpectedWindow,
+}
+
+impl AlgorithmConfig {
+    pub fn builder(algorithm: AlgorithmType) -> AlgorithmConfigBuilder {
+        AlgorithmConfigBuilder::new(algorithm)
+    }
+
+    pub fn algorithm(&self) -> AlgorithmType {
+        match self {
+            AlgorithmConfig::TokenBucket { .. } => AlgorithmType::TokenBucket,
+            AlgorithmConfig::Gcra { .. } => AlgorithmType::Gcra,
+            AlgorithmConfig::SlidingLog { .. } => AlgorithmType::SlidingLog,
+            AlgorithmConfig::SlidingCounter { .. } => AlgorithmType::SlidingCounter,
+            AlgorithmConfig::FixedWindow { .. } => AlgorithmType::FixedWindow,
+        }
+    }
+
+    // the burst, or the requests allowed per window
+    pub fn capacity(&self) -> u128 {
+        match self {
+            AlgorithmConfig::TokenBucket { burst, .. } | AlgorithmConfig::Gcra { burst, .. } => {
+                *burst
+            }
+            AlgorithmConfig::SlidingLog { limit, .. }
+            | AlgorithmConfig::SlidingCounter { limit, .. }
+            | AlgorithmConfig::FixedWindow { limit, .. } => *limit,
+        }
+    }
+
+    // tokens a second, None for window based algorithms
+    pub fn rate(&self) -> Option<u128> {
+        match self {
+            AlgorithmConfig::TokenBucket { rate, .. } | AlgorithmConfig::Gcra { rate, .. } => {
+                Some(*rate)
+            }
+            _ => None,
+        }
+    }
+
+    // None for rate based algorithms
+    pub fn window(&self) -> Option<Duration> {
+        match self {
+            AlgorithmConfig::SlidingLog { window, .. }
+            | AlgorithmConfig::SlidingCounter { window, .. }
+            | AlgorithmConfig::FixedWindow { window, .. } => Some(*window),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AlgorithmConfigBuilder {
+    algorithm: AlgorithmType,
+    capacity: Option<u128>,
+    rate: Option<u128>,
+    window: Option<Duration>,
+}
+
+impl AlgorithmConfigBuilder {
+    pub fn new(algorithm: AlgorithmType) -> Self {
+        Self {
+            algorithm,
+            capacity: None,
+            rate: None,
+            window: None,
+        }
+    }
+
+    // burst for token_bucket and gcra, requests per window for the others
+    pub fn with_capacity(mut self, capacity: u128) -> Self {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    // tokens a second, token_bucket and gcra only
+    pub fn with_rate(mut self, rate: u128) -> Self {
+        self.rate = Some(rate);
+        self
+    }
+
+    // sliding_log, sliding_counter and fixed_window only
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = Some(window);
+        self
+    }
+
+    pub fn build(self) -> Result<AlgorithmConfig, AlgorithmConfigError> {
+        let capacity = match self.capacity {
+            None => return Err(AlgorithmConfigError::MissingCapacity),
+            Some(0) => return Err(AlgorithmConfigError::ZeroCapacity),
+            Some(capacity) => capacity,
+        };
+
+        match self.algorithm {
+            AlgorithmType::TokenBucket | AlgorithmType::Gcra => {
+                if self.window.is_some() {
+                    return Err(AlgorithmConfigError::UnexpectedWindow);
+                }
+                let rate = match self.rate {
+                    None => return Err(AlgorithmConfigError::MissingRate),
+                    Some(0) => return Err(AlgorithmConfigError::ZeroRate),
+                    Some(rate) => rate,
+                };
+                Ok(match self.algorithm {
+                    AlgorithmType::Gcra => AlgorithmConfig::Gcra {
+                        burst: capacity,
+                        rate,
+                    },
+                    _ => AlgorithmConfig::TokenBucket {
+                        burst: capacity,
+                        rate,
+                    },
+                })
+            }
+            AlgorithmType::SlidingLog
+            | AlgorithmType::SlidingCounter
+            | AlgorithmType::FixedWindow => {
+                if self.rate.is_some() {
+                    return Err(AlgorithmConfigError::UnexpectedRate);
+                }
+                let window = match self.window {
+                    None => return Err(AlgorithmConfigError::MissingWindow),
+                    // before the zero check, so 500us isn't reported as no window at all
+                    Some(window) if window.subsec_nanos() % 1_000_000 != 0 => {
+                        return Err(AlgorithmConfigError::FractionalWindow(window));
+                    }
+                    Some(window) if window.is_zero() => {
+                        return Err(AlgorithmConfigError::ZeroWindow);
+                    }
+                    Some(window) => window,
+                };
+                Ok(match self.algorithm {
+                    AlgorithmType::SlidingLog => AlgorithmConfig::SlidingLog {
+                        limit: capacity,
+                        window,
+                    },
+                    AlgorithmType::SlidingCounter => AlgorithmConfig::SlidingCounter {
+                        limit: capacity,
+                        window,
+                    },
+                    _ => AlgorithmConfig::FixedWindow {
+                        limit: capacity,
+                        window,
+                    },
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn builds_each_shape() {
+        let bucket = AlgorithmConfig::builder(AlgorithmType::TokenBucket)
+            .with_capacity(10)
+            .with_rate(5)
+            .build()
+            .unwrap();
+        assert_eq!(bucket, AlgorithmConfig::TokenBucket { burst: 10, rate: 5 });
+        assert_eq!(
+            (bucket.capacity(), bucket.rate(), bucket.window()),
+            (10, Some(5), None)
+        );
+
+        let window = AlgorithmConfig::builder(AlgorithmType::FixedWindow)
+            .with_capacity(100)
+            .with_window(Duration::from_secs(60))
+            .build()
+            .unwrap();
+        assert_eq!(window.algorithm(), AlgorithmType::FixedWindow);
+        assert_eq!(window.capacity(), 100);
+        assert_eq!(window.window(), Some(Duration::from_secs(60)));
+
+        // windows don't have to be whole seconds
+        let short = AlgorithmConfig::builder(AlgorithmType::SlidingLog)
+            .with_capacity(5)
+            .with_window(Duration::from_millis(500))
+            .build()
+            .unwrap();
+        assert_eq!(short.window(), Some(Duration::from_millis(500)));
+    }
+
+    #[test]
+    pub fn rejects_invalid_combinations() {
+        let build = |algorithm, capacity, rate, window: Option<Duration>| {
+            let mut builder = AlgorithmConfigBuilder::new(algorithm).with_capacity(capacity);
+            if let Some(rate) = rate {
+                builder = builder.with_rate(rate);
+            }
+            if let Some(window) = window {
+                builder = builder.with_window(window);
+            }
+            builder.build().unwrap_err()
+        };
+        let minute = Some(Duration::from_secs(60));
+
+        assert_eq!(
+            AlgorithmConfigBuilder::new(AlgorithmType::Gcra)
+                .with_rate(1)
+                .build(),
+            Err(AlgorithmConfigError::MissingCapacity)
+        );
+        assert_eq!(
+            build(AlgorithmType::Gcra, 0, Some(1), None),
+            AlgorithmConfigError::ZeroCapacity
+        );
+        assert_eq!(
+            build(AlgorithmType::TokenBucket, 5, None, None),
+            AlgorithmConfigError::MissingRate
+        );
+        assert_eq!(
+            build(AlgorithmType::TokenBucket, 5, Some(0), None),
+            AlgorithmConfigError::ZeroRate
+        );
+        assert_eq!(
+            build(AlgorithmType::TokenBucket, 5, Some(1), minute),
+            AlgorithmConfigError::UnexpectedWindow
+        );
+        assert_eq!(
+            build(AlgorithmType::SlidingLog, 5, Some(1), minute),
+            AlgorithmConfigError::UnexpectedRate
+        );
+        assert_eq!(
+            build(AlgorithmType::SlidingCounter, 5, None, None),
+            AlgorithmConfigError::MissingWindow
+        );
+        assert_eq!(
+            build(
+                AlgorithmType::FixedWindow,
+                5,
+                None,
+                Some(Duration::from_micros(500))
+            ),
+            AlgorithmConfigError::FractionalWindow(Duration::from_micros(500))
+        );
+        assert_eq!(
+            build(AlgorithmType::FixedWindow, 5, None, Some(Duration::ZERO)),
+            AlgorithmConfigError::ZeroWindow
+        );
+        assert_eq!(
+            build(
+                AlgorithmType::FixedWindow,
+                5,
+                None,
+                Some(Duration::from_micros(1500))
+            ),
+            AlgorithmConfigError::FractionalWindow(Duration::from_micros(1500))
+        );
+    }
+}
@@ -0,0 +1,274 @@
+use std::{
+    sync::atomic::{AtomicI64, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::rate_limiter::{
+    Gcra,
+    algorithm::{AllowResult, BucketState},
+};
+
+// GCRA whose tat lives in one atomic, concurrent checks race on a CAS instead
+// of taking a lock. Instants are kept as signed nanoseconds from `origin`
+pub struct AtomicGcra {
+    capacity: u128,
+    refill_rate: u128,
+    emission_interval: i64,
+    origin: Instant,
+    tat: AtomicI64,
+    last_seen: AtomicI64,
+}
+
+impl AtomicGcra {
+    pub fn new(capacity: u128, refill_rate: u128, now: Instant) -> Self {
+        let emission_interval = Gcra::emission_interval(capacity, refill_rate).as_nanos();
+
+        Self {
+            capacity,
+            refill_rate,
+            emission_interval: emission_interval.clamp(1, i64::MAX as u128) as i64,
+            origin: now,
+            tat: AtomicI64::new(0),
+            last_seen: AtomicI64::new(0),
+        }
+    }
+
+    // same cell as a Gcra whose tat sits `tat_ahead` past `at`
+    pub fn with_tat(capacity: u128, refill_rate: u128, at: Instant, tat_ahead: Duration) -> Self {
+        let gcra = Self::new(capacity, refill_rate, at);
+        gcra.tat.store(
+            tat_ahead.as_nanos().min(i64::MAX as u128) as i64,
+            Ordering::Relaxed,
+        );
+        gcra
+    }
+
+    fn offset(&self, instant: Instant) -> i64 {
+        match instant.checked_duration_since(self.origin) {
+            Some(after) => after.as_nanos().min(i64::MAX as u128) as i64,
+            None => {
+                -(self
+                    .origin
+                    .duration_since(instant)
+                    .as_nanos()
+                    .min(i64::MAX as u128) as i64)
+            }
+        }
+    }
+
+    fn instant(&self, offset: i64) -> Instant {
+        let nanos = Duration::from_nanos(offset.unsigned_abs());
+        if offset >= 0 {
+            self.origin + nanos
+        } else {
+            self.origin - nanos
+        }
+    }
+
+    fn cells(&self, n: u128) -> i64 {
+        self.emission_interval
+            .saturating_mul(n.min(u32::MAX as u128) as i64)
+    }
+
+    pub fn allow_n(&self, now: Instant, cost: u128) -> AllowResult {
+        self.last_seen
+            .fetch_max(self.offset(now), Ordering::Relaxed);
+
+        if cost > self.capacity {
+            return AllowResult::CostExceedsCapacity {
+                cost,
+                capacity: self.capacity,
+            };
+        }
+
+        let now = self.offset(now);
+        let cost = self.cells(cost);
+        let burst = self.cells(self.capacity);
+        let mut tat = self.tat.load(Ordering::Acquire);
+
+        loop {
+            let new_tat = tat.max(now).saturating_add(cost);
+            let allow_at = new_tat.saturating_sub(burst);
+
+            if now < allow_at {
+                return AllowResult::Denied {
+                    retry_after: Duration::from_nanos((allow_at - now) as u64),
+                };
+            }
+
+            // someone else moved the tat, retry against their value
+            match self
+                .tat
+                .compare_exchange_weak(tat, new_tat, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => return AllowResult::Allowed,
+                Err(current) => tat = current,
+            }
+        }
+    }
+
+    pub fn state(&self, now: Instant) -> BucketState {
+        let ahead = self
+            .tat
+            .load(Ordering::Acquire)
+            .saturating_sub(self.offset(now))
+            .max(0) as u128;
+        let interval = self.emission_interval as u128;
+
+        let reset_after = match ahead % interval {
+            _ if ahead == 0 => Duration::ZERO,
+            0 => Duration::from_nanos(interval as u64),
+            partial => Duration::from_nanos(partial as u64),
+        };
+
+        BucketState {
+            limit: self.capacity,
+            remaining: self.capacity.saturating_sub(ahead.div_ceil(interval)),
+            reset_after,
+        }
+    }
+
+    // pull the tat back, but a cell that has refilled meanwhile stays full
+    pub fn refund(&self, now: Instant, cost: u128) {
+        let now = self.offset(now);
+        let cost = self.cells(cost);
+
+        let _ = self
+            .tat
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |tat| {
+                (tat > now).then(|| tat.saturating_sub(cost).max(now))
+            });
+    }
+
+    pub fn last_seen(&self) -> Instant {
+        self.instant(self.last_seen.load(Ordering::Relaxed))
+    }
+
+    pub fn set_last_seen(&self, now: Instant) {
+        self.last_seen.store(self.offset(now), Ordering::Relaxed);
+    }
+
+    // plain copy for snapshots
+    pub fn to_gcra(&self) -> Gcra {
+        Gcra::with_tat(
+            self.capacity,
+            self.refill_rate,
+            self.instant(self.tat.load(Ordering::Acquire)),
+            self.last_seen(),
+        )
+    }
+
+    // takes over the tat and last_seen of `other`, e.g. a restored cell
+    pub fn replace(&self, other: &AtomicGcra) {
+        let tat = other.instant(other.tat.load(Ordering::Acquire));
+        self.tat.store(self.offset(tat), Ordering::Release);
+        self.set_last_seen(other.last_seen());
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicU64, Ordering},
+        },
+        thread,
+    };
+
+    #[test]
+    pub fn matches_gcra() {
+        let t0 = Instant::now();
+        let atomic = AtomicGcra::new(5, 5, t0);
+        let mut gcra = Gcra::new(5, 5, t0);
+
+        for ms in [0, 0, 0, 10, 50, 90, 120, 200, 450, 460, 470, 1500, 1500] {
+            let now = t0 + Duration::from_millis(ms);
+            let expected = match gcra.allow_n(now, 2) {
+                AllowResult::Denied { retry_after } => Some(retry_after),
+                _ => None,
+            };
+            let got = match atomic.allow_n(now, 2) {
+                AllowResult::Denied { retry_after } => Some(retry_after),
+                _ => None,
+            };
+            assert_eq!(got, expected);
+
+            let (a, b) = (atomic.state(now), gcra.state(now));
+            assert_eq!((a.remaining, a.reset_after), (b.remaining, b.reset_after));
+        }
+
+        assert!(matches!(
+            atomic.allow_n(t0, 6),
+            AllowResult::CostExceedsCapacity { .. }
+        ));
+    }
+
+    #[test]
+    pub fn concurrent_checks_never_overshoot() {
+        let t0 = Instant::now();
+        let gcra = Arc::new(AtomicGcra::new(1000, 1, t0));
+        let allowed = Arc::new(AtomicU64::new(0));
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let gcra = gcra.clone();
+                let allowed = allowed.clone();
+                thread::spawn(move || {
+                    for _ in 0..500 {
+                        if matches!(gcra.allow_n(t0, 1), AllowResult::Allowed) {
+                            allowed.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(allowed.load(Ordering::Relaxed), 1000);
+        assert_eq!(gcra.state(t0).remaining, 0);
+    }
+
+    #[test]
+    pub fn refund_and_snapshot_copy() {
+        let t0 = Instant::now();
+        let gcra = AtomicGcra::new(5, 5, t0);
+        let _ = gcra.allow_n(t0, 5);
+
+        gcra.refund(t0, 2);
+        assert_eq!(gcra.state(t0).remaining, 2);
+
+        let copy = gcra.to_gcra();
+        assert_eq!(copy.state(t0).remaining, 2);
+        assert_eq!(copy.last_seen, t0);
+
+        // refilled meanwhile, nothing to give back
+        let later = t0 + Duration::from_secs(5);
+        gcra.refund(later, 3);
+        assert_eq!(gcra.state(later).remaining, 5);
+    }
+
+    #[test]
+    pub fn zero_refill_rate_matches_gcra() {
+        let t0 = Instant::now();
+        let atomic = AtomicGcra::new(3, 0, t0);
+        let mut gcra = Gcra::new(3, 0, t0);
+        let later = t0 + Duration::from_secs(365 * 24 * 3600);
+
+        for now in [t0, t0, later] {
+            let expected = match gcra.allow_n(now, 2) {
+                AllowResult::Denied { retry_after } => Some(retry_after),
+                _ => None,
+            };
+            let got = match atomic.allow_n(now, 2) {
+                AllowResult::Denied { retry_after } => Some(retry_after),
+                _ => None,
+            };
+            assert_eq!(got, expected);
+        }
+        assert_eq!(atomic.state(later).remaining, 1);
+    }
+}
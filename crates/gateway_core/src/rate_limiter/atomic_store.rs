@@ -0,0 +1,207 @@
+use dashmap::DashMap;
+use std::{
+    hash::Hash,
+    sync::{OnceLock, atomic::AtomicUsize},
+    time::{Duration, Instant},
+};
+
+use crate::rate_limiter::{
+    algorithm::{AllowResult, BucketState, SavedBucket},
+    atomic_gcra::AtomicGcra,
+    bucket_store::{Bucket, BucketStore, evict_sampled},
+    rate_limiter::{BucketConfig, RateLimitError, RateLimitErrorKind},
+};
+
+// keys that get a lock-free slot, enough for a global or per-route limiter
+pub const LOCK_FREE_SLOTS: usize = 16;
+
+// GCRA only, whatever the config's algorithm says. The first LOCK_FREE_SLOTS keys
+// are pinned in slots that are found without any lock and then updated by a CAS,
+// later keys spill into a DashMap. Pinned keys are never cleaned up or evicted
+pub struct AtomicGcraStore<K>
+where
+    K: Eq + Hash,
+{
+    slots: [OnceLock<(K, AtomicGcra)>; LOCK_FREE_SLOTS],
+    spill: DashMap<K, AtomicGcra>,
+    eviction_cursor: AtomicUsize,
+}
+
+impl<K> AtomicGcraStore<K>
+where
+    K: Eq + Hash,
+{
+    pub fn new() -> Self {
+        Self {
+            slots: std::array::from_fn(|_| OnceLock::new()),
+            spill: DashMap::new(),
+            eviction_cursor: AtomicUsize::new(0),
+        }
+    }
+
+    fn pinned(&self, key: &K) -> Option<&AtomicGcra> {
+        self.slots
+            .iter()
+            .map_while(|slot| slot.get())
+            .find(|(pinned, _)| pinned == key)
+            .map(|(_, cell)| cell)
+    }
+
+    // every caller scans the slots in the same order and a slot is claimed once,
+    // so a key can't be pinned twice. Hands the key back when the slots are full
+    fn pin(&self, key: K, cell: impl FnOnce() -> AtomicGcra) -> Result<&AtomicGcra, K> {
+        let mut key = Some(key);
+        let mut cell = Some(cell);
+
+        for slot in &self.slots {
+            let (pinned, pinned_cell) =
+                slot.get_or_init(|| (key.take().unwrap(), (cell.take().unwrap())()));
+            match &key {
+                // our key went into this slot
+                None => return Ok(pinned_cell),
+                Some(key) if key == pinned => return Ok(pinned_cell),
+                Some(_) => {}
+            }
+        }
+
+        Err(key.unwrap())
+    }
+}
+
+impl<K> Default for AtomicGcraStore<K>
+where
+    K: Eq + Hash,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// a window based limit runs as GCRA spreading `limit` over the window, rounded up to
+// whole tokens a second
+fn gcra_rate(config: &BucketConfig) -> u128 {
+    match (config.limit.rate(), config.limit.window()) {
+        (Some(rate), _) => rate,
+        (_, window) => {
+            let per_second = config.capacity() as f64 / window.unwrap_or_default().as_secs_f64();
+            per_second.ceil().min(u128::MAX as f64) as u128
+        }
+    }
+}
+
+fn check_cell(cell: &AtomicGcra, now: Instant, cost: u128) -> Result<BucketState, RateLimitError> {
+    match cell.allow_n(now, cost) {
+        AllowResult::Allowed => Ok(cell.state(now)),
+        AllowResult::Denied { retry_after } => Err(RateLimitError {
+            kind: RateLimitErrorKind::Limited,
+            retry_after,
+            snapshot: cell.state(now),
+        }),
+        AllowResult::CostExceedsCapacity { .. } => Err(RateLimitError {
+            kind: RateLimitErrorKind::CostExceedsCapacity,
+            retry_after: Duration::ZERO,
+            snapshot: cell.state(now),
+        }),
+    }
+}
+
+impl<K> BucketStore<K> for AtomicGcraStore<K>
+where
+    K: Eq + Hash + Send + Sync,
+{
+    fn check(
+        &self,
+        key: K,
+        now: Instant,
+        cost: u128,
+        config: &BucketConfig,
+    ) -> Result<BucketState, RateLimitError> {
+        if let Some(cell) = self.pinned(&key) {
+            return check_cell(cell, now, cost);
+        }
+
+        let new_cell = || AtomicGcra::new(config.capacity(), gcra_rate(config), now);
+        let key = match self.pin(key, new_cell) {
+            Ok(cell) => return check_cell(cell, now, cost),
+            Err(key) => key,
+        };
+
+        // a shared shard lock is enough once the key exists
+        if let Some(cell) = self.spill.get(&key) {
+            return check_cell(&cell, now, cost);
+        }
+        let cell = self.spill.entry(key).or_insert_with(new_cell);
+        check_cell(&cell, now, cost)
+    }
+
+    fn cleanup(&self, now: Instant, ttl: Duration) {
+        self.spill
+            .retain(|_, cell| now.duration_since(cell.last_seen()) <= ttl);
+    }
+
+    fn refund(&self, key: &K, now: Instant, cost: u128, config: &BucketConfig) {
+        if let Some(cell) = self.pinned(key) {
+            cell.refund(now, cost);
+        } else if let Some(cell) = self.spill.get(key) {
+            cell.refund(now, cost);
+        }
+    }
+
+    fn peek(&self, key: &K, now: Instant, config: &BucketConfig) -> Option<BucketState> {
+        if let Some(cell) = self.pinned(key) {
+            return Some(cell.state(now));
+        }
+        self.spill.get(key).map(|cell| cell.state(now))
+    }
+
+    // hands out plain Gcra copies, the snapshot can't tell the stores apart
+    fn for_each(&self, f: &mut dyn FnMut(&K, &Bucket)) {
+        let pinned = self.slots.iter().map_while(|slot| slot.get());
+        for (key, cell) in pinned {
+            f(key, &(Box::new(cell.to_gcra()) as Bucket));
+        }
+        for entry in self.spill.iter() {
+            f(entry.key(), &(Box::new(entry.to_gcra()) as Bucket));
+        }
+    }
+
+    fn insert(&self, key: K, bucket: Bucket, config: &BucketConfig) {
+        let last_seen = bucket.last_seen();
+        let SavedBucket::Gcra { tat_ahead } = bucket.save(last_seen) else {
+            return;
+        };
+        let restored =
+            AtomicGcra::with_tat(config.capacity(), gcra_rate(config), last_seen, tat_ahead);
+
+        if let Some(cell) = self.pinned(&key) {
+            cell.replace(&restored);
+            return;
+        }
+        let mut restored = Some(restored);
+        match self.pin(key, || restored.take().unwrap()) {
+            Ok(cell) => {
+                // lost the slot to a concurrent check of the same key
+                if let Some(restored) = restored {
+                    cell.replace(&restored);
+                }
+            }
+            Err(key) => {
+                self.spill.insert(key, restored.take().unwrap());
+            }
+        }
+    }
+
+    fn key_count(&self) -> usize {
+        self.slots.iter().map_while(|slot| slot.get()).count() + self.spill.len()
+    }
+
+    fn contains(&self, key: &K) -> bool {
+        self.pinned(key).is_some() || self.spill.contains_key(key)
+    }
+
+    fn evict(&self, count: usize) -> usize {
+        evict_sampled(&self.spill, &self.eviction_cursor, count, |cell| {
+            cell.last_seen()
+        })
+    }
+}
@@ -0,0 +1,159 @@
+use dashmap::DashMap;
+use std::{
+    hash::Hash,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use crate::rate_limiter::{
+    algorithm::{AllowResult, BucketState, RateLimitAlgorithm},
+    atomic_store::AtomicGcraStore,
+    dashmap_store::DashMapStore,
+    mutex_store::MutexStore,
+    rate_limiter::{BucketConfig, RateLimitError, RateLimitErrorKind},
+    sharded_store::ShardedStore,
+};
+
+pub type Bucket = Box<dyn RateLimitAlgorithm>;
+
+// Where the buckets of a RateLimiter live, `check` has to create the bucket from
+// `config` when the key is new and apply the cost atomically
+pub trait BucketStore<K>: Send + Sync {
+    fn check(
+        &self,
+        key: K,
+        now: Instant,
+        cost: u128,
+        config: &BucketConfig,
+    ) -> Result<BucketState, RateLimitError>;
+    fn cleanup(&self, now: Instant, ttl: Duration);
+    // undoes a charge made by `check`, unknown keys are ignored
+    fn refund(&self, key: &K, now: Instant, cost: u128, config: &BucketConfig);
+    // state of the key's bucket without charging it, None for unknown keys
+    fn peek(&self, key: &K, now: Instant, config: &BucketConfig) -> Option<BucketState>;
+    // visits every bucket, used to snapshot the limiter
+    fn for_each(&self, f: &mut dyn FnMut(&K, &Bucket));
+    // puts a restored bucket back, replacing whatever the key holds
+    fn insert(&self, key: K, bucket: Bucket, config: &BucketConfig);
+    fn key_count(&self) -> usize;
+    fn contains(&self, key: &K) -> bool;
+    // drops (at least) the `count` least recently seen buckets, returns how many went
+    fn evict(&self, count: usize) -> usize;
+}
+
+#[derive(Clone)]
+pub enum StoreType {
+    DashMap,
+    Mutex,
+    Sharded { shards: usize },
+    // lock-free GCRA for single-key or low-cardinality limiters
+    AtomicGcra,
+}
+
+impl StoreType {
+    pub fn build<K>(&self) -> Arc<dyn BucketStore<K>>
+    where
+        K: Eq + Hash + Send + Sync + 'static,
+    {
+        match self {
+            StoreType::DashMap => Arc::new(DashMapStore::new()),
+            StoreType::Mutex => Arc::new(MutexStore::new()),
+            StoreType::Sharded { shards } => Arc::new(ShardedStore::new(*shards)),
+            StoreType::AtomicGcra => Arc::new(AtomicGcraStore::new()),
+        }
+    }
+}
+
+// shared by the in-memory stores once they hold the bucket
+pub fn check_bucket(
+    bucket: &mut Bucket,
+    now: Instant,
+    cost: u128,
+) -> Result<BucketState, RateLimitError> {
+    bucket.set_last_seen(now);
+
+    match bucket.allow_n(now, cost) {
+        AllowResult::Allowed => Ok(bucket.state(now)),
+        AllowResult::Denied { retry_after } => Err(RateLimitError {
+            kind: RateLimitErrorKind::Limited,
+            retry_after,
+            snapshot: bucket.state(now),
+        }),
+        AllowResult::CostExceedsCapacity { .. } => Err(RateLimitError {
+            kind: RateLimitErrorKind::CostExceedsCapacity,
+            retry_after: Duration::ZERO,
+            snapshot: bucket.state(now),
+        }),
+    }
+}
+
+pub fn is_live(bucket: &Bucket, now: Instant, ttl: Duration) -> bool {
+    now.duration_since(bucket.last_seen()) <= ttl
+}
+
+// buckets looked at per evicted key, the eviction is approximate LRU over the
+// sample (like redis' maxmemory-samples) and its cost doesn't grow with the map
+pub const EVICTION_SAMPLES: usize = 5;
+
+// last_seen of the `count`-th least recently seen bucket of the sample
+pub fn eviction_cutoff(mut last_seen: Vec<Instant>, count: usize) -> Option<Instant> {
+    if count == 0 || last_seen.is_empty() {
+        return None;
+    }
+    let index = count.min(last_seen.len()) - 1;
+    let (_, cutoff, _) = last_seen.select_nth_unstable(index);
+    Some(*cutoff)
+}
+
+// Samples `count * EVICTION_SAMPLES` entries shard by shard, starting where the
+// last eviction stopped, and evicts up to `count` of them seen at or before the
+// cutoff. A shard walks its entries in the same order for the sample and the
+// removal, so the removal stays in the sampled part of the shard
+pub(crate) fn evict_sampled<K, V>(
+    map: &DashMap<K, V>,
+    cursor: &AtomicUsize,
+    count: usize,
+    last_seen: impl Fn(&V) -> Instant,
+) -> usize
+where
+    K: Eq + Hash,
+{
+    let shards = map.shards();
+    let start = cursor.load(Ordering::Relaxed);
+    let wanted = count.saturating_mul(EVICTION_SAMPLES);
+
+    let mut samples = Vec::new();
+    let mut visited = 0;
+    while visited < shards.len() && samples.len() < wanted {
+        let shard = shards[(start + visited) % shards.len()].read();
+        let left = wanted - samples.len();
+        samples.extend(
+            shard
+                .values()
+                .take(left)
+                .map(|value| last_seen(value.get())),
+        );
+        visited += 1;
+    }
+    cursor.store((start + visited) % shards.len(), Ordering::Relaxed);
+
+    let Some(cutoff) = eviction_cutoff(samples, count) else {
+        return 0;
+    };
+
+    let mut evicted = 0;
+    for index in 0..visited {
+        if evicted == count {
+            break;
+        }
+        let mut shard = shards[(start + index) % shards.len()].write();
+        evicted += shard
+            .extract_if(|_, value| last_seen(value.get()) <= cutoff)
+            .take(count - evicted)
+            .count();
+    }
+    evicted
+}
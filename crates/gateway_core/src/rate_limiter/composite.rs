@@ -0,0 +1,150 @@
+use std::{hash::Hash, time::Instant};
+
+use crate::rate_limiter::{RateLimiter, algorithm::BucketState, rate_limiter::RateLimitError};
+
+// one (limiter, key) pair of a CompositeCheck, hides the key type so tiers keyed
+// by (), String, IpAddr, ... can sit in the same list
+pub trait TierCheck {
+    fn check(&self, now: Instant, cost: u128) -> Result<BucketState, RateLimitError>;
+    fn refund(&self, now: Instant, cost: u128);
+}
+
+struct KeyedTier<'a, K>
+where
+    K: Eq + Hash,
+{
+    limiter: &'a RateLimiter<K>,
+    key: K,
+}
+
+impl<K> TierCheck for KeyedTier<'_, K>
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+{
+    fn check(&self, now: Instant, cost: u128) -> Result<BucketState, RateLimitError> {
+        self.limiter.check_with_cost(self.key.clone(), now, cost)
+    }
+
+    fn refund(&self, now: Instant, cost: u128) {
+        self.limiter.refund(&self.key, now, cost);
+    }
+}
+
+// the tier that denied a composite check, as its index in the order tiers were added
+pub struct TierDenied {
+    pub tier: usize,
+    pub error: RateLimitError,
+}
+
+// Checks several tiers as one unit: either every tier is charged or none is, so a
+// request denied by a later tier (e.g. per ip) doesn't drain the earlier ones (global)
+#[derive(Default)]
+pub struct CompositeCheck<'a> {
+    tiers: Vec<Box<dyn TierCheck + 'a>>,
+}
+
+impl<'a> CompositeCheck<'a> {
+    pub fn new() -> Self {
+        Self { tiers: Vec::new() }
+    }
+
+    pub fn tier<K>(mut self, limiter: &'a RateLimiter<K>, key: K) -> Self
+    where
+        K: Eq + Hash + Clone + Send + Sync + 'static,
+    {
+        self.tiers.push(Box::new(KeyedTier { limiter, key }));
+        self
+    }
+
+    // snapshots come back in tier order
+    pub fn check(&self, now: Instant, cost: u128) -> Result<Vec<BucketState>, TierDenied> {
+        let mut snapshots = Vec::with_capacity(self.tiers.len());
+
+        for (index, tier) in self.tiers.iter().enumerate() {
+            match tier.check(now, cost) {
+                Ok(snapshot) => snapshots.push(snapshot),
+                Err(error) => {
+                    // roll back the tiers that already let the request through
+                    for charged in &self.tiers[..index] {
+                        charged.refund(now, cost);
+                    }
+                    return Err(TierDenied { tier: index, error });
+                }
+            }
+        }
+
+        Ok(snapshots)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::rate_limiter::{
+        AlgorithmConfig,
+        rate_limiter::{AlgorithmType, RateLimitErrorKind, tests::limit},
+    };
+
+    const ALGORITHMS: [AlgorithmType; 5] = [
+        AlgorithmType::TokenBucket,
+        AlgorithmType::SlidingLog,
+        AlgorithmType::SlidingCounter,
+        AlgorithmType::Gcra,
+        AlgorithmType::FixedWindow,
+    ];
+
+    #[test]
+    pub fn denied_request_charges_no_tier() {
+        for algorithm in ALGORITHMS {
+            let global = RateLimiter::new(limit(algorithm.clone(), 10, 3600));
+            let ip = RateLimiter::new(limit(algorithm, 2, 3600));
+            let t0 = Instant::now();
+            let check = |addr: &'static str| {
+                CompositeCheck::new()
+                    .tier(&global, ())
+                    .tier(&ip, addr)
+                    .check(t0, 1)
+            };
+
+            assert!(check("10.0.0.1").is_ok());
+            assert!(check("10.0.0.1").is_ok());
+
+            // one abusive ip hammering away doesn't touch the global budget
+            for _ in 0..20 {
+                let denied = check("10.0.0.1").err().unwrap();
+                assert_eq!(denied.tier, 1);
+                assert_eq!(denied.error.kind, RateLimitErrorKind::Limited);
+            }
+
+            let snapshots = check("10.0.0.2").ok().unwrap();
+            assert_eq!(snapshots[0].remaining, 7);
+            assert_eq!(snapshots[1].remaining, 1);
+        }
+    }
+
+    #[test]
+    pub fn first_tier_denial_skips_the_rest() {
+        let global = RateLimiter::new(AlgorithmConfig::TokenBucket {
+            burst: 1,
+            rate: 3600,
+        });
+        let ip = RateLimiter::new(AlgorithmConfig::TokenBucket {
+            burst: 5,
+            rate: 3600,
+        });
+        let t0 = Instant::now();
+
+        assert!(global.check((), t0).is_ok());
+
+        let denied = CompositeCheck::new()
+            .tier(&global, ())
+            .tier(&ip, "10.0.0.1")
+            .check(t0, 1)
+            .err()
+            .unwrap();
+        assert_eq!(denied.tier, 0);
+
+        let snapshot = ip.check("10.0.0.1", t0).ok().unwrap();
+        assert_eq!(snapshot.remaining, 4);
+    }
+}
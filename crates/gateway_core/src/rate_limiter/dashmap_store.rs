@@ -0,0 +1,97 @@
+use dashmap::DashMap;
+use std::{
+    hash::Hash,
+    sync::atomic::AtomicUsize,
+    time::{Duration, Instant},
+};
+
+use crate::rate_limiter::{
+    algorithm::BucketState,
+    bucket_store::{Bucket, BucketStore, check_bucket, evict_sampled, is_live},
+    rate_limiter::{BucketConfig, RateLimitError},
+};
+
+// sharded locking -> keys on different shards don't contend
+pub struct DashMapStore<K>
+where
+    K: Eq + Hash,
+{
+    buckets: DashMap<K, Bucket>,
+    // shard the next eviction starts sampling from
+    eviction_cursor: AtomicUsize,
+}
+
+impl<K> DashMapStore<K>
+where
+    K: Eq + Hash,
+{
+    pub fn new() -> Self {
+        Self {
+            buckets: DashMap::new(),
+            eviction_cursor: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<K> Default for DashMapStore<K>
+where
+    K: Eq + Hash,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K> BucketStore<K> for DashMapStore<K>
+where
+    K: Eq + Hash + Send + Sync,
+{
+    fn check(
+        &self,
+        key: K,
+        now: Instant,
+        cost: u128,
+        config: &BucketConfig,
+    ) -> Result<BucketState, RateLimitError> {
+        let mut bucket = self.buckets.entry(key).or_insert_with(|| config.build(now));
+        check_bucket(&mut bucket, now, cost)
+    }
+
+    fn cleanup(&self, now: Instant, ttl: Duration) {
+        self.buckets.retain(|_, bucket| is_live(bucket, now, ttl));
+    }
+
+    fn refund(&self, key: &K, now: Instant, cost: u128, config: &BucketConfig) {
+        if let Some(mut bucket) = self.buckets.get_mut(key) {
+            bucket.refund(now, cost);
+        }
+    }
+
+    fn peek(&self, key: &K, now: Instant, config: &BucketConfig) -> Option<BucketState> {
+        self.buckets.get(key).map(|bucket| bucket.peek(now))
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&K, &Bucket)) {
+        for entry in self.buckets.iter() {
+            f(entry.key(), entry.value());
+        }
+    }
+
+    fn insert(&self, key: K, bucket: Bucket, config: &BucketConfig) {
+        self.buckets.insert(key, bucket);
+    }
+
+    fn key_count(&self) -> usize {
+        self.buckets.len()
+    }
+
+    fn contains(&self, key: &K) -> bool {
+        self.buckets.contains_key(key)
+    }
+
+    fn evict(&self, count: usize) -> usize {
+        evict_sampled(&self.buckets, &self.eviction_cursor, count, |bucket| {
+            bucket.last_seen()
+        })
+    }
+}
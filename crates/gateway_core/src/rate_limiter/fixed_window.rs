@@ -0,0 +1,258 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::rate_limiter::algorithm::{AllowResult, BucketState, RateLimitAlgorithm, SavedBucket};
+
+// Windows are aligned to wall-clock boundaries (e.g. :00 of every minute) instead of
+// the first request of the key, so "1000 per minute" resets for everyone at the same time
+#[derive(Clone)]
+pub struct FixedWindow {
+    capacity: u128,
+    window: Duration,
+
+    // wall-clock time (since unix epoch) that corresponds to `anchor`
+    anchor: Instant,
+    anchor_wall: Duration,
+
+    current_window: u128,
+    current_count: u128,
+
+    pub last_seen: Instant,
+}
+
+impl RateLimitAlgorithm for FixedWindow {
+    fn allow_n(&mut self, now: Instant, cost: u128) -> AllowResult {
+        FixedWindow::allow_n(self, now, cost)
+    }
+    fn state(&self, now: Instant) -> BucketState {
+        FixedWindow::state(self, now)
+    }
+    fn peek(&self, now: Instant) -> BucketState {
+        FixedWindow::peek(self, now)
+    }
+    fn last_seen(&self) -> Instant {
+        self.last_seen
+    }
+    fn set_last_seen(&mut self, now: Instant) {
+        self.last_seen = now;
+    }
+    fn save(&self, now: Instant) -> SavedBucket {
+        FixedWindow::save(self, now)
+    }
+    fn load(&mut self, saved: &SavedBucket, taken_at: Instant) -> bool {
+        FixedWindow::load(self, saved, taken_at)
+    }
+    fn refund(&mut self, now: Instant, cost: u128) {
+        FixedWindow::refund(self, now, cost)
+    }
+}
+
+impl FixedWindow {
+    pub fn new(capacity: u128, window: Duration, now: Instant) -> Self {
+        let wall = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO);
+
+        Self::with_wall_clock(capacity, window, now, wall)
+    }
+
+    // `wall` is the wall-clock time since unix epoch that matches `now`
+    pub fn with_wall_clock(capacity: u128, window: Duration, now: Instant, wall: Duration) -> Self {
+        Self {
+            capacity,
+            window,
+            anchor: now,
+            anchor_wall: wall,
+            current_window: Self::index_of(wall, window),
+            current_count: 0,
+            last_seen: now,
+        }
+    }
+
+    fn wall_time(&self, now: Instant) -> Duration {
+        if now >= self.anchor {
+            self.anchor_wall + (now - self.anchor)
+        } else {
+            self.anchor_wall.saturating_sub(self.anchor - now)
+        }
+    }
+
+    // a zero window is a single window that never ends, like a token bucket that
+    // never refills
+    fn index_of(wall: Duration, window: Duration) -> u128 {
+        wall.as_nanos().checked_div(window.as_nanos()).unwrap_or(0)
+    }
+
+    fn window_index(&self, now: Instant) -> u128 {
+        Self::index_of(self.wall_time(now), self.window)
+    }
+
+    // time left until the next aligned boundary
+    fn until_boundary(&self, now: Instant) -> Duration {
+        let window = self.window.as_nanos();
+        match self.wall_time(now).as_nanos().checked_rem(window) {
+            Some(into_window) => Duration::from_nanos((window - into_window) as u64),
+            None => Duration::MAX,
+        }
+    }
+
+    pub fn allow(&mut self, now: Instant) -> AllowResult {
+        self.allow_n(now, 1)
+    }
+
+    pub fn allow_n(&mut self, now: Instant, cost: u128) -> AllowResult {
+        if cost > self.capacity {
+            return AllowResult::CostExceedsCapacity {
+                cost,
+                capacity: self.capacity,
+            };
+        }
+
+        self.last_seen = now;
+
+        let index = self.window_index(now);
+        if index != self.current_window {
+            self.current_window = index;
+            self.current_count = 0;
+        }
+
+        if self.current_count + cost <= self.capacity {
+            self.current_count += cost;
+            AllowResult::Allowed
+        } else {
+            AllowResult::Denied {
+                retry_after: self.until_boundary(now),
+            }
+        }
+    }
+
+    pub fn state(&self, now: Instant) -> BucketState {
+        let count = if self.window_index(now) == self.current_window {
+            self.current_count
+        } else {
+            0
+        };
+
+        BucketState {
+            limit: self.capacity,
+            remaining: self.capacity.saturating_sub(count),
+            reset_after: self.until_boundary(now),
+        }
+    }
+
+    // state already treats a past window as empty
+    pub fn peek(&self, now: Instant) -> BucketState {
+        self.state(now)
+    }
+
+    pub fn save(&self, now: Instant) -> SavedBucket {
+        SavedBucket::FixedWindow {
+            window_index: self.current_window,
+            count: self.current_count,
+        }
+    }
+
+    // the window index is wall-clock based, a count from an older window is
+    // simply reset by the next allow_n
+    pub fn load(&mut self, saved: &SavedBucket, taken_at: Instant) -> bool {
+        let SavedBucket::FixedWindow {
+            window_index,
+            count,
+        } = saved
+        else {
+            return false;
+        };
+
+        self.current_window = *window_index;
+        self.current_count = *count;
+        true
+    }
+
+    // a refund only makes sense inside the window that was charged
+    pub fn refund(&mut self, now: Instant, cost: u128) {
+        if self.window_index(now) == self.current_window {
+            self.current_count = self.current_count.saturating_sub(cost);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn resets_at_aligned_boundary() {
+        let t0 = Instant::now();
+        // 45s past the minute
+        let mut window =
+            FixedWindow::with_wall_clock(3, Duration::from_secs(60), t0, Duration::from_secs(6045));
+
+        for _ in 0..3 {
+            assert!(matches!(window.allow(t0), AllowResult::Allowed));
+        }
+        match window.allow(t0) {
+            AllowResult::Denied { retry_after } => {
+                assert_eq!(retry_after, Duration::from_secs(15))
+            }
+            _ => panic!("expected denial"),
+        }
+
+        // still inside the same minute
+        assert!(matches!(
+            window.allow(t0 + Duration::from_secs(14)),
+            AllowResult::Denied { .. }
+        ));
+
+        // :00 of the next minute
+        assert!(matches!(
+            window.allow(t0 + Duration::from_secs(15)),
+            AllowResult::Allowed
+        ));
+    }
+
+    #[test]
+    pub fn state_reports_time_to_boundary() {
+        let t0 = Instant::now();
+        let mut window =
+            FixedWindow::with_wall_clock(5, Duration::from_secs(60), t0, Duration::from_secs(6010));
+
+        let _ = window.allow(t0);
+        let s = window.state(t0);
+        assert_eq!(s.limit, 5);
+        assert_eq!(s.remaining, 4);
+        assert_eq!(s.reset_after, Duration::from_secs(50));
+
+        // window rolled over -> full again
+        let s = window.state(t0 + Duration::from_secs(55));
+        assert_eq!(s.remaining, 5);
+        assert_eq!(s.reset_after, Duration::from_secs(55));
+    }
+
+    #[test]
+    pub fn peek_takes_nothing_and_sees_the_next_window() {
+        let t0 = Instant::now();
+        let mut window =
+            FixedWindow::with_wall_clock(3, Duration::from_secs(60), t0, Duration::from_secs(6050));
+        let _ = window.allow_n(t0, 3);
+
+        assert_eq!(window.peek(t0).remaining, 0);
+        assert_eq!(window.peek(t0).reset_after, Duration::from_secs(10));
+        // the count is stale once the boundary passes
+        assert_eq!(window.peek(t0 + Duration::from_secs(10)).remaining, 3);
+        assert!(matches!(window.allow(t0), AllowResult::Denied { .. }));
+    }
+
+    #[test]
+    pub fn zero_window_never_resets() {
+        let t0 = Instant::now();
+        let mut window =
+            FixedWindow::with_wall_clock(2, Duration::ZERO, t0, Duration::from_secs(6050));
+        let _ = window.allow_n(t0, 2);
+
+        let later = t0 + Duration::from_secs(3600);
+        match window.allow(later) {
+            AllowResult::Denied { retry_after } => assert_eq!(retry_after, Duration::MAX),
+            _ => panic!("expected denial"),
+        }
+        assert_eq!(window.state(later).remaining, 0);
+    }
+}
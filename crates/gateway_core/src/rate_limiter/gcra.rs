@@ -0,0 +1,287 @@
+use std::time::{Duration, Instant};
+
+use crate::rate_limiter::algorithm::{AllowResult, BucketState, RateLimitAlgorithm, SavedBucket};
+
+// longest a full burst may take to refill. A zero rate refills this slowly instead of
+// never, so the tat (at most one burst plus one cost ahead) stays a valid Instant
+const MAX_BURST_REFILL: Duration = Duration::from_nanos(i64::MAX as u64 / 2);
+
+// Generic cell rate algorithm -> only the theoretical arrival time (tat) is stored per key
+#[derive(Clone)]
+pub struct Gcra {
+    capacity: u128,
+    emission_interval: Duration,
+    tat: Instant,
+    pub last_seen: Instant,
+}
+
+impl RateLimitAlgorithm for Gcra {
+    fn allow_n(&mut self, now: Instant, cost: u128) -> AllowResult {
+        Gcra::allow_n(self, now, cost)
+    }
+    fn state(&self, now: Instant) -> BucketState {
+        Gcra::state(self, now)
+    }
+    fn peek(&self, now: Instant) -> BucketState {
+        Gcra::peek(self, now)
+    }
+    fn last_seen(&self) -> Instant {
+        self.last_seen
+    }
+    fn set_last_seen(&mut self, now: Instant) {
+        self.last_seen = now;
+    }
+    fn save(&self, now: Instant) -> SavedBucket {
+        Gcra::save(self, now)
+    }
+    fn load(&mut self, saved: &SavedBucket, taken_at: Instant) -> bool {
+        Gcra::load(self, saved, taken_at)
+    }
+    fn refund(&mut self, now: Instant, cost: u128) {
+        Gcra::refund(self, now, cost)
+    }
+}
+
+impl Gcra {
+    pub fn new(capacity: u128, refill_rate: u128, now: Instant) -> Self {
+        Self {
+            capacity,
+            emission_interval: Self::emission_interval(capacity, refill_rate),
+            tat: now,
+            last_seen: now,
+        }
+    }
+
+    // rebuilds a bucket from its parts, e.g. a copy of an AtomicGcra
+    pub fn with_tat(capacity: u128, refill_rate: u128, tat: Instant, last_seen: Instant) -> Self {
+        Self {
+            tat,
+            last_seen,
+            ..Self::new(capacity, refill_rate, last_seen)
+        }
+    }
+
+    // time one cell takes to refill, kept within [1ns, MAX_BURST_REFILL / capacity]
+    pub(crate) fn emission_interval(capacity: u128, refill_rate: u128) -> Duration {
+        let longest = MAX_BURST_REFILL / capacity.clamp(1, u32::MAX as u128) as u32;
+
+        Duration::try_from_secs_f64(1.0 / refill_rate as f64)
+            .unwrap_or(longest)
+            .clamp(Duration::from_nanos(1), longest)
+    }
+
+    fn cells(&self, n: u128) -> Duration {
+        self.emission_interval
+            .saturating_mul(n.min(u32::MAX as u128) as u32)
+    }
+
+    pub fn allow(&mut self, now: Instant) -> AllowResult {
+        self.allow_n(now, 1)
+    }
+
+    pub fn allow_n(&mut self, now: Instant, cost: u128) -> AllowResult {
+        if cost > self.capacity {
+            return AllowResult::CostExceedsCapacity {
+                cost,
+                capacity: self.capacity,
+            };
+        }
+
+        let tat = self.tat.max(now);
+        let new_tat = tat + self.cells(cost);
+
+        // earliest instant at which new_tat fits inside the burst tolerance
+        // (a full burst of `capacity` cells)
+        let allow_at = new_tat
+            .checked_sub(self.cells(self.capacity))
+            .unwrap_or(now);
+
+        if now < allow_at {
+            return AllowResult::Denied {
+                retry_after: allow_at - now,
+            };
+        }
+
+        self.tat = new_tat;
+        AllowResult::Allowed
+    }
+
+    pub fn state(&self, now: Instant) -> BucketState {
+        // time the tat is ahead of now == amount of burst already consumed
+        let ahead = self.tat.saturating_duration_since(now);
+        let interval = self.emission_interval.as_nanos();
+
+        let used = ahead.as_nanos().div_ceil(interval);
+        let remaining = self.capacity.saturating_sub(used);
+
+        // time until the next cell is freed (zero when the bucket is full)
+        let reset_after = match ahead.as_nanos() % interval {
+            _ if ahead.is_zero() => Duration::ZERO,
+            0 => self.emission_interval,
+            partial => Duration::from_nanos(partial as u64),
+        };
+
+        BucketState {
+            limit: self.capacity,
+            remaining,
+            reset_after,
+        }
+    }
+
+    // the tat already encodes time, state is read-only as is
+    pub fn peek(&self, now: Instant) -> BucketState {
+        self.state(now)
+    }
+
+    pub fn save(&self, now: Instant) -> SavedBucket {
+        SavedBucket::Gcra {
+            tat_ahead: self.tat.saturating_duration_since(now),
+        }
+    }
+
+    pub fn load(&mut self, saved: &SavedBucket, taken_at: Instant) -> bool {
+        let SavedBucket::Gcra { tat_ahead } = saved else {
+            return false;
+        };
+
+        // never restore more than a full burst worth of debt
+        self.tat = taken_at + (*tat_ahead).min(self.cells(self.capacity));
+        true
+    }
+
+    // pull the tat back, but a bucket that has refilled meanwhile stays full
+    pub fn refund(&mut self, now: Instant, cost: u128) {
+        if self.tat > now {
+            self.tat = self
+                .tat
+                .checked_sub(self.cells(cost))
+                .unwrap_or(now)
+                .max(now);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn burst_up_to_capacity() {
+        let t0 = Instant::now();
+        let mut gcra = Gcra::new(5, 5, t0);
+
+        for _ in 0..5 {
+            assert!(matches!(gcra.allow(t0), AllowResult::Allowed));
+        }
+        assert!(matches!(gcra.allow(t0), AllowResult::Denied { .. }));
+    }
+
+    #[test]
+    pub fn retry_after_is_one_emission_interval() {
+        let t0 = Instant::now();
+        let mut gcra = Gcra::new(5, 5, t0);
+
+        for _ in 0..5 {
+            let _ = gcra.allow(t0);
+        }
+        match gcra.allow(t0) {
+            AllowResult::Denied { retry_after } => {
+                assert_eq!(retry_after, Duration::from_millis(200))
+            }
+            _ => panic!("expected denial"),
+        }
+        assert!(matches!(
+            gcra.allow(t0 + Duration::from_millis(100)),
+            AllowResult::Denied { .. }
+        ));
+        assert!(matches!(
+            gcra.allow(t0 + Duration::from_millis(200)),
+            AllowResult::Allowed
+        ));
+    }
+
+    #[test]
+    pub fn refill_proportionally() {
+        let t0 = Instant::now();
+        let mut gcra = Gcra::new(10, 5, t0);
+        for _ in 0..10 {
+            let _ = gcra.allow(t0);
+        }
+        //after 1.4s -> 7 cells freed
+        let t1 = t0 + Duration::from_millis(1400);
+        for _ in 0..7 {
+            assert!(matches!(gcra.allow(t1), AllowResult::Allowed));
+        }
+        assert!(matches!(gcra.allow(t1), AllowResult::Denied { .. }));
+    }
+
+    #[test]
+    pub fn do_not_exceed_capacity() {
+        let t0 = Instant::now();
+        let mut gcra = Gcra::new(5, 5, t0);
+        for _ in 0..5 {
+            let _ = gcra.allow(t0);
+        }
+        let t1 = t0 + Duration::from_secs(100);
+        for _ in 0..5 {
+            assert!(matches!(gcra.allow(t1), AllowResult::Allowed));
+        }
+        assert!(matches!(gcra.allow(t1), AllowResult::Denied { .. }));
+    }
+
+    #[test]
+    pub fn state_reports_remaining_and_reset() {
+        let t0 = Instant::now();
+        let mut gcra = Gcra::new(5, 5, t0);
+
+        let full = gcra.state(t0);
+        assert_eq!(full.limit, 5);
+        assert_eq!(full.remaining, 5);
+        assert_eq!(full.reset_after, Duration::ZERO);
+
+        for _ in 0..2 {
+            let _ = gcra.allow(t0);
+        }
+        let s = gcra.state(t0);
+        assert_eq!(s.remaining, 3);
+        assert_eq!(s.reset_after, Duration::from_millis(200));
+
+        let s = gcra.state(t0 + Duration::from_millis(50));
+        assert_eq!(s.remaining, 3);
+        assert_eq!(s.reset_after, Duration::from_millis(150));
+
+        let s = gcra.state(t0 + Duration::from_millis(250));
+        assert_eq!(s.remaining, 4);
+        assert_eq!(s.reset_after, Duration::from_millis(150));
+    }
+
+    #[test]
+    pub fn peek_takes_no_cells() {
+        let t0 = Instant::now();
+        let mut gcra = Gcra::new(5, 5, t0);
+        let _ = gcra.allow_n(t0, 4);
+
+        for _ in 0..3 {
+            assert_eq!(gcra.peek(t0).remaining, 1);
+        }
+        assert_eq!(gcra.peek(t0 + Duration::from_millis(400)).remaining, 3);
+        assert!(matches!(gcra.allow(t0), AllowResult::Allowed));
+        assert!(matches!(gcra.allow(t0), AllowResult::Denied { .. }));
+    }
+
+    #[test]
+    pub fn zero_refill_rate_never_refills() {
+        let t0 = Instant::now();
+        let mut gcra = Gcra::new(3, 0, t0);
+        assert!(matches!(gcra.allow_n(t0, 3), AllowResult::Allowed));
+
+        let later = t0 + Duration::from_secs(365 * 24 * 3600);
+        match gcra.allow(later) {
+            AllowResult::Denied { retry_after } => {
+                assert!(retry_after > Duration::from_secs(10 * 365 * 24 * 3600))
+            }
+            _ => panic!("expected denial"),
+        }
+        assert_eq!(gcra.state(later).remaining, 0);
+    }
+}
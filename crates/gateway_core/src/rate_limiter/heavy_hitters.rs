@@ -0,0 +1,252 @@
+use serde::Serialize;
+use std::{
+    collections::{BTreeSet, HashMap},
+    hash::Hash,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+// A key's approximate request count. The true count lies in [count - error, count]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HeavyHitter<K> {
+    pub key: K,
+    pub count: u64,
+    pub error: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+    Allowed,
+    Denied,
+}
+
+// Space-saving summary, keeps `capacity` counters however many keys it sees. A key
+// without a counter takes over the smallest one, so any key with more than
+// total / capacity requests is guaranteed to be in the summary
+struct SpaceSaving<K> {
+    capacity: usize,
+    counters: HashMap<K, (u64, u64)>,
+    // (count, key), the first entry is the counter to take over
+    by_count: BTreeSet<(u64, K)>,
+}
+
+impl<K> SpaceSaving<K>
+where
+    K: Eq + Hash + Ord + Clone,
+{
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            counters: HashMap::new(),
+            by_count: BTreeSet::new(),
+        }
+    }
+
+    fn record(&mut self, key: &K, weight: u64) {
+        if let Some((count, _)) = self.counters.get_mut(key) {
+            self.by_count.remove(&(*count, key.clone()));
+            *count += weight;
+            self.by_count.insert((*count, key.clone()));
+            return;
+        }
+
+        let error = match self.counters.len() < self.capacity {
+            true => 0,
+            false => match self.by_count.pop_first() {
+                Some((min, evicted)) => {
+                    self.counters.remove(&evicted);
+                    min
+                }
+                None => return,
+            },
+        };
+        self.counters.insert(key.clone(), (error + weight, error));
+        self.by_count.insert((error + weight, key.clone()));
+    }
+
+    fn top(&self, n: usize) -> Vec<HeavyHitter<K>> {
+        self.by_count
+            .iter()
+            .rev()
+            .take(n)
+            .map(|(count, key)| HeavyHitter {
+                key: key.clone(),
+                count: *count,
+                error: self.counters[key].1,
+            })
+            .collect()
+    }
+
+    fn decay(&mut self) {
+        self.counters.retain(|_, (count, error)| {
+            *count /= 2;
+            *error /= 2;
+            *count > 0
+        });
+        self.by_count = self
+            .counters
+            .iter()
+            .map(|(key, (count, _))| (*count, key.clone()))
+            .collect();
+    }
+}
+
+// Top keys of one limiter tier, allowed and denied requests counted apart so a
+// 429 storm shows who is being turned away. Memory is bounded by 2 x capacity
+// counters, a summary is behind one lock. Only one in `sample_every` requests of
+// each decision takes the lock and counts for all of them, so neither a busy
+// upstream nor a 429 storm serializes on it
+#[derive(Clone)]
+pub struct HeavyHitters<K> {
+    allowed: Arc<Mutex<SpaceSaving<K>>>,
+    denied: Arc<Mutex<SpaceSaving<K>>>,
+    sample_every: u64,
+    allowed_seen: Arc<AtomicU64>,
+    denied_seen: Arc<AtomicU64>,
+}
+
+impl<K> HeavyHitters<K>
+where
+    K: Eq + Hash + Ord + Clone,
+{
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            allowed: Arc::new(Mutex::new(SpaceSaving::new(capacity))),
+            denied: Arc::new(Mutex::new(SpaceSaving::new(capacity))),
+            sample_every: 1,
+            allowed_seen: Arc::new(AtomicU64::new(0)),
+            denied_seen: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    // 1 (the default) and 0 record every request
+    pub fn with_sample_every(mut self, sample_every: u64) -> Self {
+        self.sample_every = sample_every.max(1);
+        self
+    }
+
+    fn summary(&self, decision: Decision) -> &Mutex<SpaceSaving<K>> {
+        match decision {
+            Decision::Allowed => &self.allowed,
+            Decision::Denied => &self.denied,
+        }
+    }
+
+    pub fn record(&self, key: &K, decision: Decision) {
+        if self.sample_every > 1 {
+            let seen = match decision {
+                Decision::Allowed => &self.allowed_seen,
+                Decision::Denied => &self.denied_seen,
+            };
+            if !seen
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(self.sample_every)
+            {
+                return;
+            }
+        }
+
+        self.summary(decision)
+            .lock()
+            .unwrap()
+            .record(key, self.sample_every);
+    }
+
+    // most requests first
+    pub fn top(&self, decision: Decision, n: usize) -> Vec<HeavyHitter<K>> {
+        self.summary(decision).lock().unwrap().top(n)
+    }
+
+    // halves every count so keys that stopped sending fall out over time
+    pub fn decay(&self) {
+        self.allowed.lock().unwrap().decay();
+        self.denied.lock().unwrap().decay();
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn exact_until_full() {
+        let hitters = HeavyHitters::new(10);
+        for (key, times) in [("a", 5), ("b", 3), ("c", 1)] {
+            for _ in 0..times {
+                hitters.record(&key, Decision::Denied);
+            }
+        }
+        hitters.record(&"d", Decision::Allowed);
+
+        let top = hitters.top(Decision::Denied, 2);
+        assert_eq!(
+            top,
+            vec![
+                HeavyHitter {
+                    key: "a",
+                    count: 5,
+                    error: 0
+                },
+                HeavyHitter {
+                    key: "b",
+                    count: 3,
+                    error: 0
+                },
+            ]
+        );
+        assert_eq!(hitters.top(Decision::Allowed, 10).len(), 1);
+
+        hitters.decay();
+        let counts: Vec<_> = hitters
+            .top(Decision::Denied, 10)
+            .iter()
+            .map(|hitter| (hitter.key, hitter.count))
+            .collect();
+        assert_eq!(counts, vec![("a", 2), ("b", 1)]);
+        assert!(hitters.top(Decision::Allowed, 10).is_empty());
+    }
+
+    #[test]
+    pub fn heavy_keys_survive_a_flood_of_others() {
+        let hitters = HeavyHitters::new(16);
+        for i in 0..10_000u64 {
+            hitters.record(&i.to_string(), Decision::Denied);
+            if i % 4 == 0 {
+                hitters.record(&"heavy".to_string(), Decision::Denied);
+            }
+        }
+
+        let top = hitters.top(Decision::Denied, 100);
+        assert_eq!(top.len(), 16);
+        assert_eq!(top[0].key, "heavy");
+        // never undercounts, overcounts by at most the error
+        assert!(top[0].count >= 2500 && top[0].count - top[0].error <= 2500);
+    }
+
+    #[test]
+    pub fn requests_are_sampled() {
+        let hitters = HeavyHitters::new(10).with_sample_every(4);
+        for _ in 0..100 {
+            hitters.record(&"a", Decision::Allowed);
+        }
+        for _ in 0..3 {
+            hitters.record(&"a", Decision::Denied);
+        }
+        for _ in 0..37 {
+            hitters.record(&"b", Decision::Denied);
+        }
+
+        // one in four taken, each standing for four requests
+        assert_eq!(hitters.top(Decision::Allowed, 1)[0].count, 100);
+        // denials are sampled on their own count, not the allowed one
+        let denied: Vec<_> = hitters
+            .top(Decision::Denied, 10)
+            .iter()
+            .map(|hitter| (hitter.key, hitter.count))
+            .collect();
+        assert_eq!(denied, vec![("b", 36), ("a", 4)]);
+    }
+}
@@ -0,0 +1,33 @@
+// most keys a single eviction drops
+pub const EVICTION_BATCH: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    // make room by dropping the least recently seen keys
+    LeastRecentlySeen,
+    // keep the known keys, new keys share one overflow bucket until room frees up
+    Overflow,
+}
+
+// Caps how many keys a RateLimiter tracks so rotating source IPs (or the IPv6
+// space) can't grow the map without bound until the cleanup TTL kicks in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyLimit {
+    pub max_keys: usize,
+    pub policy: EvictionPolicy,
+}
+
+impl KeyLimit {
+    pub fn new(max_keys: usize, policy: EvictionPolicy) -> Self {
+        Self {
+            max_keys: max_keys.max(1),
+            policy,
+        }
+    }
+
+    // 1% at a time, capped so a single request never samples more than
+    // EVICTION_BATCH * EVICTION_SAMPLES buckets
+    pub fn eviction_batch(&self) -> usize {
+        (self.max_keys / 100).clamp(1, EVICTION_BATCH)
+    }
+}
@@ -0,0 +1,114 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::rate_limiter::{
+    algorithm::BucketState,
+    bucket_store::{Bucket, BucketStore, EVICTION_SAMPLES, check_bucket, eviction_cutoff, is_live},
+    rate_limiter::{BucketConfig, RateLimitError},
+};
+
+// one lock for every key -> simplest option, see the README benchmark
+pub struct MutexStore<K>
+where
+    K: Eq + Hash,
+{
+    buckets: Mutex<HashMap<K, Bucket>>,
+}
+
+impl<K> MutexStore<K>
+where
+    K: Eq + Hash,
+{
+    pub fn new() -> Self {
+        Self {
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K> Default for MutexStore<K>
+where
+    K: Eq + Hash,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K> BucketStore<K> for MutexStore<K>
+where
+    K: Eq + Hash + Send + Sync,
+{
+    fn check(
+        &self,
+        key: K,
+        now: Instant,
+        cost: u128,
+        config: &BucketConfig,
+    ) -> Result<BucketState, RateLimitError> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key).or_insert_with(|| config.build(now));
+        check_bucket(bucket, now, cost)
+    }
+
+    fn cleanup(&self, now: Instant, ttl: Duration) {
+        self.buckets
+            .lock()
+            .unwrap()
+            .retain(|_, bucket| is_live(bucket, now, ttl));
+    }
+
+    fn refund(&self, key: &K, now: Instant, cost: u128, config: &BucketConfig) {
+        if let Some(bucket) = self.buckets.lock().unwrap().get_mut(key) {
+            bucket.refund(now, cost);
+        }
+    }
+
+    fn peek(&self, key: &K, now: Instant, config: &BucketConfig) -> Option<BucketState> {
+        self.buckets
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|bucket| bucket.peek(now))
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&K, &Bucket)) {
+        for (key, bucket) in self.buckets.lock().unwrap().iter() {
+            f(key, bucket);
+        }
+    }
+
+    fn insert(&self, key: K, bucket: Bucket, config: &BucketConfig) {
+        self.buckets.lock().unwrap().insert(key, bucket);
+    }
+
+    fn key_count(&self) -> usize {
+        self.buckets.lock().unwrap().len()
+    }
+
+    fn contains(&self, key: &K) -> bool {
+        self.buckets.lock().unwrap().contains_key(key)
+    }
+
+    // samples the first buckets of the map, extract_if walks them in the same order
+    fn evict(&self, count: usize) -> usize {
+        let mut buckets = self.buckets.lock().unwrap();
+        let last_seen = buckets
+            .values()
+            .take(count.saturating_mul(EVICTION_SAMPLES))
+            .map(|bucket| bucket.last_seen())
+            .collect();
+        let Some(cutoff) = eviction_cutoff(last_seen, count) else {
+            return 0;
+        };
+
+        buckets
+            .extract_if(|_, bucket| bucket.last_seen() <= cutoff)
+            .take(count)
+            .count()
+    }
+}
@@ -0,0 +1,353 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::rate_limiter::{
+    AlgorithmConfig, algorithm_config::AlgorithmConfigError, rate_limiter::AlgorithmType,
+};
+
+// what a RateLimiter does with a key instead of the default bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum KeyPolicy {
+    // never limited, no bucket is created for the key
+    Allow,
+    // always denied, no bucket is created for the key
+    Deny,
+    // own bucket with its own limit, same algorithm as the limiter's
+    Limit(AlgorithmConfig),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OverrideError {
+    InvalidPolicy(String),
+    InvalidRange(String),
+    // the limit doesn't fit the limiter's algorithm, e.g. a zero rate
+    InvalidLimit(String, AlgorithmConfigError),
+}
+
+impl KeyPolicy {
+    // "allow", "deny" or "<capacity>:<refill_rate>", the refill rate being the window
+    // in seconds for window based algorithms, e.g. "10:0.5" for 10 per 500ms
+    pub fn parse(raw: &str, algorithm: AlgorithmType) -> Result<Self, OverrideError> {
+        let invalid = || OverrideError::InvalidPolicy(raw.to_string());
+
+        match raw.trim() {
+            "allow" => Ok(KeyPolicy::Allow),
+            "deny" => Ok(KeyPolicy::Deny),
+            limit => {
+                let (capacity, refill_rate) = limit.split_once(':').ok_or_else(invalid)?;
+                let capacity = capacity.trim().parse().map_err(|_| invalid())?;
+                let refill_rate = refill_rate.trim();
+
+                let builder = AlgorithmConfig::builder(algorithm.clone()).with_capacity(capacity);
+                let builder = match algorithm {
+                    AlgorithmType::TokenBucket | AlgorithmType::Gcra => {
+                        builder.with_rate(refill_rate.parse().map_err(|_| invalid())?)
+                    }
+                    _ => {
+                        let secs: f64 = refill_rate.parse().map_err(|_| invalid())?;
+                        builder
+                            .with_window(Duration::try_from_secs_f64(secs).map_err(|_| invalid())?)
+                    }
+                };
+                builder
+                    .build()
+                    .map(KeyPolicy::Limit)
+                    .map_err(|err| OverrideError::InvalidLimit(raw.to_string(), err))
+            }
+        }
+    }
+}
+
+// Looked up by RateLimiter::check before a bucket is created for the key
+pub trait OverrideLookup<K>: Send + Sync {
+    fn lookup(&self, key: &K) -> Option<KeyPolicy>;
+}
+
+// exact key matches, e.g. route names
+pub struct KeyOverrides<K>
+where
+    K: Eq + Hash,
+{
+    keys: HashMap<K, KeyPolicy>,
+}
+
+impl<K> KeyOverrides<K>
+where
+    K: Eq + Hash,
+{
+    pub fn new() -> Self {
+        Self {
+            keys: HashMap::new(),
+        }
+    }
+
+    pub fn key(mut self, key: K, policy: KeyPolicy) -> Self {
+        self.keys.insert(key, policy);
+        self
+    }
+}
+
+impl<K> Default for KeyOverrides<K>
+where
+    K: Eq + Hash,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K> OverrideLookup<K> for KeyOverrides<K>
+where
+    K: Eq + Hash + Send + Sync,
+{
+    fn lookup(&self, key: &K) -> Option<KeyPolicy> {
+        self.keys.get(key).copied()
+    }
+}
+
+// CIDR block, a bare address is a block of one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Self> {
+        let addr = addr.to_canonical();
+        if prefix_len > max_prefix_len(&addr) {
+            return None;
+        }
+
+        Some(Self {
+            network: mask(addr, prefix_len),
+            prefix_len,
+        })
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn is_single_address(&self) -> bool {
+        self.prefix_len == max_prefix_len(&self.network)
+    }
+
+    pub fn network(&self) -> IpAddr {
+        self.network
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        ip.is_ipv4() == self.network.is_ipv4() && mask(ip, self.prefix_len) == self.network
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = OverrideError;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let invalid = || OverrideError::InvalidRange(raw.to_string());
+        let raw = raw.trim();
+
+        let (addr, prefix_len) = match raw.split_once('/') {
+            Some((addr, prefix_len)) => {
+                let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+                (addr, prefix_len.parse().map_err(|_| invalid())?)
+            }
+            None => {
+                let addr: IpAddr = raw.parse().map_err(|_| invalid())?;
+                (addr, max_prefix_len(&addr.to_canonical()))
+            }
+        };
+
+        IpRange::new(addr, prefix_len).ok_or_else(invalid)
+    }
+}
+
+fn max_prefix_len(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn mask(addr: IpAddr, prefix_len: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(v4) => {
+            let bits = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(v4) & bits))
+        }
+        IpAddr::V6(v6) => {
+            let bits = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(v6) & bits))
+        }
+    }
+}
+
+// exact addresses first, then the most specific range containing the address
+pub struct IpOverrides {
+    addrs: HashMap<IpAddr, KeyPolicy>,
+    // sorted by prefix length, longest first
+    ranges: Vec<(IpRange, KeyPolicy)>,
+}
+
+impl IpOverrides {
+    pub fn new() -> Self {
+        Self {
+            addrs: HashMap::new(),
+            ranges: Vec::new(),
+        }
+    }
+
+    pub fn range(mut self, range: IpRange, policy: KeyPolicy) -> Self {
+        if range.is_single_address() {
+            self.addrs.insert(range.network(), policy);
+            return self;
+        }
+
+        self.ranges.push((range, policy));
+        self.ranges
+            .sort_by_key(|(range, _)| std::cmp::Reverse(range.prefix_len()));
+        self
+    }
+}
+
+impl Default for IpOverrides {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OverrideLookup<IpAddr> for IpOverrides {
+    fn lookup(&self, key: &IpAddr) -> Option<KeyPolicy> {
+        if let Some(policy) = self.addrs.get(&key.to_canonical()) {
+            return Some(*policy);
+        }
+
+        self.ranges
+            .iter()
+            .find(|(range, _)| range.contains(key))
+            .map(|(_, policy)| *policy)
+    }
+}
+
+// A key whose policy the caller already looked up somewhere else, e.g. an ip tier
+// keyed by api key whose overrides still match the client address. The same key
+// under two policies gets two buckets
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ResolvedKey<K> {
+    pub key: K,
+    pub policy: Option<KeyPolicy>,
+}
+
+impl<K> ResolvedKey<K> {
+    pub fn new(key: K, policy: Option<KeyPolicy>) -> Self {
+        Self { key, policy }
+    }
+}
+
+// reads the policy a ResolvedKey carries
+pub struct ResolvedOverrides;
+
+impl<K> OverrideLookup<ResolvedKey<K>> for ResolvedOverrides {
+    fn lookup(&self, key: &ResolvedKey<K>) -> Option<KeyPolicy> {
+        key.policy
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    fn ip(raw: &str) -> IpAddr {
+        raw.parse().unwrap()
+    }
+
+    #[test]
+    pub fn parse_policies_and_ranges() {
+        let parse = |raw| KeyPolicy::parse(raw, AlgorithmType::TokenBucket);
+        assert_eq!(parse("allow"), Ok(KeyPolicy::Allow));
+        assert_eq!(parse("deny"), Ok(KeyPolicy::Deny));
+        assert_eq!(
+            parse("100:10"),
+            Ok(KeyPolicy::Limit(AlgorithmConfig::TokenBucket {
+                burst: 100,
+                rate: 10
+            }))
+        );
+        assert_eq!(
+            KeyPolicy::parse("100:60", AlgorithmType::FixedWindow),
+            Ok(KeyPolicy::Limit(AlgorithmConfig::FixedWindow {
+                limit: 100,
+                window: Duration::from_secs(60)
+            }))
+        );
+        assert_eq!(
+            KeyPolicy::parse("10:0.5", AlgorithmType::SlidingLog),
+            Ok(KeyPolicy::Limit(AlgorithmConfig::SlidingLog {
+                limit: 10,
+                window: Duration::from_millis(500)
+            }))
+        );
+        assert!(parse("100").is_err());
+        assert!(parse("100:0.5").is_err());
+        assert!(parse("maybe").is_err());
+        // limits are validated like the tier's own
+        assert_eq!(
+            KeyPolicy::parse("5:0", AlgorithmType::Gcra),
+            Err(OverrideError::InvalidLimit(
+                "5:0".to_string(),
+                AlgorithmConfigError::ZeroRate
+            ))
+        );
+        assert!(matches!(
+            KeyPolicy::parse("0:60", AlgorithmType::SlidingLog),
+            Err(OverrideError::InvalidLimit(
+                _,
+                AlgorithmConfigError::ZeroCapacity
+            ))
+        ));
+
+        let range: IpRange = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(range.network(), ip("10.0.0.0"));
+        assert!(range.contains(&ip("10.200.0.1")));
+        assert!(!range.contains(&ip("11.0.0.1")));
+        assert!(range.contains(&ip("::ffff:10.0.0.1")));
+
+        assert!(
+            "2001:db8::1"
+                .parse::<IpRange>()
+                .unwrap()
+                .is_single_address()
+        );
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("10.0.0/8".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    pub fn most_specific_match_wins() {
+        let partner = KeyPolicy::Limit(AlgorithmConfig::TokenBucket {
+            burst: 100,
+            rate: 10,
+        });
+        let overrides = IpOverrides::new()
+            .range("10.0.0.0/8".parse().unwrap(), KeyPolicy::Allow)
+            .range("10.1.0.0/16".parse().unwrap(), partner)
+            .range("10.1.2.3".parse().unwrap(), KeyPolicy::Deny);
+
+        assert_eq!(overrides.lookup(&ip("10.9.9.9")), Some(KeyPolicy::Allow));
+        assert_eq!(overrides.lookup(&ip("10.1.9.9")), Some(partner));
+        assert_eq!(overrides.lookup(&ip("10.1.2.3")), Some(KeyPolicy::Deny));
+        assert_eq!(overrides.lookup(&ip("192.168.0.1")), None);
+
+        let key = ResolvedKey::new("h:x-api-key:k1", Some(KeyPolicy::Deny));
+        assert_eq!(ResolvedOverrides.lookup(&key), Some(KeyPolicy::Deny));
+    }
+}
@@ -0,0 +1,369 @@
+use chrono::{Datelike, Months, NaiveDate, NaiveTime, TimeZone};
+use chrono_tz::Tz;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::{hash::Hash, path::Path, sync::Arc, time::Duration};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    rate_limiter::{
+        algorithm::BucketState,
+        rate_limiter::{RateLimitError, RateLimitErrorKind},
+        snapshot::{SnapshotError, read_versioned, write_json},
+    },
+};
+
+// bump whenever QuotaSnapshot changes shape
+pub const QUOTA_SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QuotaPeriod {
+    // resets at local midnight
+    Daily,
+    // resets at local midnight on the first of the month
+    Monthly,
+}
+
+// usage within the period that started at `period_start` (unix seconds)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct QuotaCounter {
+    period_start: i64,
+    used: u128,
+}
+
+// Long-horizon request quota, counters reset on calendar boundaries in
+// `timezone` instead of refilling continuously like the rate limiters
+#[derive(Clone)]
+pub struct QuotaLimiter<K>
+where
+    K: Eq + Hash,
+{
+    limit: u128,
+    period: QuotaPeriod,
+    timezone: Tz,
+    // wall clock, the calendar can't be derived from an Instant
+    clock: Arc<dyn Clock>,
+    counters: Arc<DashMap<K, QuotaCounter>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedQuota<K> {
+    pub key: K,
+    pub period_start: i64,
+    pub used: u128,
+}
+
+// counters carry absolute period starts, so a snapshot of any age can be
+// restored and only the counters of the current period come back
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuotaSnapshot<K> {
+    pub version: u32,
+    pub period: QuotaPeriod,
+    pub timezone: String,
+    pub counters: Vec<SavedQuota<K>>,
+}
+
+impl<K> QuotaSnapshot<K>
+where
+    K: Serialize + DeserializeOwned,
+{
+    pub fn write(&self, path: &Path) -> Result<(), SnapshotError> {
+        write_json(path, self)
+    }
+
+    pub fn read(path: &Path) -> Result<Self, SnapshotError> {
+        read_versioned(path, QUOTA_SNAPSHOT_VERSION)
+    }
+}
+
+impl<K> QuotaLimiter<K>
+where
+    K: Eq + Hash + Send + Sync + 'static,
+{
+    pub fn new(limit: u128, period: QuotaPeriod, timezone: Tz) -> Self {
+        Self {
+            limit,
+            period,
+            timezone,
+            clock: Arc::new(SystemClock),
+            counters: Arc::new(DashMap::new()),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn limit(&self) -> u128 {
+        self.limit
+    }
+
+    pub fn period(&self) -> QuotaPeriod {
+        self.period
+    }
+
+    pub fn timezone(&self) -> Tz {
+        self.timezone
+    }
+
+    pub fn check(&self, key: K, cost: u128) -> Result<BucketState, RateLimitError> {
+        let now = self.clock.unix_time();
+        let (start, end) = self.period_bounds(now);
+        let reset_after = Duration::from_secs(end.max(0) as u64).saturating_sub(now);
+
+        let mut counter = self.counters.entry(key).or_insert(QuotaCounter {
+            period_start: start,
+            used: 0,
+        });
+        if counter.period_start != start {
+            *counter = QuotaCounter {
+                period_start: start,
+                used: 0,
+            };
+        }
+
+        let state = |used: u128| BucketState {
+            limit: self.limit,
+            remaining: self.limit.saturating_sub(used),
+            reset_after,
+        };
+
+        if cost > self.limit {
+            return Err(RateLimitError {
+                kind: RateLimitErrorKind::CostExceedsCapacity,
+                retry_after: Duration::ZERO,
+                snapshot: state(counter.used),
+            });
+        }
+        if counter.used + cost > self.limit {
+            return Err(RateLimitError {
+                kind: RateLimitErrorKind::QuotaExceeded,
+                retry_after: reset_after,
+                snapshot: state(counter.used),
+            });
+        }
+
+        counter.used += cost;
+        Ok(state(counter.used))
+    }
+
+    // usage in the current period without charging, unknown keys have all of it left
+    pub fn peek(&self, key: &K) -> BucketState {
+        let now = self.clock.unix_time();
+        let (start, end) = self.period_bounds(now);
+
+        let used = self
+            .counters
+            .get(key)
+            .filter(|counter| counter.period_start == start)
+            .map_or(0, |counter| counter.used);
+
+        BucketState {
+            limit: self.limit,
+            remaining: self.limit.saturating_sub(used),
+            reset_after: Duration::from_secs(end.max(0) as u64).saturating_sub(now),
+        }
+    }
+
+    // gives back `cost` units charged in the current period
+    pub fn refund(&self, key: &K, cost: u128) {
+        let (start, _) = self.period_bounds(self.clock.unix_time());
+
+        if let Some(mut counter) = self.counters.get_mut(key)
+            && counter.period_start == start
+        {
+            counter.used = counter.used.saturating_sub(cost);
+        }
+    }
+
+    // drops counters left over from earlier periods
+    pub fn cleanup(&self) {
+        let (start, _) = self.period_bounds(self.clock.unix_time());
+        self.counters
+            .retain(|_, counter| counter.period_start == start);
+    }
+
+    pub fn keys(&self) -> usize {
+        self.counters.len()
+    }
+
+    // seconds since unix epoch of the start of the current period and the next one
+    pub fn period_bounds(&self, unix_time: Duration) -> (i64, i64) {
+        let now = self
+            .timezone
+            .timestamp_opt(unix_time.as_secs() as i64, 0)
+            .unwrap();
+        let today = now.date_naive();
+
+        let (start, next) = match self.period {
+            QuotaPeriod::Daily => (today, today.succ_opt().unwrap()),
+            QuotaPeriod::Monthly => {
+                let first = today.with_day(1).unwrap();
+                (first, first.checked_add_months(Months::new(1)).unwrap())
+            }
+        };
+
+        (self.start_of_day(start), self.start_of_day(next))
+    }
+
+    // a DST jump at midnight skips 00:00 in some zones, the day then starts at
+    // the first hour that exists
+    fn start_of_day(&self, date: NaiveDate) -> i64 {
+        (0..24)
+            .filter_map(|hour| {
+                let time = NaiveTime::from_hms_opt(hour, 0, 0)?;
+                self.timezone
+                    .from_local_datetime(&date.and_time(time))
+                    .earliest()
+            })
+            .map(|start| start.timestamp())
+            .next()
+            .unwrap()
+    }
+}
+
+impl<K> QuotaLimiter<K>
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+{
+    pub fn snapshot(&self) -> QuotaSnapshot<K> {
+        QuotaSnapshot {
+            version: QUOTA_SNAPSHOT_VERSION,
+            period: self.period,
+            timezone: self.timezone.name().to_string(),
+            counters: self
+                .counters
+                .iter()
+                .map(|entry| SavedQuota {
+                    key: entry.key().clone(),
+                    period_start: entry.period_start,
+                    used: entry.used,
+                })
+                .collect(),
+        }
+    }
+
+    // the limit may change between restarts, usage is kept as is,
+    // returns how many counters were restored
+    pub fn restore(&self, snapshot: QuotaSnapshot<K>) -> Result<usize, SnapshotError> {
+        if snapshot.version != QUOTA_SNAPSHOT_VERSION {
+            return Err(SnapshotError::IncompatibleVersion {
+                found: snapshot.version,
+                expected: QUOTA_SNAPSHOT_VERSION,
+            });
+        }
+        if snapshot.period != self.period || snapshot.timezone != self.timezone.name() {
+            return Err(SnapshotError::ConfigMismatch);
+        }
+
+        let (start, _) = self.period_bounds(self.clock.unix_time());
+        let mut restored = 0;
+
+        for saved in snapshot.counters {
+            if saved.period_start != start {
+                continue;
+            }
+
+            self.counters.insert(
+                saved.key,
+                QuotaCounter {
+                    period_start: saved.period_start,
+                    used: saved.used,
+                },
+            );
+            restored += 1;
+        }
+
+        Ok(restored)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn unix(rfc3339: &str) -> Duration {
+        let time = chrono::DateTime::parse_from_rfc3339(rfc3339).unwrap();
+        Duration::from_secs(time.timestamp() as u64)
+    }
+
+    #[test]
+    pub fn daily_quota_resets_at_local_midnight() {
+        // 22:00 in Berlin (CEST, UTC+2)
+        let clock = Arc::new(MockClock::at(unix("2025-06-10T20:00:00Z")));
+        let quota = QuotaLimiter::new(3, QuotaPeriod::Daily, chrono_tz::Europe::Berlin)
+            .with_clock(clock.clone());
+
+        assert!(quota.check("key", 3).is_ok());
+        let err = quota.check("key", 1).err().unwrap();
+        assert_eq!(err.kind, RateLimitErrorKind::QuotaExceeded);
+        assert_eq!(err.retry_after, 2 * HOUR);
+
+        // still the same day locally
+        clock.advance(HOUR + Duration::from_secs(3599));
+        assert!(quota.check("key", 1).is_err());
+
+        // 00:00 Berlin, 22:00 UTC
+        clock.advance(Duration::from_secs(1));
+        let state = quota.check("key", 1).ok().unwrap();
+        assert_eq!(state.remaining, 2);
+        assert_eq!(state.reset_after, 24 * HOUR);
+    }
+
+    #[test]
+    pub fn monthly_quota_follows_month_length_and_dst() {
+        // 2025-03-15 12:00 in New York, DST starts on 2025-03-09
+        let clock = Arc::new(MockClock::at(unix("2025-03-15T16:00:00Z")));
+        let quota = QuotaLimiter::new(10, QuotaPeriod::Monthly, chrono_tz::America::New_York)
+            .with_clock(clock.clone());
+
+        let (start, end) = quota.period_bounds(clock.unix_time());
+        assert_eq!(start as u64, unix("2025-03-01T05:00:00Z").as_secs());
+        assert_eq!(end as u64, unix("2025-04-01T04:00:00Z").as_secs());
+
+        assert!(quota.check("key", 10).is_ok());
+        assert!(quota.check("key", 1).is_err());
+        quota.refund(&"key", 4);
+        assert!(quota.check("key", 4).is_ok());
+
+        clock.advance(Duration::from_secs(end as u64) - clock.unix_time());
+        assert!(quota.check("key", 10).is_ok());
+    }
+
+    #[test]
+    pub fn restore_keeps_only_the_current_period() {
+        let clock = Arc::new(MockClock::at(unix("2025-06-10T12:00:00Z")));
+        let before =
+            QuotaLimiter::new(5, QuotaPeriod::Daily, chrono_tz::UTC).with_clock(clock.clone());
+        assert!(before.check("key".to_string(), 4).is_ok());
+        let snapshot = before.snapshot();
+
+        let path = std::env::temp_dir().join(format!("quota-{}.json", std::process::id()));
+        snapshot.write(&path).unwrap();
+        let snapshot = QuotaSnapshot::<String>::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let after =
+            QuotaLimiter::new(5, QuotaPeriod::Daily, chrono_tz::UTC).with_clock(clock.clone());
+        assert!(matches!(after.restore(snapshot.clone()), Ok(1)));
+        assert!(after.check("key".to_string(), 2).is_err());
+        assert!(after.check("key".to_string(), 1).is_ok());
+
+        // restarted the next day, yesterday's usage is gone
+        clock.advance(24 * HOUR);
+        let next_day =
+            QuotaLimiter::new(5, QuotaPeriod::Daily, chrono_tz::UTC).with_clock(clock.clone());
+        assert!(matches!(next_day.restore(snapshot.clone()), Ok(0)));
+
+        let monthly =
+            QuotaLimiter::new(5, QuotaPeriod::Monthly, chrono_tz::UTC).with_clock(clock.clone());
+        assert!(matches!(
+            monthly.restore(snapshot),
+            Err(SnapshotError::ConfigMismatch)
+        ));
+    }
+}
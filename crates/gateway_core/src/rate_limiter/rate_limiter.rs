@@ -1,85 +1,803 @@
-use crate::rate_limiter::{
-    TokenBucket,
-    algorithm::{self, AllowResult, BucketState, RateLimitAlgorithm},
-    sliding_log::SlidingLog,
+use crate::{
+    clock::{Clock, SystemClock},
+    rate_limiter::{
+        AlgorithmConfig, FixedWindow, Gcra, TokenBucket,
+        acquire::WaitQueues,
+        algorithm::{BucketState, RateLimitAlgorithm},
+        bucket_store::{Bucket, BucketStore, StoreType, check_bucket},
+        key_limit::{EvictionPolicy, KeyLimit},
+        overrides::{KeyPolicy, OverrideLookup},
+        sliding_counter::SlidingCounter,
+        sliding_log::SlidingLog,
+        snapshot::{LimiterSnapshot, SNAPSHOT_VERSION, SavedEntry, SnapshotError},
+    },
 };
-use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
 use std::{
-    net::IpAddr,
-    sync::Arc,
+    collections::HashMap,
+    hash::Hash,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
     time::{Duration, Instant},
 };
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitErrorKind {
+    Limited,
+    // the request costs more than the limiter capacity, it can never be allowed
+    CostExceedsCapacity,
+    // the shared bucket store could not be reached and the limiter fails closed
+    StoreUnavailable,
+    // the key is on the denylist, waiting will never help
+    Blocked,
+    // the daily/monthly quota is used up until the period resets
+    QuotaExceeded,
+}
+
 pub struct RateLimitError {
+    pub kind: RateLimitErrorKind,
     pub retry_after: Duration,
     pub snapshot: BucketState,
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum AlgorithmType {
     TokenBucket,
     SlidingLog,
     SlidingCounter,
+    Gcra,
+    FixedWindow,
+}
+
+// everything a store needs to create a new bucket
+#[derive(Clone)]
+pub struct BucketConfig {
+    pub limit: AlgorithmConfig,
+    // the limiter's clock, fixed windows are aligned to its wall-clock time
+    pub clock: Arc<dyn Clock>,
+}
+
+impl BucketConfig {
+    pub fn capacity(&self) -> u128 {
+        self.limit.capacity()
+    }
+
+    pub fn build(&self, now: Instant) -> Bucket {
+        match self.limit {
+            AlgorithmConfig::TokenBucket { burst, rate } => {
+                Box::new(TokenBucket::new(burst, rate, now)) as Box<dyn RateLimitAlgorithm>
+            }
+            AlgorithmConfig::SlidingLog { limit, window } => {
+                Box::new(SlidingLog::new(limit, window, now)) as Box<dyn RateLimitAlgorithm>
+            }
+            AlgorithmConfig::SlidingCounter { limit, window } => {
+                Box::new(SlidingCounter::new(limit, window, now))
+                    as Box<dyn RateLimitAlgorithm + Send + Sync>
+            }
+            AlgorithmConfig::Gcra { burst, rate } => {
+                Box::new(Gcra::new(burst, rate, now)) as Box<dyn RateLimitAlgorithm>
+            }
+            AlgorithmConfig::FixedWindow { limit, window } => {
+                // wall-clock time that matches `now` on the limiter's clock
+                let (clock_now, unix_time) = (self.clock.now(), self.clock.unix_time());
+                let wall = match now.checked_duration_since(clock_now) {
+                    Some(ahead) => unix_time + ahead,
+                    None => unix_time.saturating_sub(clock_now - now),
+                };
+                Box::new(FixedWindow::with_wall_clock(limit, window, now, wall))
+                    as Box<dyn RateLimitAlgorithm>
+            }
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct RateLimiter<K>
 where
-    K: Eq + std::hash::Hash,
+    K: Eq + Hash,
 {
-    buckets: Arc<DashMap<K, Box<dyn RateLimitAlgorithm>>>,
-    capacity: u128,
-    refill_rate: u128,
-    algorithm: AlgorithmType,
+    store: Arc<dyn BucketStore<K>>,
+    config: BucketConfig,
+    // only used where the caller doesn't pass `now` (cleanup, acquire)
+    pub(crate) clock: Arc<dyn Clock>,
+    key_limit: Option<KeyLimit>,
+    // shared by every new key while the map is full (EvictionPolicy::Overflow), one
+    // per limit so keys under an override keep their own limit
+    overflow: Arc<Mutex<HashMap<AlgorithmConfig, Bucket>>>,
+    evictions: Arc<AtomicU64>,
+    overflowed: Arc<AtomicU64>,
+    overrides: Option<Arc<dyn OverrideLookup<K>>>,
+    // callers of acquire lined up per key
+    pub(crate) waiters: WaitQueues<K>,
 }
 
 impl<K> RateLimiter<K>
 where
-    K: Eq + std::hash::Hash,
+    K: Eq + Hash + Send + Sync + 'static,
 {
-    pub fn new(capacity: u128, refill_rate: u128, algorithm: AlgorithmType) -> Self {
+    // see AlgorithmConfig::builder for a limit checked against its algorithm
+    pub fn new(limit: AlgorithmConfig) -> Self {
+        Self::with_store(limit, StoreType::DashMap.build())
+    }
+
+    pub fn with_store(limit: AlgorithmConfig, store: Arc<dyn BucketStore<K>>) -> Self {
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
         Self {
-            buckets: Arc::new(DashMap::new()),
-            capacity,
-            refill_rate,
-            algorithm,
+            store,
+            config: BucketConfig {
+                limit,
+                clock: clock.clone(),
+            },
+            clock,
+            key_limit: None,
+            overflow: Arc::new(Mutex::new(HashMap::new())),
+            evictions: Arc::new(AtomicU64::new(0)),
+            overflowed: Arc::new(AtomicU64::new(0)),
+            overrides: None,
+            waiters: WaitQueues::new(),
         }
     }
 
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.config.clock = clock.clone();
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_key_limit(mut self, key_limit: KeyLimit) -> Self {
+        self.key_limit = Some(key_limit);
+        self
+    }
+
+    pub fn with_overrides(mut self, overrides: Arc<dyn OverrideLookup<K>>) -> Self {
+        self.overrides = Some(overrides);
+        self
+    }
+
+    pub fn capacity(&self) -> u128 {
+        self.config.capacity()
+    }
+
+    pub fn limit(&self) -> AlgorithmConfig {
+        self.config.limit
+    }
+
+    // None for allowlisted and denylisted keys, they never get a bucket
+    fn config_for(&self, policy: Option<KeyPolicy>) -> Option<BucketConfig> {
+        match policy {
+            None => Some(self.config.clone()),
+            Some(KeyPolicy::Limit(limit)) => Some(BucketConfig {
+                limit,
+                clock: self.config.clock.clone(),
+            }),
+            Some(KeyPolicy::Allow) | Some(KeyPolicy::Deny) => None,
+        }
+    }
+
+    fn policy(&self, key: &K) -> Option<KeyPolicy> {
+        self.overrides
+            .as_ref()
+            .and_then(|overrides| overrides.lookup(key))
+    }
+
     pub fn check(&self, key: K, now: Instant) -> Result<BucketState, RateLimitError> {
-        let mut bucket = self
-            .buckets
-            .entry(key)
-            .or_insert_with(|| match self.algorithm {
-                AlgorithmType::TokenBucket => {
-                    Box::new(TokenBucket::new(self.capacity, self.refill_rate, now))
-                        as Box<dyn RateLimitAlgorithm>
-                }
-                AlgorithmType::SlidingLog => {
-                    Box::new(SlidingLog::new(self.capacity, self.refill_rate, now))
-                        as Box<dyn RateLimitAlgorithm>
+        self.check_with_cost(key, now, 1)
+    }
+
+    pub fn check_with_cost(
+        &self,
+        key: K,
+        now: Instant,
+        cost: u128,
+    ) -> Result<BucketState, RateLimitError> {
+        let override_config;
+        let config = match self.policy(&key) {
+            Some(KeyPolicy::Allow) => {
+                return Ok(BucketState {
+                    limit: self.config.capacity(),
+                    remaining: self.config.capacity(),
+                    reset_after: Duration::ZERO,
+                });
+            }
+            Some(KeyPolicy::Deny) => {
+                return Err(RateLimitError {
+                    kind: RateLimitErrorKind::Blocked,
+                    retry_after: Duration::ZERO,
+                    snapshot: BucketState {
+                        limit: self.config.capacity(),
+                        remaining: 0,
+                        reset_after: Duration::ZERO,
+                    },
+                });
+            }
+            // override keys count against the key limit too, a client rotating keys
+            // inside an overridden range can't grow the map either
+            Some(KeyPolicy::Limit(limit)) => {
+                override_config = BucketConfig {
+                    limit,
+                    clock: self.config.clock.clone(),
+                };
+                &override_config
+            }
+            None => &self.config,
+        };
+
+        if let Some(limit) = self.key_limit
+            && !self.store.contains(&key)
+            && self.store.key_count() >= limit.max_keys
+        {
+            match limit.policy {
+                EvictionPolicy::LeastRecentlySeen => {
+                    let evicted = self.store.evict(limit.eviction_batch());
+                    self.evictions.fetch_add(evicted as u64, Ordering::Relaxed);
                 }
-                AlgorithmType::SlidingCounter => {
-                    Box::new(SlidingCounter::new(self.capacity, self.refill_rate, now))
-                        as Box<dyn RateLimitAlgorithm + Send + Sync>
+                EvictionPolicy::Overflow => {
+                    self.overflowed.fetch_add(1, Ordering::Relaxed);
+                    let mut overflow = self.overflow.lock().unwrap();
+                    let bucket = overflow
+                        .entry(config.limit)
+                        .or_insert_with(|| config.build(now));
+                    return check_bucket(bucket, now, cost);
                 }
-            });
+            }
+        }
+
+        self.store.check(key, now, cost, config)
+    }
 
-        bucket.set_last_seen(now);
+    // gives back `cost` units charged by an earlier check of the same key
+    pub fn refund(&self, key: &K, now: Instant, cost: u128) {
+        let policy = self.policy(key);
+        let Some(config) = self.config_for(policy) else {
+            return;
+        };
 
-        match bucket.allow(now) {
-            AllowResult::Allowed => Ok(bucket.state(now)),
-            AllowResult::Denied { retry_after } => Err(RateLimitError {
-                retry_after,
-                snapshot: bucket.state(now),
-            }),
+        if self.store.contains(key) || self.key_limit.is_none() {
+            self.store.refund(key, now, cost, &config);
+            return;
+        }
+
+        // the key isn't tracked, so it was charged to the overflow bucket of its limit
+        if let Some(bucket) = self.overflow.lock().unwrap().get_mut(&config.limit) {
+            bucket.refund(now, cost);
         }
     }
 
+    // what a check of `key` would see right now, without charging it
+    pub fn peek(&self, key: &K, now: Instant) -> BucketState {
+        let policy = self.policy(key);
+        let Some(config) = self.config_for(policy) else {
+            let remaining = match policy {
+                Some(KeyPolicy::Allow) => self.config.capacity(),
+                _ => 0,
+            };
+            return BucketState {
+                limit: self.config.capacity(),
+                remaining,
+                reset_after: Duration::ZERO,
+            };
+        };
+
+        if let Some(state) = self.store.peek(key, now, &config) {
+            return state;
+        }
+
+        // an untracked key on a full map is checked against the overflow bucket
+        if let Some(limit) = self.key_limit
+            && limit.policy == EvictionPolicy::Overflow
+            && self.store.key_count() >= limit.max_keys
+            && let Some(bucket) = self.overflow.lock().unwrap().get(&config.limit)
+        {
+            return bucket.peek(now);
+        }
+
+        config.build(now).peek(now)
+    }
+
     pub fn cleanup(&self, ttl: Duration) {
-        let now = Instant::now();
+        self.store.cleanup(self.clock.now(), ttl);
+    }
+
+    // number of keys currently tracked, the overflow bucket not included
+    pub fn keys(&self) -> usize {
+        self.store.key_count()
+    }
+
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    // checks that went to the overflow bucket because the map was full
+    pub fn overflowed(&self) -> u64 {
+        self.overflowed.load(Ordering::Relaxed)
+    }
+}
+
+impl<K> RateLimiter<K>
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+{
+    pub fn snapshot(&self) -> LimiterSnapshot<K> {
+        let now = self.clock.now();
+        let mut buckets = Vec::new();
+
+        self.store.for_each(&mut |key, bucket| {
+            buckets.push(SavedEntry {
+                key: key.clone(),
+                idle: now.saturating_duration_since(bucket.last_seen()),
+                bucket: bucket.save(now),
+            })
+        });
+
+        LimiterSnapshot {
+            version: SNAPSHOT_VERSION,
+            taken_at: self.clock.unix_time(),
+            limit: self.config.limit,
+            buckets,
+        }
+    }
+
+    // the time the gateway was down counts as elapsed time for every bucket,
+    // returns how many buckets were restored
+    pub fn restore(
+        &self,
+        snapshot: LimiterSnapshot<K>,
+        max_age: Duration,
+    ) -> Result<usize, SnapshotError> {
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::IncompatibleVersion {
+                found: snapshot.version,
+                expected: SNAPSHOT_VERSION,
+            });
+        }
+        if snapshot.limit != self.config.limit {
+            return Err(SnapshotError::ConfigMismatch);
+        }
+
+        let age = match self.clock.unix_time().checked_sub(snapshot.taken_at) {
+            Some(age) if age <= max_age => age,
+            age => return Err(SnapshotError::Stale { age }),
+        };
+
+        let now = self.clock.now();
+        let Some(taken_at) = now.checked_sub(age) else {
+            return Err(SnapshotError::Stale { age: Some(age) });
+        };
+
+        let mut restored = 0;
+        for entry in snapshot.buckets {
+            let Some(last_seen) = taken_at.checked_sub(entry.idle) else {
+                continue;
+            };
+
+            // overrides may have changed since, drop keys that no longer get a bucket
+            let Some(config) = self.config_for(self.policy(&entry.key)) else {
+                continue;
+            };
+            let mut bucket = config.build(now);
+            if !bucket.load(&entry.bucket, taken_at) {
+                continue;
+            }
+            bucket.set_last_seen(last_seen);
+
+            self.store.insert(entry.key, bucket, &config);
+            restored += 1;
+        }
+
+        Ok(restored)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    const ALGORITHMS: [AlgorithmType; 5] = [
+        AlgorithmType::TokenBucket,
+        AlgorithmType::SlidingLog,
+        AlgorithmType::SlidingCounter,
+        AlgorithmType::Gcra,
+        AlgorithmType::FixedWindow,
+    ];
+
+    // `per` is the rate of token_bucket and gcra, the window in seconds of the others
+    pub fn limit(algorithm: AlgorithmType, capacity: u128, per: u64) -> AlgorithmConfig {
+        let builder = AlgorithmConfig::builder(algorithm.clone()).with_capacity(capacity);
+        match algorithm {
+            AlgorithmType::TokenBucket | AlgorithmType::Gcra => builder.with_rate(per as u128),
+            _ => builder.with_window(Duration::from_secs(per)),
+        }
+        .build()
+        .unwrap()
+    }
+
+    #[test]
+    pub fn cost_consumes_multiple_units() {
+        for algorithm in ALGORITHMS {
+            let limiter = RateLimiter::new(limit(algorithm, 10, 3600));
+            let t0 = Instant::now();
+
+            let snapshot = limiter.check_with_cost("key", t0, 4).ok().unwrap();
+            assert_eq!(snapshot.remaining, 6);
+
+            assert!(limiter.check_with_cost("key", t0, 6).is_ok());
+
+            let err = limiter.check_with_cost("key", t0, 1).err().unwrap();
+            assert_eq!(err.kind, RateLimitErrorKind::Limited);
+        }
+    }
+
+    #[test]
+    pub fn denied_cost_does_not_consume() {
+        for algorithm in ALGORITHMS {
+            let limiter = RateLimiter::new(limit(algorithm, 10, 3600));
+            let t0 = Instant::now();
+
+            assert!(limiter.check_with_cost("key", t0, 8).is_ok());
+            assert!(limiter.check_with_cost("key", t0, 3).is_err());
+            assert!(limiter.check_with_cost("key", t0, 2).is_ok());
+        }
+    }
+
+    #[test]
+    pub fn stores_behave_the_same() {
+        let stores = [
+            StoreType::DashMap,
+            StoreType::Mutex,
+            StoreType::Sharded { shards: 4 },
+        ];
+
+        for store in stores {
+            let limiter = RateLimiter::with_store(
+                AlgorithmConfig::TokenBucket { burst: 3, rate: 1 },
+                store.build(),
+            );
+            let t0 = Instant::now();
+
+            for key in ["a", "b"] {
+                for _ in 0..3 {
+                    assert!(limiter.check(key, t0).is_ok());
+                }
+                assert!(limiter.check(key, t0).is_err());
+            }
+            assert!(limiter.check("a", t0 + Duration::from_secs(1)).is_ok());
+        }
+    }
+
+    #[test]
+    pub fn cost_above_capacity_is_rejected() {
+        for algorithm in ALGORITHMS {
+            let limiter = RateLimiter::new(limit(algorithm, 10, 1));
+            let t0 = Instant::now();
+
+            let err = limiter.check_with_cost("key", t0, 11).err().unwrap();
+            assert_eq!(err.kind, RateLimitErrorKind::CostExceedsCapacity);
+            assert_eq!(err.snapshot.remaining, 10);
+        }
+    }
+
+    #[test]
+    pub fn cleanup_follows_the_clock() {
+        let clock = MockClock::new();
+        let limiter = RateLimiter::new(limit(AlgorithmType::SlidingLog, 1, 3600))
+            .with_clock(Arc::new(clock.clone()));
+
+        assert!(limiter.check("key", clock.now()).is_ok());
+        assert!(limiter.check("key", clock.now()).is_err());
+
+        // still within the ttl, the bucket survives
+        clock.advance(Duration::from_secs(4));
+        limiter.cleanup(Duration::from_secs(5));
+        assert!(limiter.check("key", clock.now()).is_err());
+
+        clock.advance(Duration::from_secs(6));
+        limiter.cleanup(Duration::from_secs(5));
+        assert!(limiter.check("key", clock.now()).is_ok());
+    }
+
+    #[test]
+    pub fn restore_carries_buckets_over() {
+        for algorithm in ALGORITHMS {
+            let clock = Arc::new(MockClock::new());
+            let before =
+                RateLimiter::new(limit(algorithm.clone(), 10, 3600)).with_clock(clock.clone());
+            assert!(before.check_with_cost("key", clock.now(), 8).is_ok());
+
+            let after = RateLimiter::new(limit(algorithm, 10, 3600)).with_clock(clock.clone());
+            let restored = after
+                .restore(before.snapshot(), Duration::from_secs(60))
+                .unwrap();
+
+            assert_eq!(restored, 1);
+            assert!(after.check_with_cost("key", clock.now(), 3).is_err());
+            assert!(after.check_with_cost("key", clock.now(), 2).is_ok());
+        }
+    }
+
+    #[test]
+    pub fn atomic_store_swaps_snapshots_with_gcra() {
+        let clock = Arc::new(MockClock::new());
+        let atomic = RateLimiter::with_store(
+            AlgorithmConfig::Gcra { burst: 5, rate: 1 },
+            StoreType::AtomicGcra.build(),
+        )
+        .with_clock(clock.clone());
+
+        // past the pinned slots the keys spill over and behave the same
+        let keys: Vec<String> = (0..20).map(|i| format!("key-{i}")).collect();
+        for key in &keys {
+            assert!(atomic.check_with_cost(key.clone(), clock.now(), 4).is_ok());
+            assert!(atomic.check_with_cost(key.clone(), clock.now(), 2).is_err());
+        }
+        assert_eq!(atomic.keys(), 20);
+
+        let plain =
+            RateLimiter::new(AlgorithmConfig::Gcra { burst: 5, rate: 1 }).with_clock(clock.clone());
+        let restored = plain
+            .restore(atomic.snapshot(), Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(restored, 20);
+
+        clock.advance(Duration::from_secs(1));
+        let back = RateLimiter::with_store(
+            AlgorithmConfig::Gcra { burst: 5, rate: 1 },
+            StoreType::AtomicGcra.build(),
+        )
+        .with_clock(clock.clone());
+        back.restore(plain.snapshot(), Duration::from_secs(60))
+            .unwrap();
+        for key in &keys {
+            assert!(plain.check_with_cost(key.clone(), clock.now(), 2).is_ok());
+            assert!(back.check_with_cost(key.clone(), clock.now(), 2).is_ok());
+            assert!(back.check(key.clone(), clock.now()).is_err());
+        }
+    }
+
+    #[test]
+    pub fn downtime_counts_as_elapsed() {
+        let clock = Arc::new(MockClock::new());
+        let before = RateLimiter::new(AlgorithmConfig::TokenBucket { burst: 10, rate: 1 })
+            .with_clock(clock.clone());
+        assert!(before.check_with_cost("key", clock.now(), 10).is_ok());
+        let snapshot = before.snapshot();
+
+        clock.advance(Duration::from_secs(4));
+        let after = RateLimiter::new(AlgorithmConfig::TokenBucket { burst: 10, rate: 1 })
+            .with_clock(clock.clone());
+        after.restore(snapshot, Duration::from_secs(60)).unwrap();
+
+        let snapshot = after.check("key", clock.now()).ok().unwrap();
+        assert_eq!(snapshot.remaining, 3);
+    }
+
+    #[test]
+    pub fn restore_rejects_bad_snapshots() {
+        let clock = Arc::new(MockClock::new());
+        let limiter = RateLimiter::new(AlgorithmConfig::TokenBucket { burst: 10, rate: 1 })
+            .with_clock(clock.clone());
+        assert!(limiter.check("key", clock.now()).is_ok());
+        let max_age = Duration::from_secs(60);
+
+        let mut snapshot = limiter.snapshot();
+        snapshot.version += 1;
+        assert!(matches!(
+            limiter.restore(snapshot, max_age),
+            Err(SnapshotError::IncompatibleVersion { .. })
+        ));
+
+        let other = RateLimiter::new(AlgorithmConfig::TokenBucket { burst: 20, rate: 1 })
+            .with_clock(clock.clone());
+        assert!(matches!(
+            other.restore(limiter.snapshot(), max_age),
+            Err(SnapshotError::ConfigMismatch)
+        ));
+
+        let mut snapshot = limiter.snapshot();
+        snapshot.taken_at += Duration::from_secs(5);
+        assert!(matches!(
+            limiter.restore(snapshot, max_age),
+            Err(SnapshotError::Stale { age: None })
+        ));
+
+        let snapshot = limiter.snapshot();
+        clock.advance(Duration::from_secs(61));
+        assert!(matches!(
+            limiter.restore(snapshot, max_age),
+            Err(SnapshotError::Stale { age: Some(_) })
+        ));
+    }
+
+    #[test]
+    pub fn full_map_evicts_least_recently_seen() {
+        let clock = MockClock::new();
+        let limiter = RateLimiter::new(limit(AlgorithmType::SlidingLog, 1, 3600))
+            .with_key_limit(KeyLimit::new(3, EvictionPolicy::LeastRecentlySeen));
+
+        for key in ["a", "b", "c"] {
+            assert!(limiter.check(key, clock.now()).is_ok());
+            clock.advance(Duration::from_secs(1));
+        }
+        // touch "a" so "b" becomes the oldest
+        assert!(limiter.check("a", clock.now()).is_err());
+
+        assert!(limiter.check("d", clock.now()).is_ok());
+        assert_eq!(limiter.keys(), 3);
+        assert_eq!(limiter.evictions(), 1);
+
+        assert!(limiter.check("a", clock.now()).is_err());
+        assert!(limiter.check("c", clock.now()).is_err());
+        // "b" was dropped and comes back with a fresh bucket
+        assert!(limiter.check("b", clock.now()).is_ok());
+    }
+
+    #[test]
+    pub fn full_map_sends_new_keys_to_overflow() {
+        let stores = [
+            StoreType::DashMap,
+            StoreType::Mutex,
+            StoreType::Sharded { shards: 4 },
+        ];
+
+        for store in stores {
+            let limiter =
+                RateLimiter::with_store(limit(AlgorithmType::SlidingLog, 2, 3600), store.build())
+                    .with_key_limit(KeyLimit::new(2, EvictionPolicy::Overflow));
+            let t0 = Instant::now();
+
+            assert!(limiter.check("a", t0).is_ok());
+            assert!(limiter.check("b", t0).is_ok());
+
+            // every new key shares the single overflow bucket
+            assert!(limiter.check("c", t0).is_ok());
+            assert!(limiter.check("d", t0).is_ok());
+            assert!(limiter.check("e", t0).is_err());
+
+            // known keys keep their own bucket
+            assert!(limiter.check("a", t0).is_ok());
+            assert_eq!(limiter.keys(), 2);
+            assert_eq!(limiter.overflowed(), 3);
+        }
+    }
+
+    #[test]
+    pub fn override_keys_count_against_the_key_limit() {
+        use crate::rate_limiter::overrides::IpOverrides;
+        use std::net::IpAddr;
+
+        let partner = KeyPolicy::parse("3:3600", AlgorithmType::SlidingLog).unwrap();
+        let overrides: Arc<dyn OverrideLookup<IpAddr>> =
+            Arc::new(IpOverrides::new().range("10.0.0.0/8".parse().unwrap(), partner));
+        let ip = |last: u8| IpAddr::from([10, 0, 0, last]);
+        let t0 = Instant::now();
+
+        let limiter = RateLimiter::new(limit(AlgorithmType::SlidingLog, 1, 3600))
+            .with_overrides(overrides.clone())
+            .with_key_limit(KeyLimit::new(2, EvictionPolicy::Overflow));
+        assert!(limiter.check(ip(1), t0).is_ok());
+        assert!(limiter.check(ip(2), t0).is_ok());
+
+        // rotating addresses inside the range share one overflow bucket with its limit
+        for last in 3..6 {
+            assert!(limiter.check(ip(last), t0).is_ok());
+        }
+        assert!(limiter.check(ip(6), t0).is_err());
+        limiter.refund(&ip(7), t0, 1);
+        assert!(limiter.check(ip(8), t0).is_ok());
+        // keys without an override get the default limit's overflow bucket
+        assert!(limiter.check(IpAddr::from([192, 168, 0, 1]), t0).is_ok());
+        assert!(limiter.check(IpAddr::from([192, 168, 0, 2]), t0).is_err());
+        assert_eq!(limiter.keys(), 2);
+
+        let limiter = RateLimiter::new(limit(AlgorithmType::SlidingLog, 1, 3600))
+            .with_overrides(overrides)
+            .with_key_limit(KeyLimit::new(2, EvictionPolicy::LeastRecentlySeen));
+        for last in 1..50 {
+            let now = t0 + Duration::from_secs(last as u64);
+            assert!(limiter.check(ip(last), now).is_ok());
+        }
+        assert_eq!(limiter.keys(), 2);
+    }
+
+    #[test]
+    pub fn overrides_apply_before_the_bucket_is_created() {
+        use crate::rate_limiter::overrides::KeyOverrides;
+
+        for algorithm in ALGORITHMS {
+            let partner = KeyPolicy::parse("5:3600", algorithm.clone()).unwrap();
+            let overrides: Arc<dyn OverrideLookup<&str>> = Arc::new(
+                KeyOverrides::new()
+                    .key("monitoring", KeyPolicy::Allow)
+                    .key("abuser", KeyPolicy::Deny)
+                    .key("partner", partner),
+            );
+            let limiter = RateLimiter::new(limit(algorithm, 1, 3600)).with_overrides(overrides);
+            let t0 = Instant::now();
+
+            for _ in 0..10 {
+                assert!(limiter.check("monitoring", t0).is_ok());
+            }
+            let err = limiter.check("abuser", t0).err().unwrap();
+            assert_eq!(err.kind, RateLimitErrorKind::Blocked);
+
+            let snapshot = limiter.check_with_cost("partner", t0, 5).ok().unwrap();
+            assert_eq!(snapshot.limit, 5);
+            assert!(limiter.check("partner", t0).is_err());
+            limiter.refund(&"partner", t0, 2);
+            assert!(limiter.check_with_cost("partner", t0, 2).is_ok());
+
+            assert!(limiter.check("anyone", t0).is_ok());
+            assert!(limiter.check("anyone", t0).is_err());
+            // allowlisted and denylisted keys never get a bucket
+            assert_eq!(limiter.keys(), 2);
+        }
+    }
+
+    #[test]
+    pub fn peek_and_refund_leave_other_keys_alone() {
+        for algorithm in ALGORITHMS {
+            let limiter = RateLimiter::new(limit(algorithm, 5, 3600));
+            let t0 = Instant::now();
+
+            // unknown keys report a full bucket and stay untracked
+            assert_eq!(limiter.peek(&"key", t0).remaining, 5);
+            assert_eq!(limiter.keys(), 0);
+
+            assert!(limiter.check_with_cost("key", t0, 4).is_ok());
+            for _ in 0..3 {
+                assert_eq!(limiter.peek(&"key", t0).remaining, 1);
+            }
+
+            limiter.refund(&"key", t0, 3);
+            assert_eq!(limiter.peek(&"key", t0).remaining, 4);
+            assert!(limiter.check_with_cost("key", t0, 4).is_ok());
+            assert!(limiter.check("key", t0).is_err());
+            assert_eq!(limiter.peek(&"other", t0).remaining, 5);
+        }
+    }
+
+    #[test]
+    pub fn windows_can_be_shorter_than_a_second() {
+        let window_algorithms = [
+            AlgorithmType::SlidingLog,
+            AlgorithmType::SlidingCounter,
+            AlgorithmType::FixedWindow,
+        ];
+
+        for algorithm in window_algorithms {
+            let clock = Arc::new(MockClock::new());
+            let limit = AlgorithmConfig::builder(algorithm)
+                .with_capacity(2)
+                .with_window(Duration::from_millis(500))
+                .build()
+                .unwrap();
+            let limiter = RateLimiter::new(limit).with_clock(clock.clone());
+            assert_eq!(limiter.limit(), limit);
+
+            assert!(limiter.check_with_cost("key", clock.now(), 2).is_ok());
+            let err = limiter.check("key", clock.now()).err().unwrap();
+            assert!(!err.retry_after.is_zero());
+            assert!(err.retry_after <= Duration::from_millis(500));
+        }
+    }
+
+    #[test]
+    pub fn fixed_windows_follow_the_limiter_clock() {
+        // 0.5s before a minute boundary on the limiter's clock
+        let clock = Arc::new(MockClock::at(Duration::from_millis(59_500)));
+        let limiter =
+            RateLimiter::new(limit(AlgorithmType::FixedWindow, 1, 60)).with_clock(clock.clone());
+        let t0 = clock.now();
 
-        self.buckets
-            .retain(|_, bucket| now.duration_since(bucket.last_seen()) <= ttl);
+        assert!(limiter.check("key", t0).is_ok());
+        let err = limiter.check("key", t0).err().unwrap();
+        assert_eq!(err.retry_after, Duration::from_millis(500));
+        assert!(
+            limiter
+                .check("key", t0 + Duration::from_millis(500))
+                .is_ok()
+        );
     }
 }
@@ -0,0 +1,22 @@
+-- KEYS[1] bucket key, ARGV algorithm, capacity, cost
+-- gives back `cost` units that a composite check charged before a later tier denied
+local algorithm = ARGV[1]
+local capacity = tonumber(ARGV[2])
+local cost = tonumber(ARGV[3])
+
+if algorithm == 'token_bucket' then
+    local tokens = tonumber(redis.call('HGET', KEYS[1], 'tokens'))
+    if tokens then
+        redis.call('HSET', KEYS[1], 'tokens', tostring(math.min(capacity, tokens + cost)))
+    end
+elseif algorithm == 'sliding_log' then
+    -- the newest entries are the ones the refunded request added
+    redis.call('ZPOPMAX', KEYS[1], cost)
+elseif algorithm == 'sliding_counter' then
+    local current = tonumber(redis.call('HGET', KEYS[1], 'current'))
+    if current then
+        redis.call('HSET', KEYS[1], 'current', math.max(0, current - cost))
+    end
+end
+
+return 1
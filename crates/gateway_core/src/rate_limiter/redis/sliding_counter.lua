@@ -0,0 +1,43 @@
+-- KEYS[1] counter hash, ARGV capacity, window_ms, cost
+-- returns {code, remaining, reset_after_ms, retry_after_ms}, code 1 allowed / 0 denied / 2 cost > capacity
+local capacity = tonumber(ARGV[1])
+local window = tonumber(ARGV[2])
+local cost = tonumber(ARGV[3])
+
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+
+local state = redis.call('HMGET', KEYS[1], 'start', 'current', 'previous')
+local start = tonumber(state[1]) or now
+local current = tonumber(state[2]) or 0
+local previous = tonumber(state[3]) or 0
+
+if now - start >= 2 * window then
+    previous = 0
+    current = 0
+    start = now
+elseif now - start >= window then
+    previous = current
+    current = 0
+    start = now
+end
+
+local elapsed = now - start
+local effective = previous * (1 - elapsed / window) + current
+
+local code = 0
+local retry_after = 0
+if cost > capacity then
+    code = 2
+elseif cost == 0 or effective + cost - 1 < capacity then
+    current = current + cost
+    effective = effective + cost
+    code = 1
+else
+    retry_after = window - elapsed
+end
+
+redis.call('HSET', KEYS[1], 'start', start, 'current', current, 'previous', previous)
+redis.call('PEXPIRE', KEYS[1], 2 * window)
+
+return {code, math.max(0, math.floor(capacity - effective)), window - elapsed, retry_after}
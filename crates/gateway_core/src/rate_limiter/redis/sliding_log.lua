@@ -0,0 +1,40 @@
+-- KEYS[1] log zset, KEYS[2] member sequence, ARGV capacity, window_ms, cost
+-- returns {code, remaining, reset_after_ms, retry_after_ms}, code 1 allowed / 0 denied / 2 cost > capacity
+local capacity = tonumber(ARGV[1])
+local window = tonumber(ARGV[2])
+local cost = tonumber(ARGV[3])
+
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+
+redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', '(' .. (now - window))
+local used = redis.call('ZCARD', KEYS[1])
+
+local code = 0
+local retry_after = 0
+if cost > capacity then
+    code = 2
+elseif used + cost <= capacity then
+    local seq = redis.call('INCR', KEYS[2])
+    for i = 1, cost do
+        redis.call('ZADD', KEYS[1], now, seq .. ':' .. i)
+    end
+    used = used + cost
+    code = 1
+else
+    -- the entry that has to expire before `cost` slots are free
+    local index = used + cost - capacity - 1
+    local blocking = redis.call('ZRANGE', KEYS[1], index, index, 'WITHSCORES')
+    retry_after = math.max(0, tonumber(blocking[2]) + window - now)
+end
+
+redis.call('PEXPIRE', KEYS[1], window)
+redis.call('PEXPIRE', KEYS[2], window)
+
+local reset_after = 0
+local oldest = redis.call('ZRANGE', KEYS[1], 0, 0, 'WITHSCORES')
+if oldest[2] then
+    reset_after = math.max(0, tonumber(oldest[2]) + window - now)
+end
+
+return {code, capacity - used, reset_after, retry_after}
@@ -0,0 +1,36 @@
+-- KEYS[1] bucket hash, ARGV capacity, refill_rate (tokens/s), cost
+-- returns {code, remaining, reset_after_ms, retry_after_ms}, code 1 allowed / 0 denied / 2 cost > capacity
+local capacity = tonumber(ARGV[1])
+local rate = tonumber(ARGV[2])
+local cost = tonumber(ARGV[3])
+
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+
+local state = redis.call('HMGET', KEYS[1], 'tokens', 'ts')
+local tokens = tonumber(state[1]) or capacity
+local ts = tonumber(state[2]) or now
+
+tokens = math.min(capacity, tokens + math.max(0, now - ts) * rate / 1000)
+
+local code = 0
+local retry_after = 0
+if cost > capacity then
+    code = 2
+elseif tokens >= cost then
+    tokens = tokens - cost
+    code = 1
+else
+    retry_after = math.ceil((cost - tokens) * 1000 / rate)
+end
+
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'ts', now)
+redis.call('PEXPIRE', KEYS[1], math.ceil(capacity * 1000 / rate) + 1000)
+
+local remaining = math.floor(tokens)
+local reset_after = 0
+if tokens < capacity then
+    reset_after = math.ceil((1 - (tokens - remaining)) * 1000 / rate)
+end
+
+return {code, remaining, reset_after, retry_after}
@@ -0,0 +1,609 @@
+use redis::{Client, Connection, Script};
+use std::{
+    marker::PhantomData,
+    net::IpAddr,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+use crate::rate_limiter::{
+    AlgorithmConfig,
+    algorithm::BucketState,
+    bucket_store::{Bucket, BucketStore},
+    overrides::{KeyPolicy, ResolvedKey},
+    rate_limiter::{AlgorithmType, BucketConfig, RateLimitError, RateLimitErrorKind},
+};
+
+// how long a fail-closed client is told to back off while the store is down
+const UNAVAILABLE_RETRY_AFTER: Duration = Duration::from_secs(1);
+// how long checks skip the store after it failed to answer
+const BACKOFF_DEFAULT: Duration = Duration::from_secs(1);
+
+// Stable string form of a limiter key, shared by every gateway replica
+pub trait RedisKey {
+    fn redis_key(&self) -> String;
+}
+
+impl RedisKey for () {
+    fn redis_key(&self) -> String {
+        "global".to_string()
+    }
+}
+
+impl RedisKey for String {
+    fn redis_key(&self) -> String {
+        self.clone()
+    }
+}
+
+impl RedisKey for &str {
+    fn redis_key(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl RedisKey for IpAddr {
+    fn redis_key(&self) -> String {
+        self.to_string()
+    }
+}
+
+// a key under an override never shares a bucket with the same key without one
+impl<K: RedisKey> RedisKey for ResolvedKey<K> {
+    fn redis_key(&self) -> String {
+        match self.policy {
+            Some(KeyPolicy::Limit(limit)) => {
+                let per = match (limit.rate(), limit.window()) {
+                    (Some(rate), _) => rate.to_string(),
+                    (_, window) => format!("{}ms", window.unwrap_or_default().as_millis()),
+                };
+                format!(
+                    "override:{}:{per}:{}",
+                    limit.capacity(),
+                    self.key.redis_key()
+                )
+            }
+            _ => self.key.redis_key(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailMode {
+    // allow the request when the store can't be reached
+    Open,
+    // deny the request when the store can't be reached
+    Closed,
+}
+
+// Keeps bucket state in a Redis-compatible server so every replica shares one budget.
+// Each check is one server-side script so the update is atomic, time comes from the
+// server clock (TIME) so replicas don't need synced clocks.
+// The calls are blocking and bounded by `timeout`, on a multi-thread tokio runtime they
+// run in block_in_place so the worker's other tasks move elsewhere meanwhile. Once the
+// store fails to answer, checks fail per `fail_mode` right away for `backoff`, then a
+// single check probes it again, so an outage costs one timeout per backoff, not one
+// per request. The client-setinfo handshake is disabled since redis-rs reads its reply
+// without a timeout, credentials or a db in the url would bring that back
+pub struct RedisStore<K> {
+    client: Client,
+    connections: Vec<Mutex<Option<Connection>>>,
+    next: AtomicUsize,
+    prefix: String,
+    fail_mode: FailMode,
+    timeout: Duration,
+    backoff: Duration,
+    // nanoseconds since `started` until which the store is skipped, 0 while it answers
+    down_until: AtomicU64,
+    started: Instant,
+    token_bucket: Script,
+    sliding_log: Script,
+    sliding_counter: Script,
+    refund: Script,
+    _key: PhantomData<fn(K)>,
+}
+
+impl<K> RedisStore<K>
+where
+    K: RedisKey,
+{
+    pub fn new(
+        url: &str,
+        prefix: &str,
+        pool_size: usize,
+        fail_mode: FailMode,
+        timeout: Duration,
+    ) -> redis::RedisResult<Self> {
+        Ok(Self {
+            client: Client::open(url)?,
+            connections: (0..pool_size.max(1)).map(|_| Mutex::new(None)).collect(),
+            next: AtomicUsize::new(0),
+            prefix: prefix.to_string(),
+            fail_mode,
+            timeout,
+            backoff: BACKOFF_DEFAULT,
+            down_until: AtomicU64::new(0),
+            started: Instant::now(),
+            token_bucket: Script::new(include_str!("redis/token_bucket.lua")),
+            sliding_log: Script::new(include_str!("redis/sliding_log.lua")),
+            sliding_counter: Script::new(include_str!("redis/sliding_counter.lua")),
+            refund: Script::new(include_str!("redis/refund.lua")),
+            _key: PhantomData,
+        })
+    }
+
+    pub fn with_backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    // parses the url only, nothing is connected until the first check
+    pub fn is_valid_url(url: &str) -> bool {
+        Client::open(url).is_ok()
+    }
+
+    pub fn supports(algorithm: &AlgorithmType) -> bool {
+        matches!(
+            algorithm,
+            AlgorithmType::TokenBucket | AlgorithmType::SlidingLog | AlgorithmType::SlidingCounter
+        )
+    }
+
+    fn connect(&self) -> redis::RedisResult<Connection> {
+        let connection = self.client.get_connection_with_timeout(self.timeout)?;
+        connection.set_read_timeout(Some(self.timeout))?;
+        connection.set_write_timeout(Some(self.timeout))?;
+        Ok(connection)
+    }
+
+    fn elapsed(&self) -> u64 {
+        self.started.elapsed().as_nanos().min(u64::MAX as u128) as u64
+    }
+
+    // true while the store is skipped. Once the backoff is over the first caller
+    // claims the probe, everyone else keeps skipping until it answers
+    fn is_down(&self) -> bool {
+        let until = self.down_until.load(Ordering::Relaxed);
+        if until == 0 {
+            return false;
+        }
+
+        let now = self.elapsed();
+        if now < until {
+            return true;
+        }
+        let next = now.saturating_add(self.backoff.as_nanos() as u64).max(1);
+        self.down_until
+            .compare_exchange(until, next, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+    }
+
+    fn with_connection<T>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> redis::RedisResult<T>,
+    ) -> redis::RedisResult<T> {
+        if self.is_down() {
+            return Err(redis::RedisError::from((
+                redis::ErrorKind::IoError,
+                "redis store backing off",
+            )));
+        }
+
+        let result = blocking(|| {
+            let slot = self.next.fetch_add(1, Ordering::Relaxed) % self.connections.len();
+            let mut connection = self.connections[slot].lock().unwrap();
+            if connection.is_none() {
+                *connection = Some(self.connect()?);
+            }
+
+            let result = f(connection.as_mut().unwrap());
+
+            // drop a broken connection so the next call reconnects
+            if result.is_err() {
+                *connection = None;
+            }
+            result
+        });
+
+        match &result {
+            // unreachable, timed out or dropped, a script error says nothing about the store
+            Err(err) if err.is_io_error() => {
+                let until = self
+                    .elapsed()
+                    .saturating_add(self.backoff.as_nanos() as u64);
+                self.down_until.store(until.max(1), Ordering::Relaxed);
+            }
+            _ => self.down_until.store(0, Ordering::Relaxed),
+        }
+        result
+    }
+
+    fn redis_key(&self, key: &K) -> String {
+        format!("{}:{}", self.prefix, key.redis_key())
+    }
+
+    fn run(&self, key: &K, cost: u128, config: &BucketConfig) -> redis::RedisResult<[u64; 4]> {
+        let key = self.redis_key(key);
+
+        self.with_connection(|conn| match config.limit {
+            AlgorithmConfig::TokenBucket { burst, rate } => self
+                .token_bucket
+                .key(&key)
+                .arg(burst as u64)
+                .arg(rate as u64)
+                .arg(cost as u64)
+                .invoke(conn),
+            AlgorithmConfig::SlidingLog { limit, window } => self
+                .sliding_log
+                .key(&key)
+                .key(format!("{key}:seq"))
+                .arg(limit as u64)
+                .arg(window.as_millis() as u64)
+                .arg(cost as u64)
+                .invoke(conn),
+            AlgorithmConfig::SlidingCounter { limit, window } => self
+                .sliding_counter
+                .key(&key)
+                .arg(limit as u64)
+                .arg(window.as_millis() as u64)
+                .arg(cost as u64)
+                .invoke(conn),
+            AlgorithmConfig::Gcra { .. } | AlgorithmConfig::FixedWindow { .. } => {
+                Err(redis::RedisError::from((
+                    redis::ErrorKind::ClientError,
+                    "algorithm not supported by the redis store",
+                )))
+            }
+        })
+    }
+
+    fn unavailable(&self, config: &BucketConfig) -> Result<BucketState, RateLimitError> {
+        match self.fail_mode {
+            FailMode::Open => Ok(BucketState {
+                limit: config.capacity(),
+                remaining: config.capacity(),
+                reset_after: Duration::ZERO,
+            }),
+            FailMode::Closed => Err(RateLimitError {
+                kind: RateLimitErrorKind::StoreUnavailable,
+                retry_after: UNAVAILABLE_RETRY_AFTER,
+                snapshot: BucketState {
+                    limit: config.capacity(),
+                    remaining: 0,
+                    reset_after: UNAVAILABLE_RETRY_AFTER,
+                },
+            }),
+        }
+    }
+}
+
+// a worker of a multi-thread runtime hands its other tasks over before blocking,
+// elsewhere (current-thread runtime, plain threads) the call just blocks
+fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
+
+impl<K> BucketStore<K> for RedisStore<K>
+where
+    K: RedisKey,
+{
+    fn check(
+        &self,
+        key: K,
+        now: Instant,
+        cost: u128,
+        config: &BucketConfig,
+    ) -> Result<BucketState, RateLimitError> {
+        let [code, remaining, reset_after_ms, retry_after_ms] = match self.run(&key, cost, config) {
+            Ok(reply) => reply,
+            Err(_) => return self.unavailable(config),
+        };
+
+        let snapshot = BucketState {
+            limit: config.capacity(),
+            remaining: remaining as u128,
+            reset_after: Duration::from_millis(reset_after_ms),
+        };
+
+        match code {
+            1 => Ok(snapshot),
+            2 => Err(RateLimitError {
+                kind: RateLimitErrorKind::CostExceedsCapacity,
+                retry_after: Duration::ZERO,
+                snapshot,
+            }),
+            _ => Err(RateLimitError {
+                kind: RateLimitErrorKind::Limited,
+                retry_after: Duration::from_millis(retry_after_ms),
+                snapshot,
+            }),
+        }
+    }
+
+    // best effort, a failed refund only leaves the tier charged
+    fn refund(&self, key: &K, now: Instant, cost: u128, config: &BucketConfig) {
+        let algorithm = match config.limit.algorithm() {
+            AlgorithmType::TokenBucket => "token_bucket",
+            AlgorithmType::SlidingLog => "sliding_log",
+            AlgorithmType::SlidingCounter => "sliding_counter",
+            AlgorithmType::Gcra | AlgorithmType::FixedWindow => return,
+        };
+        let key = self.redis_key(key);
+
+        let _: redis::RedisResult<i64> = self.with_connection(|conn| {
+            self.refund
+                .key(&key)
+                .arg(algorithm)
+                .arg(config.capacity() as u64)
+                .arg(cost as u64)
+                .invoke(conn)
+        });
+    }
+
+    // a zero cost check applies refill and expiry without charging, None
+    // while redis is unreachable
+    fn peek(&self, key: &K, now: Instant, config: &BucketConfig) -> Option<BucketState> {
+        let [_, remaining, reset_after_ms, _] = self.run(key, 0, config).ok()?;
+
+        Some(BucketState {
+            limit: config.capacity(),
+            remaining: remaining as u128,
+            reset_after: Duration::from_millis(reset_after_ms),
+        })
+    }
+
+    // keys carry their own PEXPIRE, nothing to sweep
+    fn cleanup(&self, now: Instant, ttl: Duration) {}
+
+    // the state already outlives the gateway in redis, nothing to snapshot
+    fn for_each(&self, f: &mut dyn FnMut(&K, &Bucket)) {}
+
+    fn insert(&self, key: K, bucket: Bucket, config: &BucketConfig) {}
+
+    // keys expire in redis on their own, the gateway never holds any
+    fn key_count(&self) -> usize {
+        0
+    }
+
+    fn contains(&self, key: &K) -> bool {
+        false
+    }
+
+    fn evict(&self, count: usize) -> usize {
+        0
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::rate_limiter::{RateLimiter, rate_limiter::tests::limit};
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::Arc,
+        thread,
+    };
+
+    // nothing listens on port 1
+    const UNREACHABLE: &str = "redis://127.0.0.1:1";
+
+    fn store(url: &str, prefix: &str, fail_mode: FailMode) -> Arc<RedisStore<&'static str>> {
+        Arc::new(RedisStore::new(url, prefix, 2, fail_mode, Duration::from_millis(200)).unwrap())
+    }
+
+    type Commands = Arc<Mutex<Vec<Vec<String>>>>;
+
+    // In-process stand-in speaking just enough RESP for the store: scripts are
+    // unknown until SCRIPT LOAD, every EVALSHA after that is answered by `eval`
+    // with the [code, remaining, reset_after_ms, retry_after_ms] the script returns.
+    // The scripts themselves only run against a real server, see replicas_share_one_budget
+    fn fake_redis(eval: fn(&[String]) -> [u64; 4]) -> (String, Commands) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        let commands: Commands = Arc::default();
+
+        let recorded = commands.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let recorded = recorded.clone();
+                thread::spawn(move || serve(stream.unwrap(), eval, recorded));
+            }
+        });
+        (url, commands)
+    }
+
+    fn serve(stream: TcpStream, eval: fn(&[String]) -> [u64; 4], recorded: Commands) {
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut loaded = false;
+
+        while let Some(command) = read_command(&mut reader) {
+            let reply = match command[0].to_uppercase().as_str() {
+                "SCRIPT" => {
+                    loaded = true;
+                    "$40\r\n0000000000000000000000000000000000000000\r\n".to_string()
+                }
+                "EVALSHA" if !loaded => "-NOSCRIPT No matching script\r\n".to_string(),
+                "EVALSHA" => eval(&command)
+                    .iter()
+                    .fold("*4\r\n".to_string(), |reply, n| {
+                        reply + &format!(":{n}\r\n")
+                    }),
+                _ => "+OK\r\n".to_string(),
+            };
+            recorded.lock().unwrap().push(command);
+            if writer.write_all(reply.as_bytes()).is_err() {
+                return;
+            }
+        }
+    }
+
+    // "*<n>" then n "$<len>" bulk strings, None once the client hangs up
+    fn read_command(reader: &mut impl BufRead) -> Option<Vec<String>> {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let count: usize = line.trim().strip_prefix('*')?.parse().ok()?;
+
+        (0..count)
+            .map(|_| {
+                line.clear();
+                reader.read_line(&mut line).ok()?;
+                let len: usize = line.trim().strip_prefix('$')?.parse().ok()?;
+                let mut arg = vec![0; len + 2];
+                reader.read_exact(&mut arg).ok()?;
+                arg.truncate(len);
+                String::from_utf8(arg).ok()
+            })
+            .collect()
+    }
+
+    #[test]
+    pub fn script_replies_become_decisions() {
+        // EVALSHA <sha> 1 <key> <capacity> <refill_rate> <cost>
+        let (url, commands) = fake_redis(|command| match command[6].as_str() {
+            "0" | "1" => [1, 3, 250, 0],
+            "2" => [0, 0, 250, 500],
+            _ => [2, 3, 250, 0],
+        });
+        let limiter = RateLimiter::with_store(
+            AlgorithmConfig::TokenBucket { burst: 4, rate: 2 },
+            store(&url, "test", FailMode::Closed),
+        );
+        let now = Instant::now();
+
+        let snapshot = limiter.check("key", now).ok().unwrap();
+        assert_eq!((snapshot.limit, snapshot.remaining), (4, 3));
+        assert_eq!(snapshot.reset_after, Duration::from_millis(250));
+
+        let err = limiter.check_with_cost("key", now, 2).err().unwrap();
+        assert_eq!(err.kind, RateLimitErrorKind::Limited);
+        assert_eq!(err.retry_after, Duration::from_millis(500));
+
+        let err = limiter.check_with_cost("key", now, 9).err().unwrap();
+        assert_eq!(err.kind, RateLimitErrorKind::CostExceedsCapacity);
+        assert_eq!(limiter.peek(&"key", now).remaining, 3);
+
+        let commands = commands.lock().unwrap();
+        let evals: Vec<_> = commands
+            .iter()
+            .filter(|command| command[0] == "EVALSHA")
+            .collect();
+        // the first call loads the script and retries
+        assert!(commands.iter().any(|command| command[0] == "SCRIPT"));
+        assert_eq!(evals[0][3..], ["test:key", "4", "2", "1"]);
+        assert_eq!(evals.last().unwrap()[3..], ["test:key", "4", "2", "0"]);
+    }
+
+    #[test]
+    pub fn a_silent_store_costs_one_timeout_per_backoff() {
+        // accepts connections and never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let streams: Vec<_> = listener.incoming().collect();
+        });
+
+        let store = RedisStore::new(
+            &url,
+            "test",
+            2,
+            FailMode::Closed,
+            Duration::from_millis(300),
+        )
+        .unwrap()
+        .with_backoff(Duration::from_millis(500));
+        let limiter = RateLimiter::with_store(
+            AlgorithmConfig::TokenBucket { burst: 5, rate: 1 },
+            Arc::new(store),
+        );
+        let timed = || {
+            let started = Instant::now();
+            let err = limiter.check("key", Instant::now()).err().unwrap();
+            assert_eq!(err.kind, RateLimitErrorKind::StoreUnavailable);
+            started.elapsed()
+        };
+
+        assert!(timed() >= Duration::from_millis(300));
+        for _ in 0..10 {
+            assert!(timed() < Duration::from_millis(100));
+        }
+
+        // past the backoff one check probes the store again
+        thread::sleep(Duration::from_millis(500));
+        assert!(timed() >= Duration::from_millis(300));
+        assert!(timed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    pub fn unreachable_store_fails_open() {
+        let limiter = RateLimiter::with_store(
+            AlgorithmConfig::TokenBucket { burst: 5, rate: 1 },
+            store(UNREACHABLE, "test", FailMode::Open),
+        );
+
+        let snapshot = limiter.check("key", Instant::now()).ok().unwrap();
+        assert_eq!(snapshot.remaining, 5);
+    }
+
+    #[test]
+    pub fn unreachable_store_fails_closed() {
+        let limiter = RateLimiter::with_store(
+            AlgorithmConfig::TokenBucket { burst: 5, rate: 1 },
+            store(UNREACHABLE, "test", FailMode::Closed),
+        );
+
+        let err = limiter.check("key", Instant::now()).err().unwrap();
+        assert_eq!(err.kind, RateLimitErrorKind::StoreUnavailable);
+        assert_eq!(err.retry_after, UNAVAILABLE_RETRY_AFTER);
+    }
+
+    // needs a running server, CI provides one:
+    // REDIS_URL=redis://127.0.0.1:6379 cargo test -- --ignored
+    #[test]
+    #[ignore]
+    pub fn replicas_share_one_budget() {
+        let url = std::env::var("REDIS_URL").unwrap_or("redis://127.0.0.1:6379".to_string());
+        let algorithms = [
+            AlgorithmType::TokenBucket,
+            AlgorithmType::SlidingLog,
+            AlgorithmType::SlidingCounter,
+        ];
+
+        for (i, algorithm) in algorithms.into_iter().enumerate() {
+            let prefix = format!("gateway_test:{}:{}", i, std::process::id());
+            let replica_a = RateLimiter::with_store(
+                limit(algorithm.clone(), 4, 60),
+                store(&url, &prefix, FailMode::Closed),
+            );
+            let replica_b = RateLimiter::with_store(
+                limit(algorithm, 4, 60),
+                store(&url, &prefix, FailMode::Closed),
+            );
+            let now = Instant::now();
+
+            assert!(replica_a.check_with_cost("key", now, 2).is_ok());
+            assert!(replica_b.check("key", now).is_ok());
+            assert!(replica_a.check("key", now).is_ok());
+
+            let err = replica_b.check("key", now).err().unwrap();
+            assert_eq!(err.kind, RateLimitErrorKind::Limited);
+            assert_eq!(err.snapshot.remaining, 0);
+
+            let err = replica_a.check_with_cost("key", now, 5).err().unwrap();
+            assert_eq!(err.kind, RateLimitErrorKind::CostExceedsCapacity);
+
+            replica_a.refund(&"key", now, 1);
+            assert!(replica_b.check("key", now).is_ok());
+        }
+    }
+}
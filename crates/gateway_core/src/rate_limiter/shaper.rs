@@ -0,0 +1,161 @@
+use dashmap::DashMap;
+use std::{hash::Hash, sync::Arc, time::Duration};
+
+use crate::rate_limiter::acquire::{Turn, WaitQueues};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ShapeError {
+    QueueFull,
+    WaitTooLong,
+}
+
+// Leaky bucket shaping -> denied requests wait in a bounded per-key FIFO queue
+// instead of being rejected straight away. Only the head of a queue checks the
+// limiter, so requests leave in arrival order at the rate the limiter refills
+#[derive(Clone)]
+pub struct LeakyBucketShaper<K>
+where
+    K: Eq + Hash + Clone,
+{
+    queues: Arc<DashMap<K, usize>>,
+    lines: WaitQueues<K>,
+    max_depth: usize,
+    max_wait: Duration,
+}
+
+// RAII handle for a queued request, frees the queue slot and the place in line
+// on drop
+pub struct QueueSlot<K>
+where
+    K: Eq + Hash + Clone,
+{
+    queues: Arc<DashMap<K, usize>>,
+    key: K,
+    turn: Turn<K>,
+}
+
+impl<K> LeakyBucketShaper<K>
+where
+    K: Eq + Hash + Clone,
+{
+    pub fn new(max_depth: usize, max_wait: Duration) -> Self {
+        Self {
+            queues: Arc::new(DashMap::new()),
+            lines: WaitQueues::new(),
+            max_depth,
+            max_wait,
+        }
+    }
+
+    pub fn max_wait(&self) -> Duration {
+        self.max_wait
+    }
+
+    pub fn depth(&self, key: &K) -> usize {
+        self.queues.get(key).map(|depth| *depth).unwrap_or(0)
+    }
+
+    pub fn enqueue(&self, key: K, retry_after: Duration) -> Result<QueueSlot<K>, ShapeError> {
+        if retry_after > self.max_wait {
+            return Err(ShapeError::WaitTooLong);
+        }
+
+        let mut depth = self.queues.entry(key.clone()).or_insert(0);
+        if *depth >= self.max_depth {
+            return Err(ShapeError::QueueFull);
+        }
+        *depth += 1;
+        // joined while the depth entry is locked, the line keeps the same order
+        let turn = self.lines.join(key.clone());
+
+        Ok(QueueSlot {
+            queues: self.queues.clone(),
+            key,
+            turn,
+        })
+    }
+}
+
+impl<K> QueueSlot<K>
+where
+    K: Eq + Hash + Clone,
+{
+    // resolves once every request queued earlier on the key has left
+    pub async fn wait(&mut self) {
+        self.turn.wait().await
+    }
+}
+
+impl<K> Drop for QueueSlot<K>
+where
+    K: Eq + Hash + Clone,
+{
+    fn drop(&mut self) {
+        if let Some(mut depth) = self.queues.get_mut(&self.key) {
+            *depth = depth.saturating_sub(1);
+        }
+        // drop empty queues so idle keys don't pile up
+        self.queues.remove_if(&self.key, |_, depth| *depth == 0);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::{future::poll_fn, pin::pin, task::Poll};
+
+    #[test]
+    pub fn queue_is_bounded_per_key() {
+        let shaper = LeakyBucketShaper::new(2, Duration::from_secs(1));
+
+        let a = shaper.enqueue("a", Duration::ZERO).unwrap();
+        let b = shaper.enqueue("a", Duration::ZERO).unwrap();
+        assert!(matches!(
+            shaper.enqueue("a", Duration::ZERO),
+            Err(ShapeError::QueueFull)
+        ));
+        // other keys have their own queue
+        assert!(shaper.enqueue("b", Duration::ZERO).is_ok());
+
+        drop(a);
+        assert_eq!(shaper.depth(&"a"), 1);
+        assert!(shaper.enqueue("a", Duration::ZERO).is_ok());
+
+        drop(b);
+        assert_eq!(shaper.depth(&"a"), 0);
+    }
+
+    #[test]
+    pub fn reject_wait_beyond_max() {
+        let shaper = LeakyBucketShaper::new(10, Duration::from_millis(500));
+
+        assert!(matches!(
+            shaper.enqueue("a", Duration::from_millis(501)),
+            Err(ShapeError::WaitTooLong)
+        ));
+        assert!(shaper.enqueue("a", Duration::from_millis(500)).is_ok());
+    }
+
+    #[tokio::test]
+    pub async fn queue_is_served_in_order() {
+        let shaper = LeakyBucketShaper::new(10, Duration::from_secs(1));
+        let mut first = shaper.enqueue("a", Duration::ZERO).unwrap();
+        let mut second = shaper.enqueue("a", Duration::ZERO).unwrap();
+        let mut other = shaper.enqueue("b", Duration::ZERO).unwrap();
+
+        first.wait().await;
+        other.wait().await;
+
+        // the head holds its place until it leaves
+        let mut waiting = pin!(second.wait());
+        poll_fn(|cx| {
+            assert!(waiting.as_mut().poll(cx).is_pending());
+            Poll::Ready(())
+        })
+        .await;
+
+        drop(first);
+        waiting.await;
+        assert_eq!(shaper.depth(&"a"), 1);
+    }
+}
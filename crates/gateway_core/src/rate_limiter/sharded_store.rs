@@ -0,0 +1,161 @@
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, Hash, RandomState},
+    sync::{
+        Mutex, RwLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use crate::rate_limiter::{
+    algorithm::BucketState,
+    bucket_store::{Bucket, BucketStore, EVICTION_SAMPLES, check_bucket, eviction_cutoff, is_live},
+    rate_limiter::{BucketConfig, RateLimitError},
+};
+
+// Existing keys only take the shard read lock plus their own bucket lock,
+// the shard write lock is only needed to insert new keys or clean up
+pub struct ShardedStore<K>
+where
+    K: Eq + Hash,
+{
+    shards: Vec<RwLock<HashMap<K, Mutex<Bucket>>>>,
+    hasher: RandomState,
+    // shard the next eviction starts sampling from
+    eviction_cursor: AtomicUsize,
+}
+
+impl<K> ShardedStore<K>
+where
+    K: Eq + Hash,
+{
+    pub fn new(shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1))
+                .map(|_| RwLock::new(HashMap::new()))
+                .collect(),
+            hasher: RandomState::new(),
+            eviction_cursor: AtomicUsize::new(0),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &RwLock<HashMap<K, Mutex<Bucket>>> {
+        let index = self.hasher.hash_one(key) as usize % self.shards.len();
+        &self.shards[index]
+    }
+}
+
+impl<K> BucketStore<K> for ShardedStore<K>
+where
+    K: Eq + Hash + Send + Sync,
+{
+    fn check(
+        &self,
+        key: K,
+        now: Instant,
+        cost: u128,
+        config: &BucketConfig,
+    ) -> Result<BucketState, RateLimitError> {
+        let shard = self.shard(&key);
+
+        if let Some(bucket) = shard.read().unwrap().get(&key) {
+            return check_bucket(&mut bucket.lock().unwrap(), now, cost);
+        }
+
+        let mut buckets = shard.write().unwrap();
+        let bucket = buckets
+            .entry(key)
+            .or_insert_with(|| Mutex::new(config.build(now)));
+        check_bucket(bucket.get_mut().unwrap(), now, cost)
+    }
+
+    fn cleanup(&self, now: Instant, ttl: Duration) {
+        for shard in &self.shards {
+            shard
+                .write()
+                .unwrap()
+                .retain(|_, bucket| is_live(bucket.get_mut().unwrap(), now, ttl));
+        }
+    }
+
+    fn refund(&self, key: &K, now: Instant, cost: u128, config: &BucketConfig) {
+        if let Some(bucket) = self.shard(key).read().unwrap().get(key) {
+            bucket.lock().unwrap().refund(now, cost);
+        }
+    }
+
+    fn peek(&self, key: &K, now: Instant, config: &BucketConfig) -> Option<BucketState> {
+        self.shard(key)
+            .read()
+            .unwrap()
+            .get(key)
+            .map(|bucket| bucket.lock().unwrap().peek(now))
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&K, &Bucket)) {
+        for shard in &self.shards {
+            for (key, bucket) in shard.read().unwrap().iter() {
+                f(key, &bucket.lock().unwrap());
+            }
+        }
+    }
+
+    fn insert(&self, key: K, bucket: Bucket, config: &BucketConfig) {
+        self.shard(&key)
+            .write()
+            .unwrap()
+            .insert(key, Mutex::new(bucket));
+    }
+
+    fn key_count(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap().len())
+            .sum()
+    }
+
+    fn contains(&self, key: &K) -> bool {
+        self.shard(key).read().unwrap().contains_key(key)
+    }
+
+    // same sampling as the DashMap stores, see evict_sampled
+    fn evict(&self, count: usize) -> usize {
+        let start = self.eviction_cursor.load(Ordering::Relaxed);
+        let wanted = count.saturating_mul(EVICTION_SAMPLES);
+
+        let mut last_seen = Vec::new();
+        let mut visited = 0;
+        while visited < self.shards.len() && last_seen.len() < wanted {
+            let shard = self.shards[(start + visited) % self.shards.len()]
+                .read()
+                .unwrap();
+            let left = wanted - last_seen.len();
+            for bucket in shard.values().take(left) {
+                last_seen.push(bucket.lock().unwrap().last_seen());
+            }
+            visited += 1;
+        }
+        self.eviction_cursor
+            .store((start + visited) % self.shards.len(), Ordering::Relaxed);
+
+        let Some(cutoff) = eviction_cutoff(last_seen, count) else {
+            return 0;
+        };
+
+        let mut evicted = 0;
+        for index in 0..visited {
+            if evicted == count {
+                break;
+            }
+            let mut buckets = self.shards[(start + index) % self.shards.len()]
+                .write()
+                .unwrap();
+            evicted += buckets
+                .extract_if(|_, bucket| bucket.get_mut().unwrap().last_seen() <= cutoff)
+                .take(count - evicted)
+                .count();
+        }
+        evicted
+    }
+}
@@ -0,0 +1,319 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+use crate::rate_limiter::{
+    RateLimiter,
+    algorithm::BucketState,
+    rate_limiter::{RateLimitError, RateLimitErrorKind},
+};
+
+// how often a worker hands its unused slices back by default
+pub const SYNC_INTERVAL_DEFAULT: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SliceError {
+    // tolerance has to be a fraction of the capacity in (0, 1]
+    InvalidTolerance(f64),
+    NoWorkers,
+}
+
+// Splits a shared RateLimiter between `workers` that each lease a slice of a key's
+// budget and spend it without touching the shared store. Leased tokens were taken
+// from the shared bucket, so the limit is never exceeded; the cost is that up to
+// `tolerance` of the capacity can sit unused in other workers' slices and be
+// denied early (and a worker waits until a whole slice is free). A worker gives
+// its unused tokens back on its first check after `sync_interval`, on `sync` and
+// when dropped, there is no timer, an idle worker holds them until then
+#[derive(Clone)]
+pub struct SlicedLimiter<K>
+where
+    K: Eq + Hash,
+{
+    shared: RateLimiter<K>,
+    lease: u128,
+    sync_interval: Duration,
+}
+
+// A worker's handle, owned by one thread (or one task) and never shared
+pub struct WorkerSlices<K>
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+{
+    limiter: SlicedLimiter<K>,
+    slices: HashMap<K, Slice>,
+    synced_at: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Slice {
+    tokens: u128,
+    // shared remaining when the slice was leased, for the state handed out locally
+    shared_remaining: u128,
+    // denials are answered locally until the shared bucket could allow again
+    denied_until: Option<Instant>,
+}
+
+impl<K> SlicedLimiter<K>
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+{
+    pub fn new(shared: RateLimiter<K>, workers: usize, tolerance: f64) -> Result<Self, SliceError> {
+        if !(tolerance > 0.0 && tolerance <= 1.0) {
+            return Err(SliceError::InvalidTolerance(tolerance));
+        }
+        if workers == 0 {
+            return Err(SliceError::NoWorkers);
+        }
+
+        // every worker holding a full slice strands at most `tolerance` of the capacity
+        let capacity = shared.capacity();
+        let lease = (capacity as f64 * tolerance / workers as f64) as u128;
+
+        Ok(Self {
+            shared,
+            lease: lease.max(1),
+            sync_interval: SYNC_INTERVAL_DEFAULT,
+        })
+    }
+
+    pub fn with_sync_interval(mut self, sync_interval: Duration) -> Self {
+        self.sync_interval = sync_interval;
+        self
+    }
+
+    pub fn lease(&self) -> u128 {
+        self.lease
+    }
+
+    pub fn shared(&self) -> &RateLimiter<K> {
+        &self.shared
+    }
+
+    pub fn worker(&self) -> WorkerSlices<K> {
+        WorkerSlices {
+            limiter: self.clone(),
+            slices: HashMap::new(),
+            synced_at: None,
+        }
+    }
+}
+
+impl<K> WorkerSlices<K>
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+{
+    pub fn check(&mut self, key: K, now: Instant) -> Result<BucketState, RateLimitError> {
+        self.check_with_cost(key, now, 1)
+    }
+
+    // served from the worker's slice when it holds enough, otherwise a new slice is
+    // leased from the shared limiter
+    pub fn check_with_cost(
+        &mut self,
+        key: K,
+        now: Instant,
+        cost: u128,
+    ) -> Result<BucketState, RateLimitError> {
+        match self.synced_at {
+            Some(synced_at) if now.duration_since(synced_at) < self.limiter.sync_interval => {}
+            _ => self.sync(now),
+        }
+
+        let capacity = self.limiter.shared.capacity();
+        let slice = self.slices.entry(key.clone()).or_default();
+        if slice.tokens >= cost {
+            slice.tokens -= cost;
+            return Ok(slice.state(capacity));
+        }
+
+        // the shared bucket said no a moment ago, don't ask again before it refilled
+        if let Some(denied_until) = slice.denied_until
+            && now < denied_until
+            && cost <= capacity
+        {
+            let retry_after = denied_until - now;
+            return Err(RateLimitError {
+                kind: RateLimitErrorKind::Limited,
+                retry_after,
+                snapshot: BucketState {
+                    limit: capacity,
+                    remaining: slice.tokens,
+                    reset_after: retry_after,
+                },
+            });
+        }
+
+        let local = slice.tokens;
+        let missing = cost - local;
+        let lease = self.limiter.lease.max(missing).min(capacity.max(missing));
+        // a worker waits for a whole slice instead of scraping up the last tokens,
+        // which keeps a drained bucket at one shared check per slice as well
+        match self.limiter.shared.check_with_cost(key, now, lease) {
+            Ok(state) => {
+                slice.tokens = local + lease - cost;
+                slice.shared_remaining = state.remaining;
+                slice.denied_until = None;
+                Ok(BucketState {
+                    remaining: state.remaining + slice.tokens,
+                    ..state
+                })
+            }
+            Err(mut err) => {
+                if err.kind == RateLimitErrorKind::Limited {
+                    slice.denied_until = now.checked_add(err.retry_after);
+                }
+                err.snapshot.remaining += local;
+                Err(err)
+            }
+        }
+    }
+
+    // puts `cost` back into the worker's slice of the key
+    pub fn refund(&mut self, key: &K, cost: u128) {
+        if let Some(slice) = self.slices.get_mut(key) {
+            slice.tokens += cost;
+        }
+    }
+
+    // tokens this worker holds for the key and can spend without the shared limiter
+    pub fn local_tokens(&self, key: &K) -> u128 {
+        self.slices.get(key).map_or(0, |slice| slice.tokens)
+    }
+
+    // reconciles with the shared limiter: unused tokens go back and the slices
+    // start over. Only runs from a check, an explicit call or drop, so call it
+    // from the worker's own loop when it may sit idle holding a slice
+    pub fn sync(&mut self, now: Instant) {
+        for (key, slice) in self.slices.drain() {
+            if slice.tokens > 0 {
+                self.limiter.shared.refund(&key, now, slice.tokens);
+            }
+        }
+        self.synced_at = Some(now);
+    }
+}
+
+impl Slice {
+    fn state(&self, capacity: u128) -> BucketState {
+        BucketState {
+            limit: capacity,
+            remaining: self.shared_remaining + self.tokens,
+            reset_after: Duration::ZERO,
+        }
+    }
+}
+
+impl<K> Drop for WorkerSlices<K>
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+{
+    fn drop(&mut self) {
+        let now = self.limiter.shared.clock.now();
+        self.sync(now);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::{
+        clock::{Clock, MockClock},
+        rate_limiter::AlgorithmConfig,
+    };
+    use std::sync::Arc;
+
+    #[test]
+    pub fn slices_never_exceed_the_shared_budget() {
+        let clock = Arc::new(MockClock::new());
+        let shared = RateLimiter::new(AlgorithmConfig::TokenBucket {
+            burst: 100,
+            rate: 1,
+        })
+        .with_clock(clock.clone());
+        let sliced = SlicedLimiter::new(shared, 3, 0.2).unwrap();
+        assert_eq!(sliced.lease(), 6);
+
+        let mut workers: Vec<_> = (0..3).map(|_| sliced.worker()).collect();
+        let mut allowed = 0;
+        for round in 0..50 {
+            for worker in &mut workers {
+                if worker.check("key", clock.now()).is_ok() {
+                    allowed += 1;
+                }
+            }
+            assert!(allowed <= 100, "round {round} allowed {allowed}");
+        }
+
+        // only the tokens stranded in slices and less than a slice left in the
+        // shared bucket are lost
+        let stranded: u128 = workers.iter().map(|w| w.local_tokens(&"key")).sum();
+        let left = sliced.shared().peek(&"key", clock.now()).remaining;
+        assert!(stranded <= 20 && left < sliced.lease());
+        assert_eq!(allowed + stranded + left, 100);
+
+        // a sync hands the stranded tokens back
+        for worker in &mut workers {
+            worker.sync(clock.now());
+        }
+        assert_eq!(
+            sliced.shared().peek(&"key", clock.now()).remaining,
+            stranded + left
+        );
+    }
+
+    #[test]
+    pub fn costs_and_periodic_sync() {
+        let clock = Arc::new(MockClock::new());
+        let shared = RateLimiter::new(AlgorithmConfig::TokenBucket { burst: 10, rate: 1 })
+            .with_clock(clock.clone());
+        let sliced = SlicedLimiter::new(shared, 2, 0.5)
+            .unwrap()
+            .with_sync_interval(Duration::from_secs(1));
+
+        let mut a = sliced.worker();
+        let mut b = sliced.worker();
+
+        // a leases 2 and spends 1
+        let state = a.check("key", clock.now()).ok().unwrap();
+        assert_eq!(state.remaining, 9);
+        // bigger than a slice -> exactly the missing part is leased
+        assert!(b.check_with_cost("key", clock.now(), 7).is_ok());
+        assert_eq!(b.local_tokens(&"key"), 0);
+
+        assert!(b.check_with_cost("key", clock.now(), 2).is_err());
+        let err = b.check_with_cost("key", clock.now(), 11).err().unwrap();
+        assert_eq!(err.kind, RateLimitErrorKind::CostExceedsCapacity);
+
+        // a's leftover token comes back on its next sync, 1 refilled + 1 returned
+        clock.advance(Duration::from_secs(1));
+        assert!(a.check_with_cost("key", clock.now(), 2).is_ok());
+        // one token left, b waits for a whole slice of 2
+        let err = b.check("key", clock.now()).err().unwrap();
+        assert_eq!(err.retry_after, Duration::from_secs(1));
+        assert!(b.check("key", clock.now()).is_err());
+
+        // a dropped worker returns what it holds
+        clock.advance(Duration::from_secs(2));
+        assert!(a.check("key", clock.now()).is_ok());
+        assert_eq!(a.local_tokens(&"key"), 1);
+        drop(a);
+        assert_eq!(sliced.shared().peek(&"key", clock.now()).remaining, 2);
+    }
+
+    #[test]
+    pub fn rejects_bad_tolerance() {
+        let shared = || RateLimiter::<()>::new(AlgorithmConfig::TokenBucket { burst: 10, rate: 1 });
+        assert!(matches!(
+            SlicedLimiter::new(shared(), 2, 0.0),
+            Err(SliceError::InvalidTolerance(_))
+        ));
+        assert!(SlicedLimiter::new(shared(), 2, 1.5).is_err());
+        assert!(matches!(
+            SlicedLimiter::new(shared(), 0, 0.1),
+            Err(SliceError::NoWorkers)
+        ));
+    }
+}
@@ -1,6 +1,9 @@
-use crate::rate_limiter::algorithm::RateLimitAlgorithm;
+use std::time::{Duration, Instant};
 
-struct SlidingCounter {
+use crate::rate_limiter::algorithm::{AllowResult, BucketState, RateLimitAlgorithm, SavedBucket};
+
+#[derive(Clone)]
+pub struct SlidingCounter {
     capacity: u128,
     window: Duration,
 
@@ -12,25 +15,37 @@ struct SlidingCounter {
 }
 
 impl RateLimitAlgorithm for SlidingCounter {
-    fn allow(&mut self, now: Instant) -> AllowResult {
-        SlidingCounter::allow(self, now)
+    fn allow_n(&mut self, now: Instant, cost: u128) -> AllowResult {
+        SlidingCounter::allow_n(self, now, cost)
     }
     fn state(&self, now: Instant) -> BucketState {
         SlidingCounter::state(self, now)
     }
+    fn peek(&self, now: Instant) -> BucketState {
+        SlidingCounter::peek(self, now)
+    }
     fn last_seen(&self) -> Instant {
         self.last_seen
     }
     fn set_last_seen(&mut self, now: Instant) {
         self.last_seen = now;
     }
+    fn save(&self, now: Instant) -> SavedBucket {
+        SlidingCounter::save(self, now)
+    }
+    fn load(&mut self, saved: &SavedBucket, taken_at: Instant) -> bool {
+        SlidingCounter::load(self, saved, taken_at)
+    }
+    fn refund(&mut self, now: Instant, cost: u128) {
+        SlidingCounter::refund(self, now, cost)
+    }
 }
 
 impl SlidingCounter {
-    fn new(capacity: u128, refill_rate: u128, now: Instant) -> Self {
+    pub fn new(capacity: u128, window: Duration, now: Instant) -> Self {
         Self {
             capacity,
-            window: Duration::from_secs(refill_rate as u64),
+            window,
 
             current_window_start: now,
             current_count: 0,
@@ -50,6 +65,17 @@ impl SlidingCounter {
     }
 
     fn allow(&mut self, now: Instant) -> AllowResult {
+        self.allow_n(now, 1)
+    }
+
+    fn allow_n(&mut self, now: Instant, cost: u128) -> AllowResult {
+        if cost > self.capacity {
+            return AllowResult::CostExceedsCapacity {
+                cost,
+                capacity: self.capacity,
+            };
+        }
+
         self.last_seen = now;
 
         self.roll_window(now);
@@ -59,8 +85,8 @@ impl SlidingCounter {
 
         let effective = (self.previous_count as f64 * (1.0 - weight)) + self.current_count as f64;
 
-        if effective < self.capacity as f64 {
-            self.current_count += 1;
+        if cost == 0 || effective + ((cost - 1) as f64) < self.capacity as f64 {
+            self.current_count += cost;
             AllowResult::Allowed
         } else {
             let retry_after = self.window - elapsed;
@@ -68,6 +94,13 @@ impl SlidingCounter {
         }
     }
 
+    // rolls a copy of the window, the counts are all that's cloned
+    fn peek(&self, now: Instant) -> BucketState {
+        let mut counter = self.clone();
+        counter.roll_window(now);
+        counter.state(now)
+    }
+
     fn state(&self, now: Instant) -> BucketState {
         let elapsed = now.duration_since(self.current_window_start);
 
@@ -93,4 +126,61 @@ impl SlidingCounter {
             reset_after,
         }
     }
+
+    fn save(&self, now: Instant) -> SavedBucket {
+        SavedBucket::SlidingCounter {
+            since_window_start: now.saturating_duration_since(self.current_window_start),
+            current: self.current_count,
+            previous: self.previous_count,
+        }
+    }
+
+    fn load(&mut self, saved: &SavedBucket, taken_at: Instant) -> bool {
+        let SavedBucket::SlidingCounter {
+            since_window_start,
+            current,
+            previous,
+        } = saved
+        else {
+            return false;
+        };
+        let Some(current_window_start) = taken_at.checked_sub(*since_window_start) else {
+            return false;
+        };
+
+        self.current_window_start = current_window_start;
+        self.current_count = *current;
+        self.previous_count = *previous;
+        true
+    }
+
+    fn refund(&mut self, now: Instant, cost: u128) {
+        self.current_count = self.current_count.saturating_sub(cost);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn peek_rolls_the_window_without_charging() {
+        let t0 = Instant::now();
+        let mut counter = SlidingCounter::new(4, Duration::from_secs(10), t0);
+        let _ = counter.allow_n(t0, 4);
+
+        assert_eq!(counter.peek(t0).remaining, 0);
+        assert_eq!(counter.peek(t0).remaining, 0);
+
+        // a window later the 4 requests become the previous window, fully weighted
+        let later = t0 + Duration::from_secs(10);
+        assert_eq!(counter.peek(later).remaining, 0);
+        assert_eq!(counter.peek(later).reset_after, Duration::from_secs(10));
+
+        // once a check rolled the window, halfway into it only half of them count
+        assert!(matches!(counter.allow(later), AllowResult::Denied { .. }));
+        let half = counter.peek(later + Duration::from_secs(5));
+        assert_eq!(half.remaining, 2);
+        assert_eq!(half.reset_after, Duration::from_secs(5));
+    }
 }
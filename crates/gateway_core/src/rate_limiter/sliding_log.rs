@@ -3,7 +3,7 @@ use std::{
     time::{Duration, Instant},
 };
 
-use crate::rate_limiter::algorithm::{AllowResult, BucketState, RateLimitAlgorithm};
+use crate::rate_limiter::algorithm::{AllowResult, BucketState, RateLimitAlgorithm, SavedBucket};
 
 pub struct SlidingLog {
     capacity: u128,
@@ -13,31 +13,54 @@ pub struct SlidingLog {
 }
 
 impl RateLimitAlgorithm for SlidingLog {
-    fn allow(&mut self, now: Instant) -> AllowResult {
-        SlidingLog::allow(self, now)
+    fn allow_n(&mut self, now: Instant, cost: u128) -> AllowResult {
+        SlidingLog::allow_n(self, now, cost)
     }
     fn state(&self, now: Instant) -> BucketState {
         SlidingLog::state(self, now)
     }
+    fn peek(&self, now: Instant) -> BucketState {
+        SlidingLog::peek(self, now)
+    }
     fn last_seen(&self) -> Instant {
         self.last_seen
     }
     fn set_last_seen(&mut self, now: Instant) {
         self.last_seen = now;
     }
+    fn save(&self, now: Instant) -> SavedBucket {
+        SlidingLog::save(self, now)
+    }
+    fn load(&mut self, saved: &SavedBucket, taken_at: Instant) -> bool {
+        SlidingLog::load(self, saved, taken_at)
+    }
+    fn refund(&mut self, now: Instant, cost: u128) {
+        SlidingLog::refund(self, now, cost)
+    }
 }
 
 impl SlidingLog {
-    pub fn new(capacity: u128, window_seconds: u128, now: Instant) -> Self {
+    pub fn new(capacity: u128, window: Duration, now: Instant) -> Self {
         Self {
             capacity,
-            window: Duration::from_secs(window_seconds as u64),
+            window,
             entries: VecDeque::with_capacity(capacity as usize),
             last_seen: now,
         }
     }
 
     fn allow(&mut self, now: Instant) -> AllowResult {
+        self.allow_n(now, 1)
+    }
+
+    fn allow_n(&mut self, now: Instant, cost: u128) -> AllowResult {
+        if cost > self.capacity {
+            return AllowResult::CostExceedsCapacity {
+                cost,
+                capacity: self.capacity,
+            };
+        }
+
         self.last_seen = now;
 
         while let Some(front) = self.entries.front() {
@@ -48,16 +71,39 @@ impl SlidingLog {
             }
         }
 
-        if self.entries.len() < self.capacity as usize {
-            self.entries.push_back(now);
+        let used = self.entries.len() as u128;
+        if used + cost <= self.capacity {
+            self.entries.extend(std::iter::repeat_n(now, cost as usize));
             AllowResult::Allowed
         } else {
-            let oldest = *self.entries.front().unwrap();
-            let retry_after = self.window - now.duration_since(oldest);
+            // the entry that has to expire before `cost` slots are free
+            let blocking = self.entries[(used + cost - self.capacity - 1) as usize];
+            let retry_after = self.window.saturating_sub(now.duration_since(blocking));
             AllowResult::Denied { retry_after }
         }
     }
 
+    // entries older than the window are skipped instead of popped
+    fn peek(&self, now: Instant) -> BucketState {
+        let expired = self
+            .entries
+            .iter()
+            .take_while(|entry| now.duration_since(**entry) > self.window)
+            .count();
+
+        let reset_after = self
+            .entries
+            .get(expired)
+            .map(|oldest| self.window.saturating_sub(now.duration_since(*oldest)))
+            .unwrap_or(Duration::ZERO);
+
+        BucketState {
+            limit: self.capacity,
+            remaining: self.capacity - (self.entries.len() - expired) as u128,
+            reset_after,
+        }
+    }
+
     fn state(&self, now: Instant) -> BucketState {
         let remaining = self.capacity - self.entries.len() as u128;
 
@@ -78,4 +124,61 @@ impl SlidingLog {
             reset_after,
         }
     }
+
+    pub fn save(&self, now: Instant) -> SavedBucket {
+        SavedBucket::SlidingLog {
+            entry_ages: self
+                .entries
+                .iter()
+                .map(|entry| now.saturating_duration_since(*entry))
+                .collect(),
+        }
+    }
+
+    pub fn load(&mut self, saved: &SavedBucket, taken_at: Instant) -> bool {
+        let SavedBucket::SlidingLog { entry_ages } = saved else {
+            return false;
+        };
+
+        // entries from before this process's clock started are long expired
+        self.entries = entry_ages
+            .iter()
+            .filter_map(|age| taken_at.checked_sub(*age))
+            .take(self.capacity as usize)
+            .collect();
+        true
+    }
+
+    // drop the newest entries, those are the ones the refunded request added
+    pub fn refund(&mut self, now: Instant, cost: u128) {
+        let keep = self.entries.len().saturating_sub(cost as usize);
+        self.entries.truncate(keep);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn peek_skips_expired_entries() {
+        let t0 = Instant::now();
+        let mut log = SlidingLog::new(3, Duration::from_secs(10), t0);
+        let _ = log.allow_n(t0, 2);
+        let _ = log.allow(t0 + Duration::from_secs(5));
+
+        let full = log.peek(t0 + Duration::from_secs(5));
+        assert_eq!(full.remaining, 0);
+        assert_eq!(full.reset_after, Duration::from_secs(5));
+
+        // the first two entries aged out, state still counts them
+        let later = t0 + Duration::from_secs(11);
+        assert_eq!(log.state(later).remaining, 0);
+        let peeked = log.peek(later);
+        assert_eq!(peeked.remaining, 2);
+        assert_eq!(peeked.reset_after, Duration::from_secs(4));
+
+        assert!(matches!(log.allow_n(later, 2), AllowResult::Allowed));
+        assert!(matches!(log.allow(later), AllowResult::Denied { .. }));
+    }
 }
@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::{fs, io, path::Path, time::Duration};
+
+use crate::rate_limiter::{AlgorithmConfig, algorithm::SavedBucket};
+
+// bump whenever SavedBucket or LimiterSnapshot change shape
+pub const SNAPSHOT_VERSION: u32 = 2;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    Format(serde_json::Error),
+    // written by a gateway with a different snapshot layout
+    IncompatibleVersion { found: u32, expected: u32 },
+    // the limit or algorithm changed since the snapshot was taken
+    ConfigMismatch,
+    // older than the allowed age, or taken "after" now (wall clock went back)
+    Stale { age: Option<Duration> },
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> Self {
+        SnapshotError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for SnapshotError {
+    fn from(err: serde_json::Error) -> Self {
+        SnapshotError::Format(err)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedEntry<K> {
+    pub key: K,
+    // time since the key was last seen, so cleanup keeps working after a restore
+    pub idle: Duration,
+    pub bucket: SavedBucket,
+}
+
+// Portable copy of a RateLimiter: `taken_at` is wall-clock time since unix epoch
+// and every bucket timestamp is relative to it, so it survives a restart
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LimiterSnapshot<K> {
+    pub version: u32,
+    pub taken_at: Duration,
+    pub limit: AlgorithmConfig,
+    pub buckets: Vec<SavedEntry<K>>,
+}
+
+impl<K> LimiterSnapshot<K>
+where
+    K: Serialize + DeserializeOwned,
+{
+    pub fn write(&self, path: &Path) -> Result<(), SnapshotError> {
+        write_json(path, self)
+    }
+
+    pub fn read(path: &Path) -> Result<Self, SnapshotError> {
+        read_versioned(path, SNAPSHOT_VERSION)
+    }
+}
+
+// write to a temp file first so a crash never leaves half a snapshot behind
+pub fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), SnapshotError> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec(value)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+// reads a snapshot whose top level object carries a `version` field
+pub fn read_versioned<T: DeserializeOwned>(path: &Path, expected: u32) -> Result<T, SnapshotError> {
+    let raw = fs::read(path)?;
+
+    // check the version before trusting the rest of the layout
+    #[derive(Deserialize)]
+    struct Header {
+        version: u32,
+    }
+    let header: Header = serde_json::from_slice(&raw)?;
+    if header.version != expected {
+        return Err(SnapshotError::IncompatibleVersion {
+            found: header.version,
+            expected,
+        });
+    }
+
+    Ok(serde_json::from_slice(&raw)?)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::rate_limiter::RateLimiter;
+    use std::net::IpAddr;
+
+    #[test]
+    pub fn file_round_trip() {
+        let limiter = RateLimiter::new(AlgorithmConfig::SlidingLog {
+            limit: 5,
+            window: Duration::from_secs(1),
+        });
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        assert!(limiter.check(ip, std::time::Instant::now()).is_ok());
+
+        let path = std::env::temp_dir().join(format!("snapshot-{}.json", std::process::id()));
+        let snapshot = limiter.snapshot();
+        snapshot.write(&path).unwrap();
+        let read = LimiterSnapshot::<IpAddr>::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(read, snapshot);
+    }
+
+    #[test]
+    pub fn read_rejects_other_versions() {
+        let path = std::env::temp_dir().join(format!("snapshot-v0-{}.json", std::process::id()));
+        fs::write(&path, r#"{"version":0,"layout":"unknown"}"#).unwrap();
+        let result = LimiterSnapshot::<String>::read(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            result,
+            Err(SnapshotError::IncompatibleVersion {
+                found: 0,
+                expected: SNAPSHOT_VERSION
+            })
+        ));
+    }
+}
@@ -3,7 +3,7 @@ use std::{
     time::{Duration, Instant},
 };
 
-use crate::rate_limiter::algorithm::{AllowResult, BucketState, RateLimitAlgorithm};
+use crate::rate_limiter::algorithm::{AllowResult, BucketState, RateLimitAlgorithm, SavedBucket};
 
 #[derive(Clone)]
 pub struct TokenBucket {
@@ -15,32 +15,44 @@ pub struct TokenBucket {
 }
 
 impl RateLimitAlgorithm for TokenBucket {
-    fn allow(&mut self, now: Instant) -> AllowResult {
-        TokenBucket::allow(self, now)
+    fn allow_n(&mut self, now: Instant, cost: u128) -> AllowResult {
+        TokenBucket::allow_n(self, now, cost)
     }
     fn state(&self, now: Instant) -> BucketState {
         TokenBucket::state(self, now)
     }
+    fn peek(&self, now: Instant) -> BucketState {
+        TokenBucket::peek(self, now)
+    }
     fn last_seen(&self) -> Instant {
         self.last_seen
     }
     fn set_last_seen(&mut self, now: Instant) {
         self.last_seen = now;
     }
+    fn save(&self, now: Instant) -> SavedBucket {
+        TokenBucket::save(self, now)
+    }
+    fn load(&mut self, saved: &SavedBucket, taken_at: Instant) -> bool {
+        TokenBucket::load(self, saved, taken_at)
+    }
+    fn refund(&mut self, now: Instant, cost: u128) {
+        TokenBucket::refund(self, now, cost)
+    }
 }
 impl TokenBucket {
     pub fn new(max_capacity: u128, refill_rate: u128, now: Instant) -> Self {
         Self {
-            max_capacity: max_capacity,
+            max_capacity,
             current_tokens: max_capacity,
-            refill_rate: refill_rate,
+            refill_rate,
             last_refill_time: now,
             last_seen: now,
         }
     }
 
     pub fn state(&self, now: Instant) -> BucketState {
-        let token_interval = Duration::from_secs_f64(1.0 / self.refill_rate as f64);
+        let token_interval = self.refill_time(1);
         let elapsed = now - self.last_refill_time;
 
         let mut reset_after = if elapsed >= token_interval {
@@ -63,6 +75,48 @@ impl TokenBucket {
     }
 
     pub fn allow(&mut self, current_ts: Instant) -> AllowResult {
+        self.allow_n(current_ts, 1)
+    }
+
+    pub fn allow_n(&mut self, current_ts: Instant, cost: u128) -> AllowResult {
+        if cost > self.max_capacity {
+            return AllowResult::CostExceedsCapacity {
+                cost,
+                capacity: self.max_capacity,
+            };
+        }
+
+        self.refill(current_ts);
+
+        //check if the tokens are present
+        if self.current_tokens >= cost {
+            self.current_tokens -= cost;
+            return AllowResult::Allowed;
+        }
+
+        // 1. time taken to generate the missing tokens
+        let missing = cost - self.current_tokens;
+        let token_interval = self.refill_time(missing);
+
+        // 2. difference of current time received and last refill time
+        let elapsed_time_since_last = current_ts - self.last_refill_time;
+
+        // retry after (1-2)
+        // let retry_after = token_interval - elapsed_time_since_last;
+        let retry_after = token_interval
+            .checked_sub(elapsed_time_since_last)
+            .unwrap_or(Duration::ZERO);
+
+        AllowResult::Denied { retry_after }
+    }
+
+    // time to earn `tokens`, a bucket with no refill rate never earns any
+    fn refill_time(&self, tokens: u128) -> Duration {
+        Duration::try_from_secs_f64(tokens as f64 / self.refill_rate as f64)
+            .unwrap_or(Duration::MAX)
+    }
+
+    fn refill(&mut self, current_ts: Instant) {
         let elapsed = current_ts.duration_since(self.last_refill_time);
         let tokens_float = elapsed.as_secs_f64() * (self.refill_rate as f64);
 
@@ -83,24 +137,41 @@ impl TokenBucket {
                 }
             }
         }
-        //check if the tokens are present
-        if self.current_tokens > 0 {
-            self.current_tokens = self.current_tokens - 1;
-            return AllowResult::Allowed;
-        }
+    }
 
-        let token_interval = Duration::from_secs_f64(1.0 / self.refill_rate as f64); // 1. time taken to generate 1 token
+    // refill a copy so the tokens earned since the last allow_n show up
+    pub fn peek(&self, now: Instant) -> BucketState {
+        let mut bucket = self.clone();
+        bucket.refill(now);
+        bucket.state(now)
+    }
 
-        // 2. difference of current time received and last refill time
-        let elapsed_time_since_last = current_ts - self.last_refill_time;
+    pub fn save(&self, now: Instant) -> SavedBucket {
+        SavedBucket::TokenBucket {
+            tokens: self.current_tokens,
+            since_refill: now.saturating_duration_since(self.last_refill_time),
+        }
+    }
 
-        // retry after (1-2)
-        // let retry_after = token_interval - elapsed_time_since_last;
-        let retry_after = token_interval
-            .checked_sub(elapsed_time_since_last)
-            .unwrap_or(Duration::ZERO);
+    pub fn load(&mut self, saved: &SavedBucket, taken_at: Instant) -> bool {
+        let SavedBucket::TokenBucket {
+            tokens,
+            since_refill,
+        } = saved
+        else {
+            return false;
+        };
+        let Some(last_refill_time) = taken_at.checked_sub(*since_refill) else {
+            return false;
+        };
+
+        self.current_tokens = (*tokens).min(self.max_capacity);
+        self.last_refill_time = last_refill_time;
+        true
+    }
 
-        return AllowResult::Denied { retry_after };
+    pub fn refund(&mut self, now: Instant, cost: u128) {
+        self.current_tokens = min(self.max_capacity, self.current_tokens + cost);
     }
 }
 
@@ -171,7 +242,7 @@ pub mod tests {
             let _ = bucket.allow(t0);
         }
         //bucket empty -> check after 1400ms or 1.4s -> i.e allow 7 times
-        t0 = t0 + Duration::from_secs_f64(1.4);
+        t0 += Duration::from_secs_f64(1.4);
         for _ in 0..7 {
             assert!(matches!(bucket.allow(t0), AllowResult::Allowed));
         }
@@ -199,4 +270,58 @@ pub mod tests {
 
         assert!(matches!(bucket.allow(t1), AllowResult::Denied { .. }));
     }
+
+    #[test]
+    pub fn retry_after_covers_missing_tokens() {
+        let t0 = Instant::now();
+        let mut bucket = TokenBucket::new(5, 5, t0);
+
+        assert!(matches!(bucket.allow_n(t0, 4), AllowResult::Allowed));
+        // 1 token left, 2 more needed -> 400ms
+        match bucket.allow_n(t0, 3) {
+            AllowResult::Denied { retry_after } => {
+                assert_eq!(retry_after, Duration::from_millis(400))
+            }
+            _ => panic!("expected denial"),
+        }
+        assert!(matches!(
+            bucket.allow_n(t0 + Duration::from_millis(400), 3),
+            AllowResult::Allowed
+        ));
+    }
+
+    #[test]
+    pub fn peek_includes_pending_refill() {
+        let t0 = Instant::now();
+        let mut bucket = TokenBucket::new(5, 5, t0);
+        for _ in 0..5 {
+            let _ = bucket.allow(t0);
+        }
+
+        // state only moves on allow, peek sees the 2 tokens earned since
+        let later = t0 + Duration::from_millis(400);
+        assert_eq!(bucket.state(later).remaining, 0);
+        assert_eq!(bucket.peek(later).remaining, 2);
+        assert_eq!(bucket.peek(later).remaining, 2);
+
+        assert!(matches!(bucket.allow_n(later, 2), AllowResult::Allowed));
+        assert!(matches!(bucket.allow(later), AllowResult::Denied { .. }));
+    }
+
+    #[test]
+    pub fn zero_refill_rate_never_refills() {
+        let t0 = Instant::now();
+        let mut bucket = TokenBucket::new(2, 0, t0);
+
+        assert_eq!(bucket.state(t0).reset_after, Duration::ZERO);
+        assert!(matches!(bucket.allow_n(t0, 2), AllowResult::Allowed));
+        assert_eq!(bucket.state(t0).reset_after, Duration::MAX);
+
+        let later = t0 + Duration::from_secs(3600);
+        assert!(matches!(
+            bucket.allow(later),
+            AllowResult::Denied { retry_after } if retry_after > Duration::from_secs(u64::MAX / 2)
+        ));
+        assert_eq!(bucket.peek(later).remaining, 0);
+    }
 }
@@ -0,0 +1,415 @@
+use clap::Parser;
+use clap_derive::Parser;
+use gateway_core::{
+    clock::{Clock, MockClock},
+    rate_limiter::{
+        AlgorithmConfig, RateLimiter, algorithm_config::AlgorithmConfigError,
+        rate_limiter::AlgorithmType,
+    },
+};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    sync::Arc,
+    time::Duration,
+};
+
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "Replays a request trace against rate limit algorithms in virtual time"
+)]
+struct Args {
+    // JSONL, one {"ts": <seconds>, "key": "...", "cost": 1} per line, "-" reads stdin
+    #[arg(short, long)]
+    trace: String,
+
+    #[arg(short, long)]
+    capacity: u128,
+
+    // tokens a second, token_bucket and gcra only
+    #[arg(short, long)]
+    refill_rate: Option<u128>,
+
+    // sliding_log, sliding_counter and fixed_window only, fractions allowed
+    #[arg(short, long, value_parser = parse_secs)]
+    window_secs: Option<Duration>,
+
+    // the first one is the baseline the others are diffed against
+    #[arg(
+        short,
+        long,
+        value_delimiter = ',',
+        default_value = "token_bucket,sliding_log,sliding_counter,gcra,fixed_window"
+    )]
+    algorithms: Vec<String>,
+
+    // keys listed per algorithm in the diff
+    #[arg(long, default_value = "10")]
+    top: usize,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct TraceEntry {
+    ts: f64,
+    key: String,
+    #[serde(default = "default_cost")]
+    cost: u128,
+}
+
+fn default_cost() -> u128 {
+    1
+}
+
+#[derive(Default)]
+struct KeyStats {
+    allowed: u128,
+    first_ts: f64,
+    last_ts: f64,
+}
+
+struct Replay {
+    algorithm: String,
+    limit: AlgorithmConfig,
+    // one decision per trace entry, true = allowed
+    decisions: Vec<bool>,
+    keys: BTreeMap<String, KeyStats>,
+}
+
+impl Replay {
+    fn allowed(&self) -> usize {
+        self.decisions.iter().filter(|allowed| **allowed).count()
+    }
+
+    // allowed cost per second while the key was active, at least one second so a
+    // single burst isn't reported as an enormous rate
+    fn key_rates(&self) -> Vec<f64> {
+        let mut rates: Vec<f64> = self
+            .keys
+            .values()
+            .map(|key| key.allowed as f64 / (key.last_ts - key.first_ts).max(1.0))
+            .collect();
+        rates.sort_by(f64::total_cmp);
+        rates
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+    let trace = read_trace(&args.trace).unwrap_or_else(|err| {
+        eprintln!("{err}");
+        std::process::exit(1);
+    });
+
+    let replays: Vec<Replay> = args
+        .algorithms
+        .iter()
+        .map(|name| {
+            let algorithm = parse_algorithm(name).unwrap_or_else(|| {
+                eprintln!("unknown algorithm {name}");
+                std::process::exit(1);
+            });
+            let limit = limit_for(algorithm, args.capacity, args.refill_rate, args.window_secs)
+                .unwrap_or_else(|err| {
+                    eprintln!("{name}: invalid limit ({err:?})");
+                    std::process::exit(1);
+                });
+            replay(&trace, name, &limit)
+        })
+        .collect();
+
+    report(&args, &trace, &replays);
+}
+
+fn parse_algorithm(name: &str) -> Option<AlgorithmType> {
+    match name.trim() {
+        "token_bucket" => Some(AlgorithmType::TokenBucket),
+        "sliding_log" => Some(AlgorithmType::SlidingLog),
+        "sliding_counter" => Some(AlgorithmType::SlidingCounter),
+        "gcra" => Some(AlgorithmType::Gcra),
+        "fixed_window" => Some(AlgorithmType::FixedWindow),
+        _ => None,
+    }
+}
+
+// rate based algorithms take --refill-rate, window based ones --window-secs
+fn limit_for(
+    algorithm: AlgorithmType,
+    capacity: u128,
+    refill_rate: Option<u128>,
+    window: Option<Duration>,
+) -> Result<AlgorithmConfig, AlgorithmConfigError> {
+    let mut builder = AlgorithmConfig::builder(algorithm.clone()).with_capacity(capacity);
+    match algorithm {
+        AlgorithmType::TokenBucket | AlgorithmType::Gcra => {
+            if let Some(refill_rate) = refill_rate {
+                builder = builder.with_rate(refill_rate);
+            }
+        }
+        _ => {
+            if let Some(window) = window {
+                builder = builder.with_window(window);
+            }
+        }
+    }
+    builder.build()
+}
+
+fn parse_secs(raw: &str) -> Result<Duration, String> {
+    let secs: f64 = raw
+        .parse()
+        .map_err(|_| format!("{raw} is not a number of seconds"))?;
+    Duration::try_from_secs_f64(secs).map_err(|err| err.to_string())
+}
+
+fn describe(limit: &AlgorithmConfig) -> String {
+    match limit {
+        AlgorithmConfig::TokenBucket { burst, rate } | AlgorithmConfig::Gcra { burst, rate } => {
+            format!("burst {burst}, refill rate {rate}/s")
+        }
+        AlgorithmConfig::SlidingLog { limit, window }
+        | AlgorithmConfig::SlidingCounter { limit, window }
+        | AlgorithmConfig::FixedWindow { limit, window } => {
+            format!("{limit} per {window:?} window")
+        }
+    }
+}
+
+// sorted by ts, a trace merged from several hosts is rarely in order
+fn read_trace(path: &str) -> Result<Vec<TraceEntry>, String> {
+    let reader: Box<dyn BufRead> = match path {
+        "-" => Box::new(BufReader::new(io::stdin())),
+        path => Box::new(BufReader::new(
+            File::open(path).map_err(|err| format!("{path}: {err}"))?,
+        )),
+    };
+
+    let mut trace = Vec::new();
+    for (number, line) in reader.lines().enumerate() {
+        let line = line.map_err(|err| format!("{path}: {err}"))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: TraceEntry =
+            serde_json::from_str(&line).map_err(|err| format!("{path}:{}: {err}", number + 1))?;
+        if !entry.ts.is_finite() {
+            return Err(format!("{path}:{}: ts is not a number", number + 1));
+        }
+        trace.push(entry);
+    }
+
+    trace.sort_by(|a, b| a.ts.total_cmp(&b.ts));
+    Ok(trace)
+}
+
+// Every request is checked at its trace timestamp on a clock that starts at the
+// first one, so fixed windows line up with the trace and every run decides the same
+fn replay(trace: &[TraceEntry], name: &str, limit: &AlgorithmConfig) -> Replay {
+    let first_ts = trace.first().map_or(0.0, |entry| entry.ts);
+    let clock = Arc::new(MockClock::at(Duration::from_secs_f64(first_ts.max(0.0))));
+    let limiter = RateLimiter::new(*limit).with_clock(clock.clone());
+    let start = clock.now();
+
+    let mut decisions = Vec::with_capacity(trace.len());
+    let mut keys: BTreeMap<String, KeyStats> = BTreeMap::new();
+
+    for entry in trace {
+        let now = start + Duration::from_secs_f64(entry.ts - first_ts);
+        let allowed = limiter
+            .check_with_cost(entry.key.clone(), now, entry.cost)
+            .is_ok();
+        decisions.push(allowed);
+
+        let key = keys.entry(entry.key.clone()).or_insert_with(|| KeyStats {
+            first_ts: entry.ts,
+            ..Default::default()
+        });
+        key.last_ts = entry.ts;
+        if allowed {
+            key.allowed += entry.cost;
+        }
+    }
+
+    Replay {
+        algorithm: name.to_string(),
+        limit: *limit,
+        decisions,
+        keys,
+    }
+}
+
+fn quantile(sorted: &[f64], q: f64) -> f64 {
+    match sorted.len() {
+        0 => 0.0,
+        len => sorted[((len - 1) as f64 * q).round() as usize],
+    }
+}
+
+fn report(args: &Args, trace: &[TraceEntry], replays: &[Replay]) {
+    let span = match (trace.first(), trace.last()) {
+        (Some(first), Some(last)) => last.ts - first.ts,
+        _ => 0.0,
+    };
+    let keys = replays.first().map_or(0, |replay| replay.keys.len());
+
+    println!("\n==== Trace Replay ====");
+    println!("Requests: {}", trace.len());
+    println!("Keys: {keys}");
+    println!("Virtual Duration: {span:.2}s");
+
+    println!("\nLimits:");
+    for replay in replays {
+        println!("  {:<16} {}", replay.algorithm, describe(&replay.limit));
+    }
+
+    println!("\nDecisions:");
+    println!(
+        "  {:<16} {:>10} {:>10} {:>9}",
+        "algorithm", "allowed", "denied", "denied %"
+    );
+    for replay in replays {
+        let allowed = replay.allowed();
+        let denied = replay.decisions.len() - allowed;
+        let denied_pct = 100.0 * denied as f64 / replay.decisions.len().max(1) as f64;
+        println!(
+            "  {:<16} {:>10} {:>10} {:>8.2}%",
+            replay.algorithm, allowed, denied, denied_pct
+        );
+    }
+
+    println!("\nEffective Rate per Key (allowed cost/s while active):");
+    println!(
+        "  {:<16} {:>9} {:>9} {:>9} {:>9}",
+        "algorithm", "p50", "p90", "p99", "max"
+    );
+    for replay in replays {
+        let rates = replay.key_rates();
+        println!(
+            "  {:<16} {:>9.2} {:>9.2} {:>9.2} {:>9.2}",
+            replay.algorithm,
+            quantile(&rates, 0.50),
+            quantile(&rates, 0.90),
+            quantile(&rates, 0.99),
+            quantile(&rates, 1.0)
+        );
+    }
+
+    let Some((baseline, others)) = replays.split_first() else {
+        return;
+    };
+    for other in others {
+        diff(baseline, other, args.top);
+    }
+}
+
+fn diff(baseline: &Replay, other: &Replay, top: usize) {
+    let (mut only_baseline, mut only_other) = (0, 0);
+    for (a, b) in baseline.decisions.iter().zip(&other.decisions) {
+        match (a, b) {
+            (true, false) => only_baseline += 1,
+            (false, true) => only_other += 1,
+            _ => {}
+        }
+    }
+
+    println!("\n{} vs {}:", other.algorithm, baseline.algorithm);
+    println!("  Allowed only by {}: {only_baseline}", baseline.algorithm);
+    println!("  Allowed only by {}: {only_other}", other.algorithm);
+
+    let mut keys: Vec<(&String, i128)> = baseline
+        .keys
+        .iter()
+        .map(|(key, stats)| {
+            let theirs = other.keys.get(key).map_or(0, |stats| stats.allowed);
+            (key, theirs as i128 - stats.allowed as i128)
+        })
+        .filter(|(_, delta)| *delta != 0)
+        .collect();
+    keys.sort_by_key(|(key, delta)| (std::cmp::Reverse(delta.abs()), *key));
+
+    for (key, delta) in keys.into_iter().take(top) {
+        println!("  {key:<32} {delta:>+10}");
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    fn entry(ts: f64, key: &str) -> TraceEntry {
+        TraceEntry {
+            ts,
+            key: key.to_string(),
+            cost: 1,
+        }
+    }
+
+    #[test]
+    pub fn replay_is_deterministic_and_algorithms_differ() {
+        // a burst of 4 at a window boundary, then one a second
+        let mut trace = vec![
+            entry(0.9, "a"),
+            entry(0.95, "a"),
+            entry(1.0, "a"),
+            entry(1.05, "a"),
+        ];
+        trace.extend((2..6).map(|s| entry(s as f64, "a")));
+        trace.push(entry(3.5, "b"));
+        trace.sort_by(|a, b| a.ts.total_cmp(&b.ts));
+
+        let second = Some(Duration::from_secs(1));
+        let limit = |algorithm| limit_for(algorithm, 2, Some(1), second).unwrap();
+        let bucket = replay(&trace, "token_bucket", &limit(AlgorithmType::TokenBucket));
+        let again = replay(&trace, "token_bucket", &limit(AlgorithmType::TokenBucket));
+        assert_eq!(bucket.decisions, again.decisions);
+        assert_eq!(
+            bucket.decisions,
+            vec![true, true, false, false, true, true, true, true, true]
+        );
+        assert_eq!(bucket.keys["a"].allowed, 6);
+        assert_eq!(bucket.keys["b"].allowed, 1);
+
+        // fixed windows reset at the boundary and let the burst through
+        let window = replay(&trace, "fixed_window", &limit(AlgorithmType::FixedWindow));
+        assert!(window.allowed() > bucket.allowed());
+        assert_eq!(quantile(&bucket.key_rates(), 1.0), 6.0 / 4.1);
+    }
+
+    #[test]
+    pub fn each_algorithm_reads_its_own_flag() {
+        assert_eq!(
+            limit_for(
+                AlgorithmType::Gcra,
+                20,
+                Some(5),
+                Some(Duration::from_secs(4))
+            ),
+            Ok(AlgorithmConfig::Gcra { burst: 20, rate: 5 })
+        );
+        assert_eq!(
+            limit_for(
+                AlgorithmType::SlidingLog,
+                20,
+                Some(5),
+                Some(Duration::from_secs(4))
+            ),
+            Ok(AlgorithmConfig::SlidingLog {
+                limit: 20,
+                window: Duration::from_secs(4)
+            })
+        );
+        // rejected up front instead of panicking in the limiter
+        assert_eq!(
+            limit_for(AlgorithmType::Gcra, 20, Some(0), None),
+            Err(AlgorithmConfigError::ZeroRate)
+        );
+        assert_eq!(
+            limit_for(AlgorithmType::FixedWindow, 20, Some(5), None),
+            Err(AlgorithmConfigError::MissingWindow)
+        );
+        assert_eq!(parse_secs("0.5"), Ok(Duration::from_millis(500)));
+        assert!(parse_secs("-1").is_err());
+    }
+}
@@ -179,11 +179,11 @@ fn weighted_url(base: &str, routes: usize) -> String {
 fn export_csv(hist: &Histogram<u64>) -> Result<(), Box<dyn std::error::Error>> {
     let mut wtr = csv::Writer::from_path("latency.csv")?;
 
-    wtr.write_record(&["quantile", "latency_microseconds"])?;
+    wtr.write_record(["quantile", "latency_microseconds"])?;
 
     for q in [0.50, 0.90, 0.95, 0.99, 1.0] {
         let value = hist.value_at_quantile(q);
-        wtr.write_record(&[q.to_string(), value.to_string()])?;
+        wtr.write_record([q.to_string(), value.to_string()])?;
     }
 
     wtr.flush()?;
@@ -1,4 +1,15 @@
-use std::env;
+use chrono_tz::Tz;
+use gateway_core::{
+    layer::KeyTemplate,
+    rate_limiter::{
+        AlgorithmConfig,
+        algorithm_config::{AlgorithmConfigBuilder, AlgorithmConfigError},
+        overrides::{IpRange, KeyPolicy, OverrideError},
+        rate_limiter::AlgorithmType,
+        redis_store::RedisStore,
+    },
+};
+use std::{env, time::Duration};
 
 static GLOBAL_CAPACITY_DEFAULT: u128 = 1;
 static GLOBAL_REFILL_RATE_DEFAULT: u128 = 1;
@@ -6,45 +17,562 @@ static IP_CAPACITY_DEFAULT: u128 = 1;
 static IP_REFILL_RATE_DEFAULT: u128 = 1;
 static ROUTE_CAPACITY_DEFAULT: u128 = 1;
 static ROUTE_REFILL_RATE_DEFAULT: u128 = 1;
-// static UPSTREAM_BASE_URL: &'static str = "Hello";
-static RATE_LIMITER_ALGO_DEFAULT: &'static str = "token_bucket";
+// window of sliding_log, sliding_counter and fixed_window in seconds, fractions allowed.
+// 0 -> the tier's refill rate is read as the window in seconds, as it was before
+// *_WINDOW_SECS existed
+static WINDOW_SECS_DEFAULT: f64 = 0.0;
+// static UPSTREAM_BASE_URL: &str = "Hello";
+static RATE_LIMITER_ALGO_DEFAULT: &str = "token_bucket";
+static SHAPING_ENABLED_DEFAULT: bool = false;
+static SHAPING_MAX_QUEUE_DEFAULT: u128 = 10;
+static SHAPING_MAX_WAIT_MS_DEFAULT: u128 = 1000;
+static REQUEST_COST_DEFAULT: u128 = 1;
+// 0 -> no in-flight cap
+static GLOBAL_MAX_IN_FLIGHT_DEFAULT: u128 = 0;
+static ROUTE_MAX_IN_FLIGHT_DEFAULT: u128 = 0;
+static IP_MAX_IN_FLIGHT_DEFAULT: u128 = 0;
+// off | aimd | gradient
+static ADAPTIVE_CONCURRENCY_DEFAULT: &str = "off";
+static ADAPTIVE_INITIAL_LIMIT_DEFAULT: u128 = 20;
+static ADAPTIVE_MIN_LIMIT_DEFAULT: u128 = 1;
+static ADAPTIVE_MAX_LIMIT_DEFAULT: u128 = 1000;
+static ADAPTIVE_LATENCY_THRESHOLD_MS_DEFAULT: u128 = 1000;
+// dashmap | mutex | sharded
+static BUCKET_STORE_DEFAULT: &str = "dashmap";
+static BUCKET_STORE_SHARDS_DEFAULT: u128 = 16;
+static REDIS_URL_DEFAULT: &str = "redis://127.0.0.1:6379";
+static REDIS_POOL_SIZE_DEFAULT: u128 = 8;
+static REDIS_TIMEOUT_MS_DEFAULT: u128 = 50;
+// true -> allow requests while redis is unreachable, false -> deny them
+static REDIS_FAIL_OPEN_DEFAULT: bool = true;
+// empty -> limiter state is not persisted across restarts
+static SNAPSHOT_DIR_DEFAULT: &str = "";
+static SNAPSHOT_MAX_AGE_SECS_DEFAULT: u128 = 300;
+// 0 -> no cap on tracked keys
+static ROUTE_MAX_KEYS_DEFAULT: u128 = 0;
+static IP_MAX_KEYS_DEFAULT: u128 = 0;
+// overflow | lru
+static KEY_EVICTION_POLICY_DEFAULT: &str = "overflow";
+// dry-run tiers are evaluated and reported but never deny
+static DRY_RUN_DEFAULT: bool = false;
+// empty -> shadow policies use RATE_LIMITER_ALGO
+static SHADOW_ALGO_DEFAULT: &str = "";
+// 0 -> no shadow policy for the tier
+static SHADOW_CAPACITY_DEFAULT: u128 = 0;
+static SHADOW_REFILL_RATE_DEFAULT: u128 = 1;
+// e.g. "10.0.0.0/8=allow,203.0.113.7=deny,198.51.100.0/24=1000:100"
+static IP_OVERRIDES_DEFAULT: &str = "";
+// e.g. "health=allow,export=5:1"
+static ROUTE_OVERRIDES_DEFAULT: &str = "";
+// 0 -> no quota tier
+static QUOTA_LIMIT_DEFAULT: u128 = 0;
+// daily | monthly
+static QUOTA_PERIOD_DEFAULT: &str = "daily";
+// IANA name, periods reset at local midnight
+static QUOTA_TIMEZONE_DEFAULT: &str = "UTC";
+// true -> give the tokens back when the upstream answers 5xx
+static REFUND_ON_UPSTREAM_ERROR_DEFAULT: bool = false;
+// true -> the global limiter runs a lock-free GCRA, whatever RATE_LIMITER_ALGO says
+static GLOBAL_LOCK_FREE_DEFAULT: bool = false;
+// what the route and ip tiers key on, e.g. "{header:x-api-key|ip}" or "{query:tenant}:{route}"
+static ROUTE_KEY_TEMPLATE_DEFAULT: &str = "{route}";
+static IP_KEY_TEMPLATE_DEFAULT: &str = "{ip}";
+// counters per tier and decision for /debug/top-keys, 0 -> not tracked
+static TOP_KEYS_CAPACITY_DEFAULT: u128 = 0;
+// one in N allowed requests is recorded (and counts N), denied ones always are
+static TOP_KEYS_SAMPLE_EVERY_DEFAULT: u128 = 100;
+
+// one entry of ROUTE_COSTS, e.g. "POST /api/search=5" or "/api/export=10" (any method)
+#[derive(Clone, Debug, PartialEq)]
+pub struct RouteCost {
+    pub method: Option<String>,
+    pub prefix: String,
+    pub cost: u128,
+}
 
 #[derive(Clone)]
 pub struct GatewayConfig {
     pub global_capacity: u128,
-    pub global_refill_rate: u128,
+    // None when unset, the tier's default applies unless *_WINDOW_SECS makes it moot
+    pub global_refill_rate: Option<u128>,
     pub ip_capacity: u128,
-    pub ip_refill_rate: u128,
+    pub ip_refill_rate: Option<u128>,
     pub route_capacity: u128,
-    pub route_refill_rate: u128,
+    pub route_refill_rate: Option<u128>,
+    pub global_window: Duration,
+    pub ip_window: Duration,
+    pub route_window: Duration,
     pub upstream_base_url: String,
     pub algorithm: String,
+    pub shaping_enabled: bool,
+    pub shaping_max_queue: u128,
+    pub shaping_max_wait_ms: u128,
+    pub default_cost: u128,
+    pub route_costs: Vec<RouteCost>,
+    pub global_max_in_flight: u128,
+    pub route_max_in_flight: u128,
+    pub ip_max_in_flight: u128,
+    pub adaptive_concurrency: String,
+    pub adaptive_initial_limit: u128,
+    pub adaptive_min_limit: u128,
+    pub adaptive_max_limit: u128,
+    pub adaptive_latency_threshold_ms: u128,
+    pub bucket_store: String,
+    pub bucket_store_shards: u128,
+    pub redis_url: String,
+    pub redis_pool_size: u128,
+    pub redis_timeout_ms: u128,
+    pub redis_fail_open: bool,
+    pub snapshot_dir: String,
+    pub snapshot_max_age_secs: u128,
+    pub route_max_keys: u128,
+    pub ip_max_keys: u128,
+    pub key_eviction_policy: String,
+    pub global_dry_run: bool,
+    pub route_dry_run: bool,
+    pub ip_dry_run: bool,
+    pub shadow_algorithm: String,
+    pub shadow_global_capacity: u128,
+    pub shadow_global_refill_rate: Option<u128>,
+    pub shadow_route_capacity: u128,
+    pub shadow_route_refill_rate: Option<u128>,
+    pub shadow_ip_capacity: u128,
+    pub shadow_ip_refill_rate: Option<u128>,
+    pub shadow_global_window: Duration,
+    pub shadow_route_window: Duration,
+    pub shadow_ip_window: Duration,
+    pub ip_overrides: Vec<(IpRange, KeyPolicy)>,
+    pub route_overrides: Vec<(String, KeyPolicy)>,
+    pub quota_limit: u128,
+    pub quota_period: String,
+    pub quota_timezone: Tz,
+    pub refund_on_upstream_error: bool,
+    pub global_lock_free: bool,
+    pub route_key_template: KeyTemplate,
+    pub ip_key_template: KeyTemplate,
+    pub top_keys_capacity: u128,
+    pub top_keys_sample_every: u128,
 }
 
 #[derive(Debug)]
 pub enum ConfigError {
     InvalidNumber(&'static str),
     InvalidValue(&'static str),
+    // the tier's capacity, refill rate and window don't fit its algorithm
+    InvalidLimit(&'static str, AlgorithmConfigError),
+    // BUCKET_STORE=redis has no script for the algorithm
+    UnsupportedByStore(AlgorithmType),
 }
 
 //
 impl GatewayConfig {
     pub fn from_env() -> Result<Self, ConfigError> {
-        Ok(Self {
+        // override limits are validated for the algorithm of the tier they belong to
+        let algorithm = parse_algorithm(&Self::read_string(
+            "RATE_LIMITER_ALGO",
+            RATE_LIMITER_ALGO_DEFAULT,
+        ))
+        .ok_or(ConfigError::InvalidValue("RATE_LIMITER_ALGO"))?;
+
+        let config = Self {
             global_capacity: Self::read_u128("GLOBAL_CAPACITY", GLOBAL_CAPACITY_DEFAULT),
-            global_refill_rate: Self::read_u128("GLOBAL_REFILL_RATE", GLOBAL_REFILL_RATE_DEFAULT),
+            global_refill_rate: Self::read_optional_u128("GLOBAL_REFILL_RATE"),
 
             ip_capacity: Self::read_u128("IP_CAPACITY", IP_CAPACITY_DEFAULT),
-            ip_refill_rate: Self::read_u128("IP_REFILL_RATE", IP_REFILL_RATE_DEFAULT),
+            ip_refill_rate: Self::read_optional_u128("IP_REFILL_RATE"),
 
             route_capacity: Self::read_u128("ROUTE_CAPACITY", ROUTE_CAPACITY_DEFAULT),
-            route_refill_rate: Self::read_u128("ROUTE_REFILL_RATE", ROUTE_REFILL_RATE_DEFAULT),
+            route_refill_rate: Self::read_optional_u128("ROUTE_REFILL_RATE"),
+
+            global_window: Self::read_secs("GLOBAL_WINDOW_SECS", WINDOW_SECS_DEFAULT)?,
+            ip_window: Self::read_secs("IP_WINDOW_SECS", WINDOW_SECS_DEFAULT)?,
+            route_window: Self::read_secs("ROUTE_WINDOW_SECS", WINDOW_SECS_DEFAULT)?,
 
             upstream_base_url: Self::read_string("UPSTREAM_BASE_URL", "https://httpbin.org"),
 
             algorithm: Self::read_string("RATE_LIMITER_ALGO", RATE_LIMITER_ALGO_DEFAULT),
-        })
+
+            shaping_enabled: Self::read_bool("SHAPING_ENABLED", SHAPING_ENABLED_DEFAULT),
+            shaping_max_queue: Self::read_u128("SHAPING_MAX_QUEUE", SHAPING_MAX_QUEUE_DEFAULT),
+            shaping_max_wait_ms: Self::read_u128(
+                "SHAPING_MAX_WAIT_MS",
+                SHAPING_MAX_WAIT_MS_DEFAULT,
+            ),
+
+            default_cost: Self::read_u128("DEFAULT_REQUEST_COST", REQUEST_COST_DEFAULT),
+            route_costs: Self::parse_route_costs(&Self::read_string("ROUTE_COSTS", ""))?,
+
+            global_max_in_flight: Self::read_u128(
+                "GLOBAL_MAX_IN_FLIGHT",
+                GLOBAL_MAX_IN_FLIGHT_DEFAULT,
+            ),
+            route_max_in_flight: Self::read_u128(
+                "ROUTE_MAX_IN_FLIGHT",
+                ROUTE_MAX_IN_FLIGHT_DEFAULT,
+            ),
+            ip_max_in_flight: Self::read_u128("IP_MAX_IN_FLIGHT", IP_MAX_IN_FLIGHT_DEFAULT),
+
+            adaptive_concurrency: Self::read_string(
+                "ADAPTIVE_CONCURRENCY",
+                ADAPTIVE_CONCURRENCY_DEFAULT,
+            ),
+            adaptive_initial_limit: Self::read_u128(
+                "ADAPTIVE_INITIAL_LIMIT",
+                ADAPTIVE_INITIAL_LIMIT_DEFAULT,
+            ),
+            adaptive_min_limit: Self::read_u128("ADAPTIVE_MIN_LIMIT", ADAPTIVE_MIN_LIMIT_DEFAULT),
+            adaptive_max_limit: Self::read_u128("ADAPTIVE_MAX_LIMIT", ADAPTIVE_MAX_LIMIT_DEFAULT),
+            adaptive_latency_threshold_ms: Self::read_u128(
+                "ADAPTIVE_LATENCY_THRESHOLD_MS",
+                ADAPTIVE_LATENCY_THRESHOLD_MS_DEFAULT,
+            ),
+
+            bucket_store: Self::read_string("BUCKET_STORE", BUCKET_STORE_DEFAULT),
+            bucket_store_shards: Self::read_u128(
+                "BUCKET_STORE_SHARDS",
+                BUCKET_STORE_SHARDS_DEFAULT,
+            ),
+
+            redis_url: Self::read_string("REDIS_URL", REDIS_URL_DEFAULT),
+            redis_pool_size: Self::read_u128("REDIS_POOL_SIZE", REDIS_POOL_SIZE_DEFAULT),
+            redis_timeout_ms: Self::read_u128("REDIS_TIMEOUT_MS", REDIS_TIMEOUT_MS_DEFAULT),
+            redis_fail_open: Self::read_bool("REDIS_FAIL_OPEN", REDIS_FAIL_OPEN_DEFAULT),
+
+            snapshot_dir: Self::read_string("SNAPSHOT_DIR", SNAPSHOT_DIR_DEFAULT),
+            snapshot_max_age_secs: Self::read_u128(
+                "SNAPSHOT_MAX_AGE_SECS",
+                SNAPSHOT_MAX_AGE_SECS_DEFAULT,
+            ),
+
+            route_max_keys: Self::read_u128("ROUTE_MAX_KEYS", ROUTE_MAX_KEYS_DEFAULT),
+            ip_max_keys: Self::read_u128("IP_MAX_KEYS", IP_MAX_KEYS_DEFAULT),
+            key_eviction_policy: Self::read_string(
+                "KEY_EVICTION_POLICY",
+                KEY_EVICTION_POLICY_DEFAULT,
+            ),
+
+            global_dry_run: Self::read_bool("GLOBAL_DRY_RUN", DRY_RUN_DEFAULT),
+            route_dry_run: Self::read_bool("ROUTE_DRY_RUN", DRY_RUN_DEFAULT),
+            ip_dry_run: Self::read_bool("IP_DRY_RUN", DRY_RUN_DEFAULT),
+
+            shadow_algorithm: Self::read_string("SHADOW_RATE_LIMITER_ALGO", SHADOW_ALGO_DEFAULT),
+            shadow_global_capacity: Self::read_u128(
+                "SHADOW_GLOBAL_CAPACITY",
+                SHADOW_CAPACITY_DEFAULT,
+            ),
+            shadow_global_refill_rate: Self::read_optional_u128("SHADOW_GLOBAL_REFILL_RATE"),
+            shadow_route_capacity: Self::read_u128(
+                "SHADOW_ROUTE_CAPACITY",
+                SHADOW_CAPACITY_DEFAULT,
+            ),
+            shadow_route_refill_rate: Self::read_optional_u128("SHADOW_ROUTE_REFILL_RATE"),
+            shadow_ip_capacity: Self::read_u128("SHADOW_IP_CAPACITY", SHADOW_CAPACITY_DEFAULT),
+            shadow_ip_refill_rate: Self::read_optional_u128("SHADOW_IP_REFILL_RATE"),
+            shadow_global_window: Self::read_secs(
+                "SHADOW_GLOBAL_WINDOW_SECS",
+                WINDOW_SECS_DEFAULT,
+            )?,
+            shadow_route_window: Self::read_secs("SHADOW_ROUTE_WINDOW_SECS", WINDOW_SECS_DEFAULT)?,
+            shadow_ip_window: Self::read_secs("SHADOW_IP_WINDOW_SECS", WINDOW_SECS_DEFAULT)?,
+
+            ip_overrides: Self::parse_overrides(
+                "IP_OVERRIDES",
+                &Self::read_string("IP_OVERRIDES", IP_OVERRIDES_DEFAULT),
+                &algorithm,
+            )?,
+            route_overrides: Self::parse_overrides(
+                "ROUTE_OVERRIDES",
+                &Self::read_string("ROUTE_OVERRIDES", ROUTE_OVERRIDES_DEFAULT),
+                &algorithm,
+            )?,
+
+            quota_limit: Self::read_u128("QUOTA_LIMIT", QUOTA_LIMIT_DEFAULT),
+            quota_period: Self::read_string("QUOTA_PERIOD", QUOTA_PERIOD_DEFAULT),
+            quota_timezone: Self::read_timezone("QUOTA_TIMEZONE", QUOTA_TIMEZONE_DEFAULT)?,
+
+            refund_on_upstream_error: Self::read_bool(
+                "REFUND_ON_UPSTREAM_ERROR",
+                REFUND_ON_UPSTREAM_ERROR_DEFAULT,
+            ),
+            global_lock_free: Self::read_bool("GLOBAL_LOCK_FREE", GLOBAL_LOCK_FREE_DEFAULT),
+
+            route_key_template: Self::read_template(
+                "ROUTE_KEY_TEMPLATE",
+                ROUTE_KEY_TEMPLATE_DEFAULT,
+            )?,
+            ip_key_template: Self::read_template("IP_KEY_TEMPLATE", IP_KEY_TEMPLATE_DEFAULT)?,
+            top_keys_capacity: Self::read_u128("TOP_KEYS_CAPACITY", TOP_KEYS_CAPACITY_DEFAULT),
+            top_keys_sample_every: Self::read_u128(
+                "TOP_KEYS_SAMPLE_EVERY",
+                TOP_KEYS_SAMPLE_EVERY_DEFAULT,
+            ),
+        };
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    // a value that would break a limiter fails at startup, not on the first request
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        // an unknown store fails instead of falling back to dashmap
+        match self.bucket_store.as_str() {
+            "dashmap" | "mutex" | "sharded" => {}
+            "redis" => {
+                let algorithm = self.algorithm_type()?;
+                if !RedisStore::<()>::supports(&algorithm) {
+                    return Err(ConfigError::UnsupportedByStore(algorithm));
+                }
+                if !RedisStore::<()>::is_valid_url(&self.redis_url) {
+                    return Err(ConfigError::InvalidValue("REDIS_URL"));
+                }
+            }
+            _ => return Err(ConfigError::InvalidValue("BUCKET_STORE")),
+        }
+
+        self.global_limit()?;
+        self.route_limit()?;
+        self.ip_limit()?;
+        self.shadow_global_limit()?;
+        self.shadow_route_limit()?;
+        self.shadow_ip_limit()?;
+
+        // the adaptive limit is clamped into [min, max]
+        if self.adaptive_min_limit > self.adaptive_max_limit {
+            return Err(ConfigError::InvalidValue("ADAPTIVE_MIN_LIMIT"));
+        }
+        Ok(())
+    }
+
+    pub fn algorithm_type(&self) -> Result<AlgorithmType, ConfigError> {
+        parse_algorithm(&self.algorithm).ok_or(ConfigError::InvalidValue("RATE_LIMITER_ALGO"))
+    }
+
+    // the lock-free global store only runs GCRA, whatever RATE_LIMITER_ALGO says
+    pub fn global_limit(&self) -> Result<AlgorithmConfig, ConfigError> {
+        let algorithm = match self.global_lock_free && self.bucket_store != "redis" {
+            true => AlgorithmType::Gcra,
+            false => self.algorithm_type()?,
+        };
+        Self::limit(
+            "GLOBAL",
+            algorithm,
+            self.global_capacity,
+            self.global_refill_rate,
+            GLOBAL_REFILL_RATE_DEFAULT,
+            self.global_window,
+        )
+    }
+
+    pub fn route_limit(&self) -> Result<AlgorithmConfig, ConfigError> {
+        Self::limit(
+            "ROUTE",
+            self.algorithm_type()?,
+            self.route_capacity,
+            self.route_refill_rate,
+            ROUTE_REFILL_RATE_DEFAULT,
+            self.route_window,
+        )
+    }
+
+    pub fn ip_limit(&self) -> Result<AlgorithmConfig, ConfigError> {
+        Self::limit(
+            "IP",
+            self.algorithm_type()?,
+            self.ip_capacity,
+            self.ip_refill_rate,
+            IP_REFILL_RATE_DEFAULT,
+            self.ip_window,
+        )
+    }
+
+    // None when the tier has no shadow policy
+    pub fn shadow_global_limit(&self) -> Result<Option<AlgorithmConfig>, ConfigError> {
+        self.shadow_limit(
+            "SHADOW_GLOBAL",
+            self.shadow_global_capacity,
+            self.shadow_global_refill_rate,
+            self.shadow_global_window,
+        )
+    }
+
+    pub fn shadow_route_limit(&self) -> Result<Option<AlgorithmConfig>, ConfigError> {
+        self.shadow_limit(
+            "SHADOW_ROUTE",
+            self.shadow_route_capacity,
+            self.shadow_route_refill_rate,
+            self.shadow_route_window,
+        )
+    }
+
+    pub fn shadow_ip_limit(&self) -> Result<Option<AlgorithmConfig>, ConfigError> {
+        self.shadow_limit(
+            "SHADOW_IP",
+            self.shadow_ip_capacity,
+            self.shadow_ip_refill_rate,
+            self.shadow_ip_window,
+        )
+    }
+
+    fn shadow_limit(
+        &self,
+        tier: &'static str,
+        capacity: u128,
+        refill_rate: Option<u128>,
+        window: Duration,
+    ) -> Result<Option<AlgorithmConfig>, ConfigError> {
+        let algorithm = match self.shadow_algorithm.as_str() {
+            "" => self.algorithm_type()?,
+            name => parse_algorithm(name)
+                .ok_or(ConfigError::InvalidValue("SHADOW_RATE_LIMITER_ALGO"))?,
+        };
+        match capacity {
+            0 => Ok(None),
+            capacity => Self::limit(
+                tier,
+                algorithm,
+                capacity,
+                refill_rate,
+                SHADOW_REFILL_RATE_DEFAULT,
+                window,
+            )
+            .map(Some),
+        }
+    }
+
+    // <tier>_CAPACITY is the burst or the requests per window, <tier>_REFILL_RATE the
+    // tokens a second and <tier>_WINDOW_SECS the window, whichever the algorithm uses
+    fn limit(
+        tier: &'static str,
+        algorithm: AlgorithmType,
+        capacity: u128,
+        refill_rate: Option<u128>,
+        default_rate: u128,
+        window: Duration,
+    ) -> Result<AlgorithmConfig, ConfigError> {
+        let secs = |secs: u128| Duration::from_secs(secs.min(u64::MAX as u128) as u64);
+
+        let mut builder = AlgorithmConfigBuilder::new(algorithm.clone()).with_capacity(capacity);
+        if !window.is_zero() {
+            builder = builder.with_window(window);
+        }
+        let builder = match (algorithm, refill_rate) {
+            (AlgorithmType::TokenBucket | AlgorithmType::Gcra, rate) => {
+                builder.with_rate(rate.unwrap_or(default_rate))
+            }
+            (_, rate) if window.is_zero() => {
+                builder.with_window(secs(rate.unwrap_or(default_rate)))
+            }
+            // a rate next to a window fails the build instead of being dropped
+            (_, Some(rate)) => builder.with_rate(rate),
+            (_, None) => builder,
+        };
+        builder
+            .build()
+            .map_err(|err| ConfigError::InvalidLimit(tier, err))
+    }
+
+    // longest matching prefix wins, a method specific entry beats an any-method one
+    pub fn route_cost(&self, method: &str, path: &str) -> u128 {
+        self.route_costs
+            .iter()
+            .filter(|rc| {
+                rc.method
+                    .as_deref()
+                    .is_none_or(|m| m.eq_ignore_ascii_case(method))
+            })
+            .filter(|rc| Self::prefix_matches(&rc.prefix, path))
+            .max_by_key(|rc| (rc.prefix.len(), rc.method.is_some()))
+            .map(|rc| rc.cost)
+            .unwrap_or(self.default_cost)
+    }
+
+    // match on whole path segments so /api/search doesn't match /api/searchx
+    fn prefix_matches(prefix: &str, path: &str) -> bool {
+        match path.strip_prefix(prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'),
+            None => false,
+        }
+    }
+
+    fn parse_route_costs(raw: &str) -> Result<Vec<RouteCost>, ConfigError> {
+        raw.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (route, cost) = entry
+                    .rsplit_once('=')
+                    .ok_or(ConfigError::InvalidValue("ROUTE_COSTS"))?;
+
+                let cost = cost
+                    .trim()
+                    .parse::<u128>()
+                    .map_err(|_| ConfigError::InvalidNumber("ROUTE_COSTS"))?;
+
+                let (method, prefix) = match route.trim().split_once(char::is_whitespace) {
+                    Some((method, prefix)) => (Some(method.to_uppercase()), prefix.trim()),
+                    None => (None, route.trim()),
+                };
+
+                if !prefix.starts_with('/') {
+                    return Err(ConfigError::InvalidValue("ROUTE_COSTS"));
+                }
+
+                Ok(RouteCost {
+                    method,
+                    prefix: prefix.to_string(),
+                    cost,
+                })
+            })
+            .collect()
+    }
+    // "<key>=<allow|deny|capacity:refill_rate>" entries, comma separated
+    fn parse_overrides<T>(
+        name: &'static str,
+        raw: &str,
+        algorithm: &AlgorithmType,
+    ) -> Result<Vec<(T, KeyPolicy)>, ConfigError>
+    where
+        T: std::str::FromStr,
+    {
+        raw.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (key, policy) = entry
+                    .rsplit_once('=')
+                    .ok_or(ConfigError::InvalidValue(name))?;
+
+                let key = key
+                    .trim()
+                    .parse::<T>()
+                    .map_err(|_| ConfigError::InvalidValue(name))?;
+                let policy =
+                    KeyPolicy::parse(policy, algorithm.clone()).map_err(|err| match err {
+                        OverrideError::InvalidLimit(_, err) => ConfigError::InvalidLimit(name, err),
+                        _ => ConfigError::InvalidValue(name),
+                    })?;
+
+                Ok((key, policy))
+            })
+            .collect()
     }
+
+    // seconds, fractions allowed, e.g. 0.5 for a 500ms window
+    fn read_secs(key: &'static str, default: f64) -> Result<Duration, ConfigError> {
+        let secs = match env::var(key) {
+            Ok(raw) => raw
+                .trim()
+                .parse()
+                .map_err(|_| ConfigError::InvalidNumber(key))?,
+            Err(_) => default,
+        };
+        Duration::try_from_secs_f64(secs).map_err(|_| ConfigError::InvalidNumber(key))
+    }
+
+    fn read_optional_u128(key: &str) -> Option<u128> {
+        env::var(key).ok().and_then(|v| v.parse::<u128>().ok())
+    }
+
     fn read_u128(key: &str, default: u128) -> u128 {
         env::var(key)
             .ok()
@@ -52,7 +580,336 @@ impl GatewayConfig {
             .unwrap_or(default)
     }
 
+    fn read_bool(key: &str, default: bool) -> bool {
+        env::var(key)
+            .ok()
+            .and_then(|v| v.parse::<bool>().ok())
+            .unwrap_or(default)
+    }
+
     fn read_string(key: &str, default: &str) -> String {
         env::var(key).unwrap_or_else(|_| default.to_string())
     }
+
+    fn read_template(key: &'static str, default: &str) -> Result<KeyTemplate, ConfigError> {
+        Self::read_string(key, default)
+            .parse()
+            .map_err(|_| ConfigError::InvalidValue(key))
+    }
+
+    // IANA name, e.g. "Asia/Kolkata"
+    fn read_timezone(key: &'static str, default: &str) -> Result<Tz, ConfigError> {
+        Self::read_string(key, default)
+            .parse()
+            .map_err(|_| ConfigError::InvalidValue(key))
+    }
+}
+
+pub fn parse_algorithm(name: &str) -> Option<AlgorithmType> {
+    match name {
+        "token_bucket" => Some(AlgorithmType::TokenBucket),
+        "sliding_log" => Some(AlgorithmType::SlidingLog),
+        "sliding_counter" => Some(AlgorithmType::SlidingCounter),
+        "gcra" => Some(AlgorithmType::Gcra),
+        "fixed_window" => Some(AlgorithmType::FixedWindow),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    pub fn config_with(route_costs: &str) -> GatewayConfig {
+        GatewayConfig {
+            global_capacity: 1,
+            global_refill_rate: Some(1),
+            ip_capacity: 1,
+            ip_refill_rate: Some(1),
+            route_capacity: 1,
+            route_refill_rate: Some(1),
+            global_window: Duration::ZERO,
+            ip_window: Duration::ZERO,
+            route_window: Duration::ZERO,
+            upstream_base_url: String::new(),
+            algorithm: RATE_LIMITER_ALGO_DEFAULT.to_string(),
+            shaping_enabled: false,
+            shaping_max_queue: 0,
+            shaping_max_wait_ms: 0,
+            default_cost: 1,
+            route_costs: GatewayConfig::parse_route_costs(route_costs).unwrap(),
+            global_max_in_flight: 0,
+            route_max_in_flight: 0,
+            ip_max_in_flight: 0,
+            adaptive_concurrency: ADAPTIVE_CONCURRENCY_DEFAULT.to_string(),
+            adaptive_initial_limit: 0,
+            adaptive_min_limit: 0,
+            adaptive_max_limit: 0,
+            adaptive_latency_threshold_ms: 0,
+            bucket_store: BUCKET_STORE_DEFAULT.to_string(),
+            bucket_store_shards: 0,
+            redis_url: REDIS_URL_DEFAULT.to_string(),
+            redis_pool_size: 0,
+            redis_timeout_ms: 0,
+            redis_fail_open: REDIS_FAIL_OPEN_DEFAULT,
+            snapshot_dir: SNAPSHOT_DIR_DEFAULT.to_string(),
+            snapshot_max_age_secs: 0,
+            route_max_keys: 0,
+            ip_max_keys: 0,
+            key_eviction_policy: KEY_EVICTION_POLICY_DEFAULT.to_string(),
+            global_dry_run: false,
+            route_dry_run: false,
+            ip_dry_run: false,
+            shadow_algorithm: SHADOW_ALGO_DEFAULT.to_string(),
+            shadow_global_capacity: 0,
+            shadow_global_refill_rate: None,
+            shadow_route_capacity: 0,
+            shadow_route_refill_rate: None,
+            shadow_ip_capacity: 0,
+            shadow_ip_refill_rate: None,
+            shadow_global_window: Duration::ZERO,
+            shadow_route_window: Duration::ZERO,
+            shadow_ip_window: Duration::ZERO,
+            ip_overrides: Vec::new(),
+            route_overrides: Vec::new(),
+            quota_limit: 0,
+            quota_period: QUOTA_PERIOD_DEFAULT.to_string(),
+            quota_timezone: Tz::UTC,
+            refund_on_upstream_error: false,
+            global_lock_free: false,
+            route_key_template: ROUTE_KEY_TEMPLATE_DEFAULT.parse().unwrap(),
+            ip_key_template: IP_KEY_TEMPLATE_DEFAULT.parse().unwrap(),
+            top_keys_capacity: 0,
+            top_keys_sample_every: 1,
+        }
+    }
+
+    #[test]
+    pub fn parse_route_costs() {
+        let costs =
+            GatewayConfig::parse_route_costs("post /api/search=5, /api/export = 10").unwrap();
+        assert_eq!(
+            costs,
+            vec![
+                RouteCost {
+                    method: Some("POST".to_string()),
+                    prefix: "/api/search".to_string(),
+                    cost: 5,
+                },
+                RouteCost {
+                    method: None,
+                    prefix: "/api/export".to_string(),
+                    cost: 10,
+                },
+            ]
+        );
+
+        assert!(GatewayConfig::parse_route_costs("/api/search").is_err());
+        assert!(GatewayConfig::parse_route_costs("/api/search=x").is_err());
+        assert!(GatewayConfig::parse_route_costs("GET api=1").is_err());
+    }
+
+    #[test]
+    pub fn longest_prefix_wins() {
+        let config = config_with("/api=2,/api/search=5,GET /api/search=3,/api/search/bulk=20");
+
+        assert_eq!(config.route_cost("POST", "/api/search"), 5);
+        assert_eq!(config.route_cost("GET", "/api/search/items"), 3);
+        assert_eq!(config.route_cost("POST", "/api/search/bulk/1"), 20);
+        assert_eq!(config.route_cost("GET", "/api/searchx"), 2);
+        assert_eq!(config.route_cost("GET", "/health"), 1);
+    }
+
+    #[test]
+    pub fn parse_overrides() {
+        let bucket = AlgorithmType::TokenBucket;
+        let overrides: Vec<(IpRange, KeyPolicy)> = GatewayConfig::parse_overrides(
+            "IP_OVERRIDES",
+            "10.0.0.0/8=allow, 203.0.113.7=deny,2001:db8::/32=100:10",
+            &bucket,
+        )
+        .unwrap();
+
+        assert_eq!(overrides.len(), 3);
+        assert_eq!(
+            overrides[0],
+            ("10.0.0.0/8".parse().unwrap(), KeyPolicy::Allow)
+        );
+        assert_eq!(
+            overrides[2].1,
+            KeyPolicy::Limit(AlgorithmConfig::TokenBucket {
+                burst: 100,
+                rate: 10
+            })
+        );
+
+        let parse = |raw, algorithm| {
+            GatewayConfig::parse_overrides::<IpRange>("IP_OVERRIDES", raw, algorithm)
+        };
+        assert!(parse("10.0.0.0/8", &bucket).is_err());
+        assert!(parse("example.com=allow", &bucket).is_err());
+        assert!(
+            GatewayConfig::parse_overrides::<String>("ROUTE_OVERRIDES", "export=5", &bucket)
+                .is_err()
+        );
+        // a limit the tier's algorithm can't run fails at startup, not in the limiter
+        assert!(matches!(
+            parse("10.0.0.0/8=5:0", &AlgorithmType::Gcra),
+            Err(ConfigError::InvalidLimit(
+                "IP_OVERRIDES",
+                AlgorithmConfigError::ZeroRate
+            ))
+        ));
+        assert!(matches!(
+            parse("10.0.0.0/8=5:0", &AlgorithmType::FixedWindow),
+            Err(ConfigError::InvalidLimit(
+                "IP_OVERRIDES",
+                AlgorithmConfigError::ZeroWindow
+            ))
+        ));
+    }
+
+    #[test]
+    pub fn limits_fit_their_algorithm() {
+        let mut config = config_with("");
+        config.route_capacity = 100;
+        config.route_refill_rate = Some(10);
+        assert_eq!(
+            config.route_limit().unwrap(),
+            AlgorithmConfig::TokenBucket {
+                burst: 100,
+                rate: 10
+            }
+        );
+
+        // window algorithms read *_WINDOW_SECS, the refill rate only when no window is set
+        config.algorithm = "fixed_window".to_string();
+        assert_eq!(
+            config.route_limit().unwrap(),
+            AlgorithmConfig::FixedWindow {
+                limit: 100,
+                window: Duration::from_secs(10)
+            }
+        );
+        config.route_window = Duration::from_secs(60);
+        assert!(matches!(
+            config.route_limit(),
+            Err(ConfigError::InvalidLimit(
+                "ROUTE",
+                AlgorithmConfigError::UnexpectedRate
+            ))
+        ));
+        config.route_refill_rate = None;
+        assert_eq!(
+            config.route_limit().unwrap(),
+            AlgorithmConfig::FixedWindow {
+                limit: 100,
+                window: Duration::from_secs(60)
+            }
+        );
+        config.route_window = Duration::from_millis(500);
+        assert_eq!(
+            config.route_limit().unwrap(),
+            AlgorithmConfig::FixedWindow {
+                limit: 100,
+                window: Duration::from_millis(500)
+            }
+        );
+
+        config.global_lock_free = true;
+        config.global_refill_rate = Some(0);
+        assert!(matches!(
+            config.global_limit(),
+            Err(ConfigError::InvalidLimit(
+                "GLOBAL",
+                AlgorithmConfigError::ZeroRate
+            ))
+        ));
+
+        config.algorithm = "token_bucket".to_string();
+        assert!(matches!(
+            config.route_limit(),
+            Err(ConfigError::InvalidLimit(
+                "ROUTE",
+                AlgorithmConfigError::UnexpectedWindow
+            ))
+        ));
+        assert_eq!(config.shadow_route_limit().unwrap(), None);
+        config.shadow_route_capacity = 5;
+        config.shadow_route_refill_rate = Some(0);
+        assert!(matches!(
+            config.shadow_route_limit(),
+            Err(ConfigError::InvalidLimit(
+                "SHADOW_ROUTE",
+                AlgorithmConfigError::ZeroRate
+            ))
+        ));
+    }
+
+    #[test]
+    pub fn stores_and_algorithms_must_be_known() {
+        let mut config = config_with("");
+        config.algorithm = "leaky_bucket".to_string();
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidValue("RATE_LIMITER_ALGO"))
+        ));
+
+        config.algorithm = "token_bucket".to_string();
+        config.shadow_algorithm = "leaky_bucket".to_string();
+        config.shadow_ip_capacity = 5;
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidValue("SHADOW_RATE_LIMITER_ALGO"))
+        ));
+
+        config.shadow_algorithm = String::new();
+        config.bucket_store = "memcached".to_string();
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidValue("BUCKET_STORE"))
+        ));
+
+        // redis only runs the algorithms it has a script for
+        config.bucket_store = "redis".to_string();
+        assert!(config.validate().is_ok());
+        config.algorithm = "gcra".to_string();
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::UnsupportedByStore(AlgorithmType::Gcra))
+        ));
+
+        config.algorithm = "sliding_log".to_string();
+        config.redis_url = "localhost:6379".to_string();
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidValue("REDIS_URL"))
+        ));
+    }
+
+    #[test]
+    pub fn adaptive_range_must_not_be_empty() {
+        let mut config = config_with("");
+        config.adaptive_min_limit = 5;
+        config.adaptive_max_limit = 50;
+        assert!(config.validate().is_ok());
+
+        config.adaptive_max_limit = 4;
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidValue("ADAPTIVE_MIN_LIMIT"))
+        ));
+    }
+
+    #[test]
+    pub fn timezones_parse_at_startup() {
+        assert!(matches!(
+            GatewayConfig::read_timezone("QUOTA_TIMEZONE_TEST_UNSET", "Asia/Kolkata"),
+            Ok(chrono_tz::Asia::Kolkata)
+        ));
+        assert!(matches!(
+            GatewayConfig::read_timezone("QUOTA_TIMEZONE_TEST_UNSET", "Mars/Olympus"),
+            Err(ConfigError::InvalidValue("QUOTA_TIMEZONE_TEST_UNSET"))
+        ));
+    }
 }
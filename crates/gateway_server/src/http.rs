@@ -1 +1,4 @@
 pub mod errors;
+pub mod permit_body;
+pub mod status;
+pub mod top_keys;
@@ -11,6 +11,8 @@ struct RateLimitBody {
 }
 
 pub struct RateLimitHttpError {
+    pub status: StatusCode,
+    pub error: &'static str,
     pub retry_after_ms: u64,
     pub ratelimit_limit: u64,
     pub ratelimit_remaining: u64,
@@ -20,10 +22,10 @@ pub struct RateLimitHttpError {
 impl IntoResponse for RateLimitHttpError {
     fn into_response(self) -> axum::response::Response {
         //convert the millis to seconds using ceiling
-        let seconds = (self.retry_after_ms + 999) / 1000;
+        let seconds = self.retry_after_ms.div_ceil(1000);
 
         let body = RateLimitBody {
-            error: "rate_limited",
+            error: self.error,
             retry_after_ms: self.retry_after_ms,
             limit: self.ratelimit_limit,
             remaining: self.ratelimit_remaining,
@@ -32,9 +34,44 @@ impl IntoResponse for RateLimitHttpError {
 
         let json = serde_json::to_string(&body).unwrap();
 
+        let mut builder = Response::builder().status(self.status);
+        // only a 429 clears up by waiting, 403 and 413 get no Retry-After
+        if self.status == StatusCode::TOO_MANY_REQUESTS {
+            builder = builder.header("Retry-After", seconds.to_string());
+        }
+        builder
+            .header("Content-Type", "application/json")
+            .body(Body::from(json))
+            .unwrap()
+    }
+}
+
+#[derive(Serialize)]
+struct ConcurrencyBody {
+    error: &'static str,
+    limit: u64,
+    in_flight: u64,
+}
+
+pub struct ConcurrencyHttpError {
+    pub status: StatusCode,
+    pub error: &'static str,
+    pub limit: u64,
+    pub in_flight: u64,
+}
+
+impl IntoResponse for ConcurrencyHttpError {
+    fn into_response(self) -> axum::response::Response {
+        let body = ConcurrencyBody {
+            error: self.error,
+            limit: self.limit,
+            in_flight: self.in_flight,
+        };
+
+        let json = serde_json::to_string(&body).unwrap();
+
         Response::builder()
-            .status(StatusCode::TOO_MANY_REQUESTS)
-            .header("Retry-After", seconds.to_string())
+            .status(self.status)
             .header("Content-Type", "application/json")
             .body(Body::from(json))
             .unwrap()
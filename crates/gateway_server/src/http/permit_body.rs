@@ -0,0 +1,94 @@
+use axum::body::{Body, Bytes};
+use http_body::{Frame, SizeHint};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+// Holds the concurrency permits until the response body has been fully streamed,
+// or until it is dropped when the client goes away first
+pub struct PermitBody<P> {
+    inner: Body,
+    permits: Option<P>,
+}
+
+impl<P> PermitBody<P> {
+    pub fn new(inner: Body, permits: P) -> Self {
+        Self {
+            inner,
+            permits: Some(permits),
+        }
+    }
+}
+
+impl<P> http_body::Body for PermitBody<P>
+where
+    P: Unpin,
+{
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = Pin::new(&mut self.inner).poll_frame(cx);
+        // the last frame is out, don't wait for the server to drop the body
+        if let Poll::Ready(None | Some(Err(_))) = frame {
+            self.permits.take();
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use gateway_core::concurrency::ConcurrencyLimiter;
+    use http_body::Body as _;
+    use std::task::Waker;
+
+    #[test]
+    pub fn permits_are_released_when_the_body_ends() {
+        let limiter = ConcurrencyLimiter::new(1);
+        let permit = limiter.try_acquire(()).unwrap();
+        let mut body = PermitBody::new(Body::from("streamed"), permit);
+        let mut cx = Context::from_waker(Waker::noop());
+
+        // still streaming, the slot stays taken
+        let frame = Pin::new(&mut body).poll_frame(&mut cx);
+        assert!(matches!(frame, Poll::Ready(Some(Ok(_)))));
+        assert_eq!(limiter.in_flight(&()), 1);
+        assert!(limiter.try_acquire(()).is_err());
+
+        // the end of the stream frees it while the body is still alive
+        assert!(matches!(
+            Pin::new(&mut body).poll_frame(&mut cx),
+            Poll::Ready(None)
+        ));
+        assert_eq!(limiter.in_flight(&()), 0);
+        drop(body);
+        assert_eq!(limiter.in_flight(&()), 0);
+    }
+
+    #[test]
+    pub fn permits_are_released_when_the_body_is_dropped_early() {
+        let limiter = ConcurrencyLimiter::new(1);
+        let permit = limiter.try_acquire(()).unwrap();
+        let body = PermitBody::new(Body::from("never read"), permit);
+        assert_eq!(limiter.in_flight(&()), 1);
+
+        // the client hung up before the body was sent
+        drop(body);
+        assert_eq!(limiter.in_flight(&()), 0);
+        assert!(limiter.try_acquire(()).is_ok());
+    }
+}
@@ -0,0 +1,28 @@
+use gateway_core::rate_limiter::algorithm::BucketState;
+use serde::Serialize;
+
+#[derive(Serialize)]
+pub struct TierStatus {
+    pub limit: u64,
+    pub remaining: u64,
+    pub reset: u64,
+}
+
+impl From<BucketState> for TierStatus {
+    fn from(state: BucketState) -> Self {
+        Self {
+            limit: state.limit as u64,
+            remaining: state.remaining as u64,
+            reset: state.reset_after.as_secs(),
+        }
+    }
+}
+
+// body of /ratelimit/status, a tier is null when it doesn't apply
+#[derive(Serialize)]
+pub struct RateLimitStatus {
+    pub global: TierStatus,
+    pub route: Option<TierStatus>,
+    pub ip: TierStatus,
+    pub quota: Option<TierStatus>,
+}
@@ -0,0 +1,47 @@
+use gateway_core::rate_limiter::heavy_hitters::{Decision, HeavyHitter, HeavyHitters};
+use serde::Serialize;
+use std::hash::{BuildHasher, RandomState};
+
+// body of /debug/top-keys, approximate counts since the counters last decayed
+#[derive(Serialize)]
+pub struct TopKeys {
+    pub tier: &'static str,
+    pub allowed: Vec<HeavyHitter<String>>,
+    pub denied: Vec<HeavyHitter<String>>,
+}
+
+impl TopKeys {
+    // keys read from headers or the query can be api keys, with a hasher only their
+    // hash leaves the process. Addresses and routes are reported as they are
+    pub fn new(
+        tier: &'static str,
+        hitters: &HeavyHitters<String>,
+        n: usize,
+        hasher: Option<&RandomState>,
+    ) -> Self {
+        let top = |decision| {
+            hitters
+                .top(decision, n)
+                .into_iter()
+                .map(|hitter| match hasher {
+                    Some(hasher) => HeavyHitter {
+                        key: redact_key(hasher, &hitter.key),
+                        ..hitter
+                    },
+                    None => hitter,
+                })
+                .collect()
+        };
+
+        Self {
+            tier,
+            allowed: top(Decision::Allowed),
+            denied: top(Decision::Denied),
+        }
+    }
+}
+
+// stable for the life of the process, so the same key keeps its name between calls
+pub fn redact_key(hasher: &RandomState, key: &str) -> String {
+    format!("{:016x}", hasher.hash_one(key))
+}
@@ -6,25 +6,50 @@ pub mod metrics;
 pub mod middleware;
 
 use crate::{
-    config::gateway_config::GatewayConfig, metrics::gateway_metrics::GatewayMetrices,
-    middleware::rate_limit::rate_limit_middleware,
+    config::gateway_config::GatewayConfig,
+    http::{
+        status::RateLimitStatus,
+        top_keys::{TopKeys, redact_key},
+    },
+    metrics::gateway_metrics::GatewayMetrices,
+    middleware::rate_limit::{TIER_NAMES, rate_limit_middleware, tier_keys},
 };
 use axum::{
-    Router,
+    Json, Router,
     body::{Body, to_bytes},
-    extract::State,
+    extract::{ConnectInfo, Query, State},
     http::{Request, Response, StatusCode},
     middleware::from_fn_with_state,
     response::IntoResponse,
     routing::{any, get},
 };
-use gateway_core::rate_limiter::{RateLimiter, TokenBucket, rate_limiter::AlgorithmType};
+use gateway_core::{
+    clock::{Clock, SystemClock},
+    concurrency::{
+        AdaptiveAlgorithmType, AdaptiveLimiter, ConcurrencyLimiter, limit_algorithm::Outcome,
+    },
+    rate_limiter::{
+        AlgorithmConfig, RateLimiter, TokenBucket,
+        bucket_store::{BucketStore, StoreType},
+        heavy_hitters::{Decision, HeavyHitters},
+        key_limit::{EvictionPolicy, KeyLimit},
+        overrides::{IpOverrides, KeyOverrides, ResolvedKey, ResolvedOverrides},
+        quota::{QuotaLimiter, QuotaPeriod, QuotaSnapshot},
+        rate_limiter::AlgorithmType,
+        redis_store::{FailMode, RedisKey, RedisStore},
+        shaper::LeakyBucketShaper,
+        snapshot::LimiterSnapshot,
+    },
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
 use reqwest::Client;
 use std::{
+    hash::{Hash, RandomState},
     net::{IpAddr, SocketAddr},
+    path::Path,
     sync::{Arc, atomic::Ordering},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use tracing::info;
@@ -37,9 +62,33 @@ pub struct AppState {
     client: Client,
     config: GatewayConfig,
     global_limiter: RateLimiter<()>,
-    ip_limiter: RateLimiter<IpAddr>,
-    route_limiter: RateLimiter<String>,
+    // keyed by IP_KEY_TEMPLATE, the client address unless configured otherwise
+    ip_limiter: RateLimiter<ResolvedKey<String>>,
+    route_limiter: RateLimiter<ResolvedKey<String>>,
+    // looked up by client address and route name when the tier keys are rendered
+    ip_overrides: Arc<IpOverrides>,
+    route_overrides: Arc<KeyOverrides<String>>,
+    global_shaper: LeakyBucketShaper<()>,
+    ip_shaper: LeakyBucketShaper<String>,
+    route_shaper: LeakyBucketShaper<String>,
+    global_concurrency: ConcurrencyLimiter<()>,
+    route_concurrency: ConcurrencyLimiter<String>,
+    ip_concurrency: ConcurrencyLimiter<IpAddr>,
+    // candidate policies evaluated next to the enforced ones, never enforced
+    global_shadow: Option<RateLimiter<()>>,
+    route_shadow: Option<RateLimiter<String>>,
+    ip_shadow: Option<RateLimiter<String>>,
+    // daily/monthly request quota per ip, None when QUOTA_LIMIT is 0
+    quota_limiter: Option<QuotaLimiter<IpAddr>>,
+    // most active keys per tier in TIER_NAMES order, None when TOP_KEYS_CAPACITY is 0
+    top_keys: Option<[HeavyHitters<String>; 3]>,
+    // hashes header and query keys in /debug/top-keys so they never show in the clear
+    top_keys_hasher: RandomState,
+    // keyed by upstream base url, None when adaptive concurrency is off
+    upstream_limiter: Option<AdaptiveLimiter<String>>,
     metrics: Arc<GatewayMetrices>,
+    // every limiter decision reads time from here so tests can swap in a MockClock
+    clock: Arc<dyn Clock>,
 }
 
 #[tokio::main]
@@ -52,28 +101,8 @@ async fn main() {
         .build()
         .unwrap();
 
-    let algorithm = match config.algorithm.as_str() {
-        "sliding_log" => AlgorithmType::SlidingLog,
-        "sliding_counter" => AlgorithmType::SlidingCounter,
-        _ => AlgorithmType::TokenBucket,
-    };
-
-    let state = AppState {
-        client,
-        config: config.clone(),
-        global_limiter: RateLimiter::new(
-            config.global_capacity,
-            config.global_refill_rate,
-            algorithm.clone(),
-        ),
-        route_limiter: RateLimiter::new(
-            config.route_capacity,
-            config.route_refill_rate,
-            algorithm.clone(),
-        ),
-        ip_limiter: RateLimiter::new(config.ip_capacity, config.ip_refill_rate, algorithm),
-        metrics: metrics.clone(),
-    };
+    let state = build_state(config, client, metrics.clone(), Arc::new(SystemClock));
+    restore_limiters(&state);
 
     let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(());
 
@@ -81,6 +110,12 @@ async fn main() {
         let global = state.global_limiter.clone();
         let route = state.route_limiter.clone();
         let ip = state.ip_limiter.clone();
+        let global_shadow = state.global_shadow.clone();
+        let route_shadow = state.route_shadow.clone();
+        let ip_shadow = state.ip_shadow.clone();
+        let quota = state.quota_limiter.clone();
+        let top_keys = state.top_keys.clone();
+        let snapshot_dir = state.config.snapshot_dir.clone();
         let mut shutdown_rx = shutdown_rx.clone();
 
         tokio::spawn(async move {
@@ -90,6 +125,16 @@ async fn main() {
                         global.cleanup(BUCKET_TTL);
                         route.cleanup(BUCKET_TTL);
                         ip.cleanup(BUCKET_TTL);
+                        global_shadow.iter().for_each(|shadow| shadow.cleanup(BUCKET_TTL));
+                        route_shadow.iter().for_each(|shadow| shadow.cleanup(BUCKET_TTL));
+                        ip_shadow.iter().for_each(|shadow| shadow.cleanup(BUCKET_TTL));
+                        // older traffic counts half each tick, top keys follow the current load
+                        top_keys.iter().flatten().for_each(HeavyHitters::decay);
+                        if let Some(quota) = &quota {
+                            quota.cleanup();
+                            // quota periods span days, don't leave them to a clean shutdown
+                            save_quota(quota, &snapshot_dir);
+                        }
                         tracing::debug!("bucket cleanup executed");
                     }
                     _ = shutdown_rx.changed() => {
@@ -104,18 +149,7 @@ async fn main() {
     let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
     info!("Starting API Gateway server on http://{}", addr);
 
-    let internal = Router::new()
-        .route("/health", get(health))
-        .route("/metrics", get(metrics_handler));
-
-    let api = Router::new()
-        .route("/{*path}", any(special_handler))
-        .layer(from_fn_with_state(state.clone(), rate_limit_middleware));
-
-    let app = Router::new()
-        .nest("/api", api)
-        .merge(internal)
-        .with_state(state.clone());
+    let app = router(state.clone());
 
     let listener = tokio::net::TcpListener::bind(addr)
         .await
@@ -140,6 +174,306 @@ async fn main() {
     .with_graceful_shutdown(shutdown_signal)
     .await
     .unwrap();
+
+    // serve only returns once shutdown_tx fired and in-flight requests drained,
+    // so the buckets are final here
+    save_limiters(&state);
+}
+
+fn restore_limiters(state: &AppState) {
+    let dir = &state.config.snapshot_dir;
+    if dir.is_empty() {
+        return;
+    }
+    let max_age = Duration::from_secs(state.config.snapshot_max_age_secs as u64);
+
+    restore_limiter(&state.global_limiter, dir, "global", max_age);
+    restore_limiter(&state.route_limiter, dir, "route", max_age);
+    restore_limiter(&state.ip_limiter, dir, "ip", max_age);
+
+    if let Some(quota) = &state.quota_limiter {
+        let path = Path::new(dir).join("quota.json");
+        match QuotaSnapshot::read(&path).and_then(|snapshot| quota.restore(snapshot)) {
+            Ok(restored) => info!(limiter = "quota", restored, "quota counters restored"),
+            Err(err) => tracing::warn!(limiter = "quota", ?err, "quota snapshot ignored"),
+        }
+    }
+}
+
+fn save_limiters(state: &AppState) {
+    let dir = &state.config.snapshot_dir;
+    if dir.is_empty() {
+        return;
+    }
+
+    save_limiter(&state.global_limiter, dir, "global");
+    save_limiter(&state.route_limiter, dir, "route");
+    save_limiter(&state.ip_limiter, dir, "ip");
+
+    if let Some(quota) = &state.quota_limiter {
+        save_quota(quota, dir);
+    }
+}
+
+fn save_quota(quota: &QuotaLimiter<IpAddr>, dir: &str) {
+    if dir.is_empty() {
+        return;
+    }
+
+    let path = Path::new(dir).join("quota.json");
+    let snapshot = quota.snapshot();
+    let counters = snapshot.counters.len();
+
+    match snapshot.write(&path) {
+        Ok(()) => tracing::debug!(limiter = "quota", counters, "quota counters saved"),
+        Err(err) => tracing::warn!(limiter = "quota", ?err, "failed to save quota counters"),
+    }
+}
+
+// a missing or rejected snapshot only costs clients a fresh burst, never fail startup
+fn restore_limiter<K>(limiter: &RateLimiter<K>, dir: &str, tier: &str, max_age: Duration)
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static + Serialize + DeserializeOwned,
+{
+    let path = Path::new(dir).join(format!("{tier}.json"));
+    let result =
+        LimiterSnapshot::read(&path).and_then(|snapshot| limiter.restore(snapshot, max_age));
+
+    match result {
+        Ok(restored) => info!(limiter = tier, restored, "limiter state restored"),
+        Err(err) => tracing::warn!(limiter = tier, ?err, "limiter snapshot ignored"),
+    }
+}
+
+fn save_limiter<K>(limiter: &RateLimiter<K>, dir: &str, tier: &str)
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static + Serialize + DeserializeOwned,
+{
+    let path = Path::new(dir).join(format!("{tier}.json"));
+    let snapshot = limiter.snapshot();
+    let buckets = snapshot.buckets.len();
+
+    match snapshot.write(&path) {
+        Ok(()) => info!(limiter = tier, buckets, "limiter state saved"),
+        Err(err) => tracing::warn!(limiter = tier, ?err, "failed to save limiter state"),
+    }
+}
+
+fn build_state(
+    config: GatewayConfig,
+    client: Client,
+    metrics: Arc<GatewayMetrices>,
+    clock: Arc<dyn Clock>,
+) -> AppState {
+    // from_env already rejected limits that don't fit their algorithm or store
+    let global_limit = config.global_limit().expect("Invalid GLOBAL limit");
+    let route_limit = config.route_limit().expect("Invalid ROUTE limit");
+    let ip_limit = config.ip_limit().expect("Invalid IP limit");
+
+    let store = match config.bucket_store.as_str() {
+        "mutex" => StoreType::Mutex,
+        "sharded" => StoreType::Sharded {
+            shards: config.bucket_store_shards as usize,
+        },
+        _ => StoreType::DashMap,
+    };
+
+    let adaptive_algorithm = match config.adaptive_concurrency.as_str() {
+        "aimd" => Some(AdaptiveAlgorithmType::Aimd {
+            latency_threshold: Duration::from_millis(config.adaptive_latency_threshold_ms as u64),
+        }),
+        "gradient" => Some(AdaptiveAlgorithmType::Gradient),
+        _ => None,
+    };
+
+    let eviction_policy = match config.key_eviction_policy.as_str() {
+        "lru" => EvictionPolicy::LeastRecentlySeen,
+        _ => EvictionPolicy::Overflow,
+    };
+
+    let quota_period = match config.quota_period.as_str() {
+        "monthly" => QuotaPeriod::Monthly,
+        _ => QuotaPeriod::Daily,
+    };
+    let quota_timezone = config.quota_timezone;
+
+    let shaping_queue = config.shaping_max_queue as usize;
+    let shaping_wait = Duration::from_millis(config.shaping_max_wait_ms as u64);
+
+    AppState {
+        client,
+        config: config.clone(),
+        global_limiter: global_limiter(&config, &global_limit, &store).with_clock(clock.clone()),
+        route_limiter: bound_keys(
+            RateLimiter::with_store(route_limit, build_store(&config, &store, "route"))
+                .with_clock(clock.clone())
+                .with_overrides(Arc::new(ResolvedOverrides)),
+            config.route_max_keys,
+            eviction_policy,
+        ),
+        ip_limiter: bound_keys(
+            RateLimiter::with_store(ip_limit, build_store(&config, &store, "ip"))
+                .with_clock(clock.clone())
+                .with_overrides(Arc::new(ResolvedOverrides)),
+            config.ip_max_keys,
+            eviction_policy,
+        ),
+        ip_overrides: Arc::new(ip_overrides(&config)),
+        route_overrides: Arc::new(route_overrides(&config)),
+        global_shadow: shadow_limiter(
+            config
+                .shadow_global_limit()
+                .expect("Invalid SHADOW_GLOBAL limit"),
+            &clock,
+        ),
+        route_shadow: shadow_limiter(
+            config
+                .shadow_route_limit()
+                .expect("Invalid SHADOW_ROUTE limit"),
+            &clock,
+        ),
+        ip_shadow: shadow_limiter(
+            config.shadow_ip_limit().expect("Invalid SHADOW_IP limit"),
+            &clock,
+        ),
+        quota_limiter: match config.quota_limit {
+            0 => None,
+            limit => Some(
+                QuotaLimiter::new(limit, quota_period, quota_timezone).with_clock(clock.clone()),
+            ),
+        },
+        top_keys: match config.top_keys_capacity {
+            0 => None,
+            capacity => Some(std::array::from_fn(|_| {
+                HeavyHitters::new(capacity as usize)
+                    .with_sample_every(config.top_keys_sample_every as u64)
+            })),
+        },
+        top_keys_hasher: RandomState::new(),
+        global_shaper: LeakyBucketShaper::new(shaping_queue, shaping_wait),
+        route_shaper: LeakyBucketShaper::new(shaping_queue, shaping_wait),
+        ip_shaper: LeakyBucketShaper::new(shaping_queue, shaping_wait),
+        global_concurrency: ConcurrencyLimiter::new(max_in_flight(config.global_max_in_flight)),
+        route_concurrency: ConcurrencyLimiter::new(max_in_flight(config.route_max_in_flight)),
+        ip_concurrency: ConcurrencyLimiter::new(max_in_flight(config.ip_max_in_flight)),
+        upstream_limiter: adaptive_algorithm.map(|algorithm| {
+            AdaptiveLimiter::new(
+                algorithm,
+                config.adaptive_initial_limit as usize,
+                config.adaptive_min_limit as usize,
+                config.adaptive_max_limit as usize,
+            )
+        }),
+        metrics,
+        clock,
+    }
+}
+
+fn router(state: AppState) -> Router {
+    let internal = Router::new()
+        .route("/health", get(health))
+        .route("/metrics", get(metrics_handler))
+        .route("/ratelimit/status", get(ratelimit_status_handler))
+        .route("/debug/top-keys", get(top_keys_handler));
+
+    let api = Router::new()
+        .route("/{*path}", any(special_handler))
+        .layer(from_fn_with_state(state.clone(), rate_limit_middleware));
+
+    Router::new()
+        .nest("/api", api)
+        .merge(internal)
+        .with_state(state)
+}
+
+// a single key, so the lock-free GCRA store fits it best when enabled
+fn global_limiter(
+    config: &GatewayConfig,
+    limit: &AlgorithmConfig,
+    store: &StoreType,
+) -> RateLimiter<()> {
+    // global_limit is already GCRA when the lock-free store is on
+    if config.global_lock_free && config.bucket_store != "redis" {
+        return RateLimiter::with_store(*limit, StoreType::AtomicGcra.build());
+    }
+
+    RateLimiter::with_store(*limit, build_store(config, store, "global"))
+}
+
+// every tier gets its own key prefix so replicas share buckets per tier
+fn build_store<K>(config: &GatewayConfig, store: &StoreType, tier: &str) -> Arc<dyn BucketStore<K>>
+where
+    K: RedisKey + Eq + Hash + Send + Sync + 'static,
+{
+    if config.bucket_store != "redis" {
+        return store.build();
+    }
+
+    let fail_mode = match config.redis_fail_open {
+        true => FailMode::Open,
+        false => FailMode::Closed,
+    };
+
+    Arc::new(
+        RedisStore::new(
+            &config.redis_url,
+            &format!("gateway:{tier}"),
+            config.redis_pool_size as usize,
+            fail_mode,
+            Duration::from_millis(config.redis_timeout_ms as u64),
+        )
+        .expect("REDIS_URL is checked by validate"),
+    )
+}
+
+// shadow policies stay in memory, a candidate must never write to the shared store
+fn shadow_limiter<K>(
+    limit: Option<AlgorithmConfig>,
+    clock: &Arc<dyn Clock>,
+) -> Option<RateLimiter<K>>
+where
+    K: Eq + Hash + Send + Sync + 'static,
+{
+    limit.map(|limit| {
+        RateLimiter::with_store(limit, StoreType::DashMap.build()).with_clock(clock.clone())
+    })
+}
+
+// a configured key cap of 0 means the map is unbounded
+fn bound_keys<K>(limiter: RateLimiter<K>, max_keys: u128, policy: EvictionPolicy) -> RateLimiter<K>
+where
+    K: Eq + Hash + Send + Sync + 'static,
+{
+    match max_keys {
+        0 => limiter,
+        max => limiter.with_key_limit(KeyLimit::new(max as usize, policy)),
+    }
+}
+
+fn route_overrides(config: &GatewayConfig) -> KeyOverrides<String> {
+    config
+        .route_overrides
+        .iter()
+        .fold(KeyOverrides::new(), |overrides, (route, policy)| {
+            overrides.key(route.clone(), *policy)
+        })
+}
+
+fn ip_overrides(config: &GatewayConfig) -> IpOverrides {
+    config
+        .ip_overrides
+        .iter()
+        .fold(IpOverrides::new(), |overrides, (range, policy)| {
+            overrides.range(*range, *policy)
+        })
+}
+
+// a configured cap of 0 means no cap
+fn max_in_flight(configured: u128) -> usize {
+    match configured {
+        0 => usize::MAX,
+        cap => cap as usize,
+    }
 }
 
 async fn health() -> &'static str {
@@ -166,20 +500,49 @@ async fn special_handler(
         path_and_query.trim_start_matches('/')
     );
     tracing::debug!(%full_url);
-    let upstream = state
-        .client
-        .request(method, full_url)
-        .headers(headers)
-        .body(body)
-        .send()
-        .await
-        .map_err(|_| StatusCode::BAD_GATEWAY)?;
 
-    let status = upstream.status();
-    let body = upstream
-        .bytes()
-        .await
-        .map_err(|_| StatusCode::BAD_GATEWAY)?;
+    // shed before more calls pile onto a struggling upstream
+    let permit = match &state.upstream_limiter {
+        Some(limiter) => {
+            match limiter.try_acquire(state.config.upstream_base_url.clone(), state.clock.now()) {
+                Ok(permit) => Some(permit),
+                Err(err) => {
+                    state
+                        .metrics
+                        .adaptive_rejected
+                        .fetch_add(1, Ordering::Relaxed);
+                    tracing::warn!(limiter = "adaptive", limit = err.limit, decision = "denied");
+                    return Err(StatusCode::SERVICE_UNAVAILABLE);
+                }
+            }
+        }
+        None => None,
+    };
+
+    let result = async {
+        let upstream = state
+            .client
+            .request(method, full_url)
+            .headers(headers)
+            .body(body)
+            .send()
+            .await?;
+
+        let status = upstream.status();
+        let body = upstream.bytes().await?;
+        Ok::<_, reqwest::Error>((status, body))
+    }
+    .await;
+
+    if let Some(permit) = permit {
+        let outcome = match &result {
+            Ok((status, _)) if !status.is_server_error() => Outcome::Success,
+            _ => Outcome::Dropped,
+        };
+        permit.release(outcome, state.clock.now());
+    }
+
+    let (status, body) = result.map_err(|_| StatusCode::BAD_GATEWAY)?;
 
     Ok(Response::builder()
         .status(status)
@@ -187,9 +550,80 @@ async fn special_handler(
         .unwrap())
 }
 
+#[derive(Deserialize)]
+struct StatusQuery {
+    route: Option<String>,
+}
+
+// remaining budget of the caller on every tier, peeked so checking is free
+async fn ratelimit_status_handler(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<StatusQuery>,
+    req: Request<Body>,
+) -> Json<RateLimitStatus> {
+    let now = state.clock.now();
+    let ip = addr.ip();
+    // the same keys a request to the route would be charged on
+    let route = query.route.as_deref().unwrap_or_default();
+    let keys = tier_keys(&state, &req, req.uri().path(), route, ip);
+
+    Json(RateLimitStatus {
+        global: state.global_limiter.peek(&(), now).into(),
+        route: query
+            .route
+            .map(|_| state.route_limiter.peek(&keys.route, now).into()),
+        ip: state.ip_limiter.peek(&keys.ip, now).into(),
+        quota: state
+            .quota_limiter
+            .as_ref()
+            .map(|quota| quota.peek(&ip).into()),
+    })
+}
+
+#[derive(Deserialize)]
+struct TopKeysQuery {
+    tier: String,
+    n: Option<usize>,
+}
+
+const TOP_KEYS_DEFAULT_N: usize = 20;
+
+// heaviest allowed and denied keys of a tier, e.g. /debug/top-keys?tier=ip&n=20
+async fn top_keys_handler(
+    State(state): State<AppState>,
+    Query(query): Query<TopKeysQuery>,
+) -> Result<Json<TopKeys>, StatusCode> {
+    let top_keys = state.top_keys.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    let tier = TIER_NAMES
+        .iter()
+        .position(|name| *name == query.tier)
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    let n = query.n.unwrap_or(TOP_KEYS_DEFAULT_N);
+    let redact = match TIER_NAMES[tier] {
+        "route" => state.config.route_key_template.reads_headers_or_query(),
+        "ip" => state.config.ip_key_template.reads_headers_or_query(),
+        _ => false,
+    };
+
+    Ok(Json(TopKeys::new(
+        TIER_NAMES[tier],
+        &top_keys[tier],
+        n,
+        redact.then_some(&state.top_keys_hasher),
+    )))
+}
+
 async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
     let m = &state.metrics;
 
+    let upstream = &state.config.upstream_base_url;
+    let (adaptive_limit, adaptive_in_flight) = state
+        .upstream_limiter
+        .as_ref()
+        .map(|limiter| (limiter.limit(upstream), limiter.in_flight(upstream)))
+        .unwrap_or((0, 0));
+
     let body = format!(
         r#"
         gateway_total_requests {}
@@ -198,6 +632,32 @@ async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
         gateway_global_rate_limited {}
         gateway_route_rate_limited {}
         gateway_ip_rate_limited {}
+        gateway_shaped_requests {}
+        gateway_shaping_rejected {}
+        gateway_shaping_queue_depth {}
+        gateway_shaping_wait_ms_total {}
+        gateway_total_concurrency_rejected {}
+        gateway_global_concurrency_rejected {}
+        gateway_route_concurrency_rejected {}
+        gateway_ip_concurrency_rejected {}
+        gateway_adaptive_rejected {}
+        gateway_adaptive_limit {}
+        gateway_adaptive_in_flight {}
+        gateway_route_keys {}
+        gateway_ip_keys {}
+        gateway_route_key_evictions {}
+        gateway_ip_key_evictions {}
+        gateway_route_key_overflow {}
+        gateway_ip_key_overflow {}
+        gateway_dry_run_global_denied {}
+        gateway_dry_run_route_denied {}
+        gateway_dry_run_ip_denied {}
+        gateway_shadow_global_denied {}
+        gateway_shadow_route_denied {}
+        gateway_shadow_ip_denied {}
+        gateway_quota_exceeded {}
+        gateway_quota_keys {}
+        gateway_refunded_requests {}
     "#,
         m.total_requests.load(Ordering::Relaxed),
         m.total_allowed.load(Ordering::Relaxed),
@@ -205,6 +665,32 @@ async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
         m.global_rate_limited.load(Ordering::Relaxed),
         m.route_rate_limited.load(Ordering::Relaxed),
         m.ip_rate_limited.load(Ordering::Relaxed),
+        m.shaped_requests.load(Ordering::Relaxed),
+        m.shaping_rejected.load(Ordering::Relaxed),
+        m.shaping_queue_depth.load(Ordering::Relaxed),
+        m.shaping_wait_ms_total.load(Ordering::Relaxed),
+        m.total_concurrency_rejected.load(Ordering::Relaxed),
+        m.global_concurrency_rejected.load(Ordering::Relaxed),
+        m.route_concurrency_rejected.load(Ordering::Relaxed),
+        m.ip_concurrency_rejected.load(Ordering::Relaxed),
+        m.adaptive_rejected.load(Ordering::Relaxed),
+        adaptive_limit,
+        adaptive_in_flight,
+        state.route_limiter.keys(),
+        state.ip_limiter.keys(),
+        state.route_limiter.evictions(),
+        state.ip_limiter.evictions(),
+        state.route_limiter.overflowed(),
+        state.ip_limiter.overflowed(),
+        m.dry_run_global_denied.load(Ordering::Relaxed),
+        m.dry_run_route_denied.load(Ordering::Relaxed),
+        m.dry_run_ip_denied.load(Ordering::Relaxed),
+        m.shadow_global_denied.load(Ordering::Relaxed),
+        m.shadow_route_denied.load(Ordering::Relaxed),
+        m.shadow_ip_denied.load(Ordering::Relaxed),
+        m.quota_exceeded.load(Ordering::Relaxed),
+        state.quota_limiter.as_ref().map_or(0, |quota| quota.keys()),
+        m.refunded_requests.load(Ordering::Relaxed),
     );
 
     (
@@ -213,3 +699,1077 @@ async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
         body,
     )
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::config::gateway_config::tests::config_with;
+    use axum::extract::ConnectInfo;
+    use gateway_core::{clock::MockClock, rate_limiter::overrides::KeyPolicy};
+    use tower::ServiceExt;
+
+    // upstream that answers every request with 200 so the gateway can forward
+    async fn spawn_upstream() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let upstream = Router::new()
+            .route("/{*path}", any(|| async { "upstream" }))
+            .route("/fail", any(|| async { StatusCode::INTERNAL_SERVER_ERROR }));
+        tokio::spawn(async move { axum::serve(listener, upstream).await.unwrap() });
+        format!("http://{addr}")
+    }
+
+    async fn send(app: &Router) -> Response<Body> {
+        send_from(app, [10, 0, 0, 1]).await
+    }
+
+    async fn send_from(app: &Router, ip: [u8; 4]) -> Response<Body> {
+        get_from(app, "/api/test", ip).await
+    }
+
+    async fn get_from(app: &Router, uri: &str, ip: [u8; 4]) -> Response<Body> {
+        let mut req = Request::get(uri).body(Body::empty()).unwrap();
+        req.extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from((ip, 4000))));
+        app.clone().oneshot(req).await.unwrap()
+    }
+
+    fn header(response: &Response<Body>, name: &str) -> String {
+        response.headers()[name].to_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    pub async fn headers_follow_virtual_time() {
+        let mut config = config_with("");
+        config.upstream_base_url = spawn_upstream().await;
+        config.global_capacity = 1000;
+        config.global_refill_rate = Some(1000);
+        config.route_capacity = 1000;
+        config.route_refill_rate = Some(1000);
+        config.ip_capacity = 3;
+        config.ip_refill_rate = Some(1);
+
+        let clock = MockClock::new();
+        let state = build_state(
+            config,
+            Client::new(),
+            Arc::new(GatewayMetrices::new()),
+            Arc::new(clock.clone()),
+        );
+        let app = router(state);
+
+        for remaining in ["2", "1", "0"] {
+            let response = send(&app).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(header(&response, "ratelimit-remaining"), remaining);
+        }
+
+        let response = send(&app).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&response, "retry-after"), "1");
+        assert_eq!(header(&response, "ratelimit-reset"), "1");
+
+        // half a token later the wait shrinks but the bucket is still empty
+        clock.advance(Duration::from_millis(500));
+        let response = send(&app).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&response, "ratelimit-reset"), "0");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["retry_after_ms"], 500);
+
+        clock.advance(Duration::from_millis(500));
+        let response = send(&app).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, "ratelimit-remaining"), "0");
+
+        clock.advance(Duration::from_secs(10));
+        let response = send(&app).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, "ratelimit-remaining"), "2");
+    }
+
+    #[tokio::test]
+    pub async fn limits_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("gateway-snapshot-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut config = config_with("");
+        config.upstream_base_url = spawn_upstream().await;
+        config.global_capacity = 1000;
+        config.route_capacity = 1000;
+        config.ip_capacity = 2;
+        config.snapshot_dir = dir.to_string_lossy().to_string();
+        config.snapshot_max_age_secs = 60;
+
+        let clock = MockClock::new();
+        let build = || {
+            build_state(
+                config.clone(),
+                Client::new(),
+                Arc::new(GatewayMetrices::new()),
+                Arc::new(clock.clone()),
+            )
+        };
+
+        let before = build();
+        let app = router(before.clone());
+        assert_eq!(send(&app).await.status(), StatusCode::OK);
+        assert_eq!(send(&app).await.status(), StatusCode::OK);
+        save_limiters(&before);
+
+        let after = build();
+        restore_limiters(&after);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let response = send(&router(after)).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    pub async fn ip_denials_leave_global_budget_alone() {
+        let mut config = config_with("");
+        config.upstream_base_url = spawn_upstream().await;
+        config.global_capacity = 2;
+        config.route_capacity = 1000;
+        config.ip_capacity = 1;
+
+        let state = build_state(
+            config,
+            Client::new(),
+            Arc::new(GatewayMetrices::new()),
+            Arc::new(MockClock::new()),
+        );
+        let app = router(state.clone());
+
+        assert_eq!(
+            send_from(&app, [10, 0, 0, 1]).await.status(),
+            StatusCode::OK
+        );
+        for _ in 0..5 {
+            let response = send_from(&app, [10, 0, 0, 1]).await;
+            assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        }
+        assert_eq!(state.metrics.ip_rate_limited.load(Ordering::Relaxed), 5);
+
+        let response = send_from(&app, [10, 0, 0, 2]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, "ratelimit-remaining"), "0");
+    }
+
+    #[tokio::test]
+    pub async fn lock_free_global_limiter() {
+        let mut config = config_with("");
+        config.upstream_base_url = spawn_upstream().await;
+        config.global_capacity = 2;
+        config.global_refill_rate = Some(1);
+        config.route_capacity = 1000;
+        config.ip_capacity = 1000;
+        config.global_lock_free = true;
+
+        let clock = Arc::new(MockClock::new());
+        let state = build_state(
+            config,
+            Client::new(),
+            Arc::new(GatewayMetrices::new()),
+            clock.clone(),
+        );
+        let app = router(state.clone());
+
+        assert_eq!(
+            send_from(&app, [10, 0, 0, 1]).await.status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            send_from(&app, [10, 0, 0, 2]).await.status(),
+            StatusCode::OK
+        );
+        let response = send_from(&app, [10, 0, 0, 3]).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&response, "retry-after"), "1");
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(
+            send_from(&app, [10, 0, 0, 3]).await.status(),
+            StatusCode::OK
+        );
+        assert_eq!(state.metrics.global_rate_limited.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    pub async fn dry_run_and_shadow_tiers_only_report() {
+        let mut config = config_with("");
+        config.upstream_base_url = spawn_upstream().await;
+        config.global_capacity = 1000;
+        config.route_capacity = 1000;
+        config.ip_capacity = 1;
+        config.ip_dry_run = true;
+        config.shadow_route_capacity = 2;
+        config.shadow_route_refill_rate = Some(1);
+
+        let state = build_state(
+            config,
+            Client::new(),
+            Arc::new(GatewayMetrices::new()),
+            Arc::new(MockClock::new()),
+        );
+        let app = router(state.clone());
+
+        let response = send(&app).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(
+            response
+                .headers()
+                .get("x-ratelimit-dry-run-denied")
+                .is_none()
+        );
+
+        send(&app).await;
+        let response = send(&app).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, "x-ratelimit-dry-run-denied"), "ip");
+        assert_eq!(header(&response, "x-ratelimit-shadow-denied"), "route");
+        // headers reflect the dry-run tier as if it were enforced
+        assert_eq!(header(&response, "ratelimit-remaining"), "0");
+
+        assert_eq!(state.metrics.dry_run_ip_denied.load(Ordering::Relaxed), 2);
+        assert_eq!(state.metrics.shadow_route_denied.load(Ordering::Relaxed), 1);
+        assert_eq!(state.metrics.ip_rate_limited.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    pub async fn ip_overrides_allow_limit_and_block() {
+        let mut config = config_with("");
+        config.upstream_base_url = spawn_upstream().await;
+        config.global_capacity = 1000;
+        config.route_capacity = 1000;
+        config.ip_capacity = 1;
+        config.ip_overrides = vec![
+            ("10.0.0.0/8".parse().unwrap(), KeyPolicy::Allow),
+            (
+                "10.1.0.0/16".parse().unwrap(),
+                KeyPolicy::Limit(AlgorithmConfig::TokenBucket { burst: 3, rate: 1 }),
+            ),
+            ("10.1.0.9".parse().unwrap(), KeyPolicy::Deny),
+        ];
+
+        let state = build_state(
+            config,
+            Client::new(),
+            Arc::new(GatewayMetrices::new()),
+            Arc::new(MockClock::new()),
+        );
+        let app = router(state);
+
+        for _ in 0..5 {
+            let response = send_from(&app, [10, 2, 0, 1]).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        for _ in 0..3 {
+            let response = send_from(&app, [10, 1, 0, 1]).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = send_from(&app, [10, 1, 0, 1]).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let response = send_from(&app, [10, 1, 0, 9]).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = send_from(&app, [192, 168, 0, 1]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send_from(&app, [192, 168, 0, 1]).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    pub async fn route_tier_keys_on_the_first_segment() {
+        let mut config = config_with("");
+        config.upstream_base_url = spawn_upstream().await;
+        config.global_capacity = 1000;
+        config.ip_capacity = 1000;
+        config.route_capacity = 1;
+
+        let state = build_state(
+            config,
+            Client::new(),
+            Arc::new(GatewayMetrices::new()),
+            Arc::new(MockClock::new()),
+        );
+        let app = router(state);
+        let send_to = |path: &str| {
+            let mut req = Request::get(path).body(Body::empty()).unwrap();
+            req.extensions_mut()
+                .insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 4000))));
+            app.clone().oneshot(req)
+        };
+
+        // the nest strips /api, the key still has to name the route
+        assert_eq!(
+            send_to("/api/orders/1").await.unwrap().status(),
+            StatusCode::OK
+        );
+        let response = send_to("/api/orders/2").await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            send_to("/api/users").await.unwrap().status(),
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    pub async fn route_overrides_allow_limit_and_block() {
+        let mut config = config_with("");
+        config.upstream_base_url = spawn_upstream().await;
+        config.global_capacity = 1000;
+        config.ip_capacity = 1000;
+        config.route_capacity = 1;
+        config.route_overrides = vec![
+            ("health".to_string(), KeyPolicy::Allow),
+            (
+                "export".to_string(),
+                KeyPolicy::Limit(AlgorithmConfig::TokenBucket { burst: 3, rate: 1 }),
+            ),
+            ("admin".to_string(), KeyPolicy::Deny),
+        ];
+
+        let state = build_state(
+            config,
+            Client::new(),
+            Arc::new(GatewayMetrices::new()),
+            Arc::new(MockClock::new()),
+        );
+        let app = router(state);
+        let send_to = |path: &str| {
+            let mut req = Request::get(path).body(Body::empty()).unwrap();
+            req.extensions_mut()
+                .insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 4000))));
+            app.clone().oneshot(req)
+        };
+
+        for _ in 0..5 {
+            let response = send_to("/api/health").await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        for _ in 0..3 {
+            let response = send_to("/api/export/csv").await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = send_to("/api/export/csv").await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let response = send_to("/api/admin/users").await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        assert_eq!(
+            send_to("/api/orders").await.unwrap().status(),
+            StatusCode::OK
+        );
+        let response = send_to("/api/orders").await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    pub async fn templated_tier_keys() {
+        let mut config = config_with("");
+        config.upstream_base_url = spawn_upstream().await;
+        config.global_capacity = 1000;
+        config.route_capacity = 1;
+        config.ip_capacity = 1;
+        config.ip_key_template = "{header:x-api-key|ip}".parse().unwrap();
+        config.route_key_template = "{query:tenant|\"public\"}:{route}:{method}"
+            .parse()
+            .unwrap();
+
+        let state = build_state(
+            config,
+            Client::new(),
+            Arc::new(GatewayMetrices::new()),
+            Arc::new(MockClock::new()),
+        );
+        let app = router(state);
+
+        let send_keyed = |uri: &str, api_key: Option<&str>| {
+            let mut req = Request::get(uri);
+            if let Some(api_key) = api_key {
+                req = req.header("x-api-key", api_key);
+            }
+            let mut req = req.body(Body::empty()).unwrap();
+            req.extensions_mut()
+                .insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 4000))));
+            app.clone().oneshot(req)
+        };
+
+        // one client address, every api key and tenant has its own budget
+        for (uri, api_key) in [
+            ("/api/test?tenant=a", Some("k1")),
+            ("/api/test?tenant=b", Some("k2")),
+            ("/api/test", None),
+        ] {
+            let response = send_keyed(uri, api_key).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        // the ip tier still knows k1
+        let response = send_keyed("/api/test?tenant=c", Some("k1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        // no tenant shares the "public" bucket, no api key falls back to the address
+        let response = send_keyed("/api/test", Some("k3")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let response = send_keyed("/api/test?tenant=d", None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let response = send_keyed("/api/test?tenant=d", Some("k4")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    pub async fn header_values_cannot_pose_as_addresses() {
+        let mut config = config_with("");
+        config.upstream_base_url = spawn_upstream().await;
+        config.global_capacity = 1000;
+        config.route_capacity = 1000;
+        config.ip_capacity = 1;
+        config.ip_key_template = "{header:x-api-key|ip}".parse().unwrap();
+        config.ip_overrides = vec![("10.0.0.0/8".parse().unwrap(), KeyPolicy::Allow)];
+
+        let state = build_state(
+            config,
+            Client::new(),
+            Arc::new(GatewayMetrices::new()),
+            Arc::new(MockClock::new()),
+        );
+        let app = router(state);
+
+        let send_keyed = |ip: [u8; 4], api_key: Option<&str>| {
+            let mut req = Request::get("/api/test");
+            if let Some(api_key) = api_key {
+                req = req.header("x-api-key", api_key);
+            }
+            let mut req = req.body(Body::empty()).unwrap();
+            req.extensions_mut()
+                .insert(ConnectInfo(SocketAddr::from((ip, 4000))));
+            app.clone().oneshot(req)
+        };
+
+        // an allowlisted address in the header doesn't allowlist the sender
+        let response = send_keyed([192, 168, 0, 1], Some("10.0.0.1"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send_keyed([192, 168, 0, 1], Some("10.0.0.1"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // nor does it spend the budget of the client at that address
+        let response = send_keyed([192, 168, 0, 2], Some("192.168.0.3"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send_keyed([192, 168, 0, 3], None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // the allowlist follows the client address, whatever key it sends
+        for _ in 0..3 {
+            let response = send_keyed([10, 0, 0, 1], Some("k1")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    pub async fn quota_tier_skips_rate_limited_requests() {
+        let mut config = config_with("");
+        config.upstream_base_url = spawn_upstream().await;
+        config.global_capacity = 1000;
+        config.route_capacity = 1000;
+        config.ip_capacity = 1;
+        config.quota_limit = 2;
+        config.quota_timezone = chrono_tz::Asia::Kolkata;
+
+        let clock = Arc::new(MockClock::new());
+        let state = build_state(
+            config,
+            Client::new(),
+            Arc::new(GatewayMetrices::new()),
+            clock.clone(),
+        );
+        let app = router(state.clone());
+
+        let response = send(&app).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, "x-quota-remaining"), "1");
+        assert!(header(&response, "ratelimit-policy").starts_with("2;w=86400;"));
+
+        // denied by the ip tier, the quota unit is given back
+        let response = send(&app).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(send(&app).await.status(), StatusCode::OK);
+
+        clock.advance(Duration::from_secs(1));
+        let response = send(&app).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&response, "x-quota-remaining"), "0");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("quota_exceeded"));
+
+        assert_eq!(state.metrics.quota_exceeded.load(Ordering::Relaxed), 1);
+        assert_eq!(state.metrics.ip_rate_limited.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    pub async fn status_reports_budget_without_charging() {
+        let mut config = config_with("");
+        config.upstream_base_url = spawn_upstream().await;
+        config.global_capacity = 10;
+        config.route_capacity = 10;
+        config.ip_capacity = 3;
+
+        let state = build_state(
+            config,
+            Client::new(),
+            Arc::new(GatewayMetrices::new()),
+            Arc::new(MockClock::new()),
+        );
+        let app = router(state);
+
+        assert_eq!(send(&app).await.status(), StatusCode::OK);
+
+        for _ in 0..3 {
+            let response = get_from(&app, "/ratelimit/status?route=test", [10, 0, 0, 1]).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+            assert_eq!(status["global"]["remaining"], 9);
+            assert_eq!(status["route"]["remaining"], 9);
+            assert_eq!(status["ip"]["remaining"], 2);
+            assert!(status["quota"].is_null());
+        }
+
+        let response = get_from(&app, "/ratelimit/status", [10, 0, 0, 2]).await;
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(status["ip"]["remaining"], 3);
+        assert!(status["route"].is_null());
+    }
+
+    #[tokio::test]
+    pub async fn top_keys_name_the_offenders() {
+        let mut config = config_with("");
+        config.upstream_base_url = spawn_upstream().await;
+        config.global_capacity = 1000;
+        config.route_capacity = 1000;
+        config.ip_capacity = 2;
+        config.top_keys_capacity = 4;
+
+        let state = build_state(
+            config,
+            Client::new(),
+            Arc::new(GatewayMetrices::new()),
+            Arc::new(MockClock::new()),
+        );
+        let app = router(state.clone());
+
+        // more distinct clients than counters, memory stays at 4 keys
+        for last in 2..10 {
+            send_from(&app, [10, 0, 0, last]).await;
+        }
+        for _ in 0..6 {
+            send_from(&app, [10, 0, 0, 1]).await;
+        }
+
+        let response = get_from(&app, "/debug/top-keys?tier=ip&n=2", [10, 0, 0, 1]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let top: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(top["tier"], "ip");
+        assert_eq!(top["denied"][0]["key"], "ip:10.0.0.1");
+        assert_eq!(top["denied"][0]["count"], 4);
+        assert_eq!(top["denied"].as_array().unwrap().len(), 1);
+        assert_eq!(top["allowed"].as_array().unwrap().len(), 2);
+        let tracked = state.top_keys.as_ref().unwrap()[2].top(Decision::Allowed, 100);
+        assert_eq!(tracked.len(), 4);
+
+        let response = get_from(&app, "/debug/top-keys?tier=global", [10, 0, 0, 1]).await;
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let top: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        // took over a counter of 2, so it is counted as 4 with an error of 2
+        assert_eq!(top["allowed"][0]["key"], "10.0.0.1");
+        assert_eq!(top["allowed"][0]["count"], 4);
+        assert_eq!(top["allowed"][0]["error"], 2);
+
+        let response = get_from(&app, "/debug/top-keys?tier=tenant", [10, 0, 0, 1]).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    pub async fn top_keys_hide_api_keys() {
+        let mut config = config_with("");
+        config.upstream_base_url = spawn_upstream().await;
+        config.ip_key_template = "{header:x-api-key|ip}".parse().unwrap();
+        config.top_keys_capacity = 4;
+
+        let state = build_state(
+            config,
+            Client::new(),
+            Arc::new(GatewayMetrices::new()),
+            Arc::new(MockClock::new()),
+        );
+        let app = router(state.clone());
+
+        let mut req = Request::get("/api/test")
+            .header("x-api-key", "secret-key")
+            .body(Body::empty())
+            .unwrap();
+        req.extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 4000))));
+        assert_eq!(
+            app.clone().oneshot(req).await.unwrap().status(),
+            StatusCode::OK
+        );
+
+        // the ip tier reads a header, only the hash of its keys is shown
+        let response = get_from(&app, "/debug/top-keys?tier=ip", [10, 0, 0, 1]).await;
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let top: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let hidden = redact_key(&state.top_keys_hasher, "h:x-api-key:secret-key");
+        assert_eq!(top["allowed"][0]["key"], hidden.as_str());
+        assert!(!String::from_utf8_lossy(&body).contains("secret-key"));
+
+        // the global tier only knows the address
+        let response = get_from(&app, "/debug/top-keys?tier=global", [10, 0, 0, 1]).await;
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let top: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(top["allowed"][0]["key"], "10.0.0.1");
+    }
+
+    #[tokio::test]
+    pub async fn upstream_errors_are_refunded() {
+        let mut config = config_with("");
+        config.upstream_base_url = spawn_upstream().await;
+        config.global_capacity = 10;
+        config.route_capacity = 10;
+        config.ip_capacity = 1;
+        config.refund_on_upstream_error = true;
+
+        let state = build_state(
+            config,
+            Client::new(),
+            Arc::new(GatewayMetrices::new()),
+            Arc::new(MockClock::new()),
+        );
+        let app = router(state.clone());
+
+        for _ in 0..3 {
+            let response = get_from(&app, "/api/fail", [10, 0, 0, 1]).await;
+            assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        assert_eq!(state.metrics.refunded_requests.load(Ordering::Relaxed), 3);
+
+        assert_eq!(send(&app).await.status(), StatusCode::OK);
+        assert_eq!(send(&app).await.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    pub async fn key_limit_shows_in_metrics() {
+        let mut config = config_with("");
+        config.upstream_base_url = spawn_upstream().await;
+        config.global_capacity = 1000;
+        config.route_capacity = 1000;
+        config.ip_capacity = 2;
+        config.ip_max_keys = 2;
+        config.key_eviction_policy = "lru".to_string();
+
+        let clock = MockClock::new();
+        let state = build_state(
+            config.clone(),
+            Client::new(),
+            Arc::new(GatewayMetrices::new()),
+            Arc::new(clock.clone()),
+        );
+        let app = router(state.clone());
+
+        assert_eq!(
+            send_from(&app, [10, 0, 0, 1]).await.status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            send_from(&app, [10, 0, 0, 1]).await.status(),
+            StatusCode::OK
+        );
+        for last in 2..=4 {
+            clock.advance(Duration::from_secs(1));
+            assert_eq!(
+                send_from(&app, [10, 0, 0, last]).await.status(),
+                StatusCode::OK
+            );
+        }
+
+        let response = get_from(&app, "/metrics", [10, 0, 0, 9]).await;
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains("gateway_ip_keys 2\n"), "{body}");
+        assert!(body.contains("gateway_ip_key_evictions 2\n"));
+        assert!(body.contains("gateway_route_keys 1\n"));
+        assert!(body.contains("gateway_route_key_evictions 0\n"));
+
+        // the least recently seen address was evicted and starts with a full bucket
+        let response = send_from(&app, [10, 0, 0, 1]).await;
+        assert_eq!(header(&response, "ratelimit-remaining"), "1");
+
+        // overflow keeps the known keys and counts the newcomers instead
+        config.key_eviction_policy = "overflow".to_string();
+        let state = build_state(
+            config,
+            Client::new(),
+            Arc::new(GatewayMetrices::new()),
+            Arc::new(clock.clone()),
+        );
+        let app = router(state.clone());
+        for last in 1..=4 {
+            assert_eq!(
+                send_from(&app, [10, 0, 0, last]).await.status(),
+                StatusCode::OK
+            );
+        }
+        assert_eq!(state.ip_limiter.keys(), 2);
+        assert_eq!(state.ip_limiter.evictions(), 0);
+        assert_eq!(state.ip_limiter.overflowed(), 2);
+    }
+
+    #[tokio::test]
+    pub async fn costs_over_capacity_are_too_large() {
+        let mut config = config_with("/api/export=10");
+        config.upstream_base_url = spawn_upstream().await;
+        config.global_capacity = 1000;
+        config.route_capacity = 1000;
+        config.ip_capacity = 5;
+
+        let state = build_state(
+            config,
+            Client::new(),
+            Arc::new(GatewayMetrices::new()),
+            Arc::new(MockClock::new()),
+        );
+        let app = router(state);
+
+        // waiting never makes a bucket of 5 hold 10, so no Retry-After
+        let response = get_from(&app, "/api/export", [10, 0, 0, 1]).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(response.headers().get("retry-after").is_none());
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "cost_exceeds_limit");
+
+        // and nothing was charged
+        let response = send(&app).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, "ratelimit-remaining"), "4");
+    }
+
+    #[tokio::test]
+    pub async fn route_costs_drain_their_weight() {
+        let mut config = config_with("post /api/search=5, /api/export=3, /api/bulk=20");
+        config.upstream_base_url = spawn_upstream().await;
+        config.global_capacity = 1000;
+        config.route_capacity = 1000;
+        config.ip_capacity = 10;
+
+        let state = build_state(
+            config,
+            Client::new(),
+            Arc::new(GatewayMetrices::new()),
+            Arc::new(MockClock::new()),
+        );
+        let app = router(state);
+
+        let response = get_from(&app, "/api/export/123", [10, 0, 0, 1]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, "x-ratelimit-cost"), "3");
+        assert_eq!(header(&response, "ratelimit-remaining"), "7");
+
+        let mut req = Request::post("/api/search").body(Body::empty()).unwrap();
+        req.extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 4000))));
+        let response = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, "x-ratelimit-cost"), "5");
+        assert_eq!(header(&response, "ratelimit-remaining"), "2");
+
+        // the method specific entry doesn't match a GET, the default cost applies
+        let response = get_from(&app, "/api/search", [10, 0, 0, 1]).await;
+        assert_eq!(header(&response, "x-ratelimit-cost"), "1");
+        assert_eq!(header(&response, "ratelimit-remaining"), "1");
+
+        // 3 doesn't fit in the 1 left, a later retry would
+        let response = get_from(&app, "/api/export", [10, 0, 0, 1]).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&response, "x-ratelimit-cost"), "3");
+        assert_eq!(header(&response, "retry-after"), "2");
+
+        // 20 never fits in a bucket of 10
+        let response = get_from(&app, "/api/bulk", [10, 0, 0, 2]).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(header(&response, "x-ratelimit-cost"), "20");
+    }
+
+    #[tokio::test]
+    pub async fn concurrency_caps_hold_until_the_body_is_done() {
+        let mut config = config_with("");
+        config.upstream_base_url = spawn_upstream().await;
+        config.global_capacity = 1000;
+        config.route_capacity = 1000;
+        config.ip_capacity = 1000;
+        config.global_max_in_flight = 2;
+        config.ip_max_in_flight = 1;
+
+        let state = build_state(
+            config,
+            Client::new(),
+            Arc::new(GatewayMetrices::new()),
+            Arc::new(MockClock::new()),
+        );
+        let app = router(state.clone());
+
+        // an unread body keeps its permits, a second request from the address gets a 429
+        let first = send_from(&app, [10, 0, 0, 1]).await;
+        assert_eq!(first.status(), StatusCode::OK);
+        let response = send_from(&app, [10, 0, 0, 1]).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "ip_concurrency_limited");
+        assert_eq!(body["in_flight"], 1);
+
+        // the gateway as a whole is full -> 503 for everyone
+        let second = send_from(&app, [10, 0, 0, 2]).await;
+        assert_eq!(second.status(), StatusCode::OK);
+        let response = send_from(&app, [10, 0, 0, 3]).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "global_concurrency_limited");
+        assert_eq!(
+            state
+                .metrics
+                .global_concurrency_rejected
+                .load(Ordering::Relaxed),
+            1
+        );
+        assert_eq!(
+            state
+                .metrics
+                .ip_concurrency_rejected
+                .load(Ordering::Relaxed),
+            1
+        );
+
+        // reading a body to the end frees its slot, dropping one does too
+        to_bytes(first.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(
+            send_from(&app, [10, 0, 0, 3]).await.status(),
+            StatusCode::OK
+        );
+        drop(second);
+        assert_eq!(
+            send_from(&app, [10, 0, 0, 1]).await.status(),
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    pub async fn adaptive_limit_sheds_and_learns_from_the_upstream() {
+        // /hold answers once the test hands out a permit
+        let gate = Arc::new(tokio::sync::Semaphore::new(0));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let held = gate.clone();
+        let upstream = Router::new()
+            .route(
+                "/hold",
+                any(move || async move {
+                    held.acquire().await.unwrap().forget();
+                    "upstream"
+                }),
+            )
+            .route("/fail", any(|| async { StatusCode::INTERNAL_SERVER_ERROR }));
+        tokio::spawn(async move { axum::serve(listener, upstream).await.unwrap() });
+
+        let mut config = config_with("");
+        config.upstream_base_url = format!("http://{addr}");
+        config.global_capacity = 1000;
+        config.route_capacity = 1000;
+        config.ip_capacity = 1000;
+        config.adaptive_concurrency = "aimd".to_string();
+        config.adaptive_initial_limit = 2;
+        config.adaptive_min_limit = 1;
+        config.adaptive_max_limit = 10;
+        config.adaptive_latency_threshold_ms = 1000;
+
+        let clock = MockClock::new();
+        let state = build_state(
+            config,
+            Client::new(),
+            Arc::new(GatewayMetrices::new()),
+            Arc::new(clock.clone()),
+        );
+        let app = router(state.clone());
+        let upstream = state.config.upstream_base_url.clone();
+        let limiter = state.upstream_limiter.clone().unwrap();
+
+        let mut calls = Vec::new();
+        for last in 1..=2 {
+            let app = app.clone();
+            calls.push(tokio::spawn(async move {
+                get_from(&app, "/api/hold", [10, 0, 0, last]).await.status()
+            }));
+        }
+        while limiter.in_flight(&upstream) < 2 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        // the limit is reached, the third call is shed before it reaches the upstream
+        let response = send_from(&app, [10, 0, 0, 3]).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(state.metrics.adaptive_rejected.load(Ordering::Relaxed), 1);
+
+        // fast answers under full load grow the limit
+        gate.add_permits(2);
+        for call in calls {
+            assert_eq!(call.await.unwrap(), StatusCode::OK);
+        }
+        assert_eq!(limiter.in_flight(&upstream), 0);
+        assert_eq!(limiter.limit(&upstream), 4);
+
+        // a 5xx is released as Dropped, the permit is freed and the limit backs off
+        let response = get_from(&app, "/api/fail", [10, 0, 0, 1]).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(limiter.in_flight(&upstream), 0);
+        assert_eq!(limiter.limit(&upstream), 3);
+
+        // latency is measured on the gateway clock, a call that took 2s of it is too slow
+        let app_slow = app.clone();
+        let call = tokio::spawn(async move {
+            get_from(&app_slow, "/api/hold", [10, 0, 0, 1])
+                .await
+                .status()
+        });
+        while limiter.in_flight(&upstream) < 1 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        clock.advance(Duration::from_secs(2));
+        gate.add_permits(1);
+        assert_eq!(call.await.unwrap(), StatusCode::OK);
+        assert_eq!(limiter.limit(&upstream), 2);
+    }
+
+    // real clock, the shaping queue sleeps on tokio time
+    async fn shaping_app(refill_rate: u128, max_queue: u128, max_wait_ms: u128) -> AppState {
+        let mut config = config_with("");
+        config.upstream_base_url = spawn_upstream().await;
+        config.global_capacity = 1000;
+        config.route_capacity = 1000;
+        config.ip_capacity = 1;
+        config.ip_refill_rate = Some(refill_rate);
+        config.shaping_enabled = true;
+        config.shaping_max_queue = max_queue;
+        config.shaping_max_wait_ms = max_wait_ms;
+
+        build_state(
+            config,
+            Client::new(),
+            Arc::new(GatewayMetrices::new()),
+            Arc::new(SystemClock),
+        )
+    }
+
+    // starts a request and returns once it sits in a shaping queue
+    async fn queue_request(
+        state: &AppState,
+        app: &Router,
+    ) -> tokio::task::JoinHandle<(StatusCode, std::time::Instant)> {
+        let queued = || state.metrics.shaped_requests.load(Ordering::Relaxed);
+        let before = queued();
+        let app = app.clone();
+        let request = tokio::spawn(async move {
+            let status = send(&app).await.status();
+            (status, std::time::Instant::now())
+        });
+        while queued() == before {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        request
+    }
+
+    #[tokio::test]
+    pub async fn shaping_forwards_queued_requests_in_order_at_the_refill_rate() {
+        let state = shaping_app(20, 5, 1000).await;
+        let app = router(state.clone());
+
+        let start = std::time::Instant::now();
+        assert_eq!(send(&app).await.status(), StatusCode::OK);
+        let mut queued = Vec::new();
+        for _ in 0..3 {
+            queued.push(queue_request(&state, &app).await);
+        }
+
+        // one token every 50ms, in the order the requests queued up
+        let mut forwarded = Vec::new();
+        for request in queued {
+            let (status, at) = request.await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            forwarded.push(at);
+        }
+        assert!(forwarded.is_sorted());
+        assert!(forwarded[2] - start >= Duration::from_millis(150));
+        assert_eq!(state.metrics.shaped_requests.load(Ordering::Relaxed), 3);
+        assert_eq!(state.metrics.shaping_rejected.load(Ordering::Relaxed), 0);
+        assert_eq!(state.metrics.shaping_queue_depth.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    pub async fn shaping_rejects_when_the_queue_is_full() {
+        let state = shaping_app(20, 1, 1000).await;
+        let app = router(state.clone());
+
+        assert_eq!(send(&app).await.status(), StatusCode::OK);
+        let queued = queue_request(&state, &app).await;
+
+        // no room behind the queued request, straight 429
+        let response = send(&app).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(state.metrics.shaping_rejected.load(Ordering::Relaxed), 1);
+
+        assert_eq!(queued.await.unwrap().0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    pub async fn shaping_rejects_waits_over_the_max() {
+        // a token every 100ms
+        let state = shaping_app(10, 5, 150).await;
+        let app = router(state.clone());
+        assert_eq!(send(&app).await.status(), StatusCode::OK);
+
+        // 100ms for the next token fits, the one after would be ready at 200ms
+        let start = std::time::Instant::now();
+        let first = queue_request(&state, &app).await;
+        let response = send(&app).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(start.elapsed() < Duration::from_millis(200));
+        assert_eq!(first.await.unwrap().0, StatusCode::OK);
+        assert_eq!(state.metrics.shaping_rejected.load(Ordering::Relaxed), 1);
+
+        // a wait that is over the max on its own is turned down without queueing
+        let state = shaping_app(1, 5, 500).await;
+        let app = router(state.clone());
+        assert_eq!(send(&app).await.status(), StatusCode::OK);
+        let response = send(&app).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&response, "retry-after"), "1");
+        assert_eq!(state.metrics.shaped_requests.load(Ordering::Relaxed), 0);
+    }
+}
@@ -1,6 +1,7 @@
 use std::sync::atomic::AtomicU64;
 
 // Atomic type -> No locking, thread safe and high performance
+#[derive(Default)]
 pub struct GatewayMetrices {
     pub total_requests: AtomicU64,
     pub total_rate_limited: AtomicU64,
@@ -8,6 +9,23 @@ pub struct GatewayMetrices {
     pub route_rate_limited: AtomicU64,
     pub ip_rate_limited: AtomicU64,
     pub total_allowed: AtomicU64,
+    pub shaped_requests: AtomicU64,
+    pub shaping_rejected: AtomicU64,
+    pub shaping_queue_depth: AtomicU64,
+    pub shaping_wait_ms_total: AtomicU64,
+    pub total_concurrency_rejected: AtomicU64,
+    pub global_concurrency_rejected: AtomicU64,
+    pub route_concurrency_rejected: AtomicU64,
+    pub ip_concurrency_rejected: AtomicU64,
+    pub adaptive_rejected: AtomicU64,
+    pub dry_run_global_denied: AtomicU64,
+    pub dry_run_route_denied: AtomicU64,
+    pub dry_run_ip_denied: AtomicU64,
+    pub shadow_global_denied: AtomicU64,
+    pub shadow_route_denied: AtomicU64,
+    pub shadow_ip_denied: AtomicU64,
+    pub quota_exceeded: AtomicU64,
+    pub refunded_requests: AtomicU64,
 }
 
 impl GatewayMetrices {
@@ -19,6 +37,23 @@ impl GatewayMetrices {
             route_rate_limited: AtomicU64::new(0),
             ip_rate_limited: AtomicU64::new(0),
             total_allowed: AtomicU64::new(0),
+            shaped_requests: AtomicU64::new(0),
+            shaping_rejected: AtomicU64::new(0),
+            shaping_queue_depth: AtomicU64::new(0),
+            shaping_wait_ms_total: AtomicU64::new(0),
+            total_concurrency_rejected: AtomicU64::new(0),
+            global_concurrency_rejected: AtomicU64::new(0),
+            route_concurrency_rejected: AtomicU64::new(0),
+            ip_concurrency_rejected: AtomicU64::new(0),
+            adaptive_rejected: AtomicU64::new(0),
+            dry_run_global_denied: AtomicU64::new(0),
+            dry_run_route_denied: AtomicU64::new(0),
+            dry_run_ip_denied: AtomicU64::new(0),
+            shadow_global_denied: AtomicU64::new(0),
+            shadow_route_denied: AtomicU64::new(0),
+            shadow_ip_denied: AtomicU64::new(0),
+            quota_exceeded: AtomicU64::new(0),
+            refunded_requests: AtomicU64::new(0),
         }
     }
 }
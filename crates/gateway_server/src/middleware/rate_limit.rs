@@ -1,15 +1,48 @@
-use crate::{AppState, http::errors::RateLimitHttpError, metrics};
+use crate::{
+    AppState,
+    http::{
+        errors::{ConcurrencyHttpError, RateLimitHttpError},
+        permit_body::PermitBody,
+    },
+    metrics,
+};
 use axum::{
     body::Body,
-    extract::{ConnectInfo, State},
+    extract::{ConnectInfo, OriginalUri, State},
     http::{HeaderName, HeaderValue, Request},
     middleware::Next,
     response::{IntoResponse, Response},
 };
-use std::{net::SocketAddr, sync::atomic::Ordering, time::Instant};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
 
-use gateway_core::rate_limiter::{algorithm::BucketState, rate_limiter::RateLimitError};
+use gateway_core::{
+    concurrency::{ConcurrencyError, ConcurrencyPermit},
+    layer::KeyContext,
+    rate_limiter::{
+        algorithm::BucketState,
+        composite::{CompositeCheck, TierDenied},
+        heavy_hitters::Decision,
+        overrides::{OverrideLookup, ResolvedKey},
+        quota::QuotaPeriod,
+        rate_limiter::{RateLimitError, RateLimitErrorKind},
+        shaper::{QueueSlot, ShapeError},
+    },
+};
 use reqwest::StatusCode;
+use tracing::Instrument;
+
+// never spin on a zero retry_after while shaping
+const MIN_SHAPING_SLEEP: Duration = Duration::from_millis(1);
+
+type ConcurrencyPermits = (
+    ConcurrencyPermit<()>,
+    ConcurrencyPermit<String>,
+    ConcurrencyPermit<IpAddr>,
+);
 
 fn attach_headers(response: &mut Response<Body>, snapshot: &BucketState) {
     let headers = response.headers_mut();
@@ -31,10 +64,67 @@ fn attach_headers(response: &mut Response<Body>, snapshot: &BucketState) {
     );
 }
 
+// echo the charged cost so clients can debug the route cost table
+fn attach_cost_header(response: &mut Response<Body>, cost: u128) {
+    response.headers_mut().insert(
+        HeaderName::from_static("x-ratelimit-cost"),
+        HeaderValue::from_str(&cost.to_string()).unwrap(),
+    );
+}
+
+// describes the quota tier, e.g. `1000;w=86400;comment="daily quota, resets at midnight UTC"`
+fn attach_quota_headers(response: &mut Response<Body>, state: &AppState, snapshot: &BucketState) {
+    let Some(quota) = &state.quota_limiter else {
+        return;
+    };
+
+    let (start, end) = quota.period_bounds(state.clock.unix_time());
+    let period = match quota.period() {
+        QuotaPeriod::Daily => "daily",
+        QuotaPeriod::Monthly => "monthly",
+    };
+    let policy = format!(
+        "{};w={};comment=\"{period} quota, resets at midnight {}\"",
+        quota.limit(),
+        end - start,
+        quota.timezone().name()
+    );
+
+    let headers = response.headers_mut();
+    headers.insert(
+        HeaderName::from_static("ratelimit-policy"),
+        HeaderValue::from_str(&policy).unwrap(),
+    );
+    headers.insert(
+        HeaderName::from_static("x-quota-remaining"),
+        HeaderValue::from_str(&snapshot.remaining.to_string()).unwrap(),
+    );
+    headers.insert(
+        HeaderName::from_static("x-quota-reset"),
+        HeaderValue::from_str(&snapshot.reset_after.as_secs().to_string()).unwrap(),
+    );
+}
+
 fn build_rate_limit_response(err: RateLimitError) -> (Response<Body>, BucketState) {
     let snapshot = err.snapshot;
 
+    let (status, error) = match err.kind {
+        RateLimitErrorKind::Limited => (StatusCode::TOO_MANY_REQUESTS, "rate_limited"),
+        // the request is bigger than the bucket, retrying the same request never helps
+        RateLimitErrorKind::CostExceedsCapacity => {
+            (StatusCode::PAYLOAD_TOO_LARGE, "cost_exceeds_limit")
+        }
+        RateLimitErrorKind::StoreUnavailable => {
+            (StatusCode::TOO_MANY_REQUESTS, "limiter_unavailable")
+        }
+        // denylisted key, no amount of waiting will get it through
+        RateLimitErrorKind::Blocked => (StatusCode::FORBIDDEN, "blocked"),
+        RateLimitErrorKind::QuotaExceeded => (StatusCode::TOO_MANY_REQUESTS, "quota_exceeded"),
+    };
+
     let response = RateLimitHttpError {
+        status,
+        error,
         retry_after_ms: err.retry_after.as_millis() as u64,
         ratelimit_limit: snapshot.limit as u64,
         ratelimit_remaining: snapshot.remaining as u64,
@@ -76,6 +166,469 @@ fn inc_route_limit(state: &AppState) {
         .fetch_add(1, Ordering::Relaxed);
 }
 
+fn inc_concurrency_rejected(state: &AppState, tier: &AtomicU64) {
+    state
+        .metrics
+        .total_concurrency_rejected
+        .fetch_add(1, Ordering::Relaxed);
+    tier.fetch_add(1, Ordering::Relaxed);
+}
+
+fn concurrency_http_error(
+    status: StatusCode,
+    error: &'static str,
+    err: ConcurrencyError,
+) -> ConcurrencyHttpError {
+    ConcurrencyHttpError {
+        status,
+        error,
+        limit: err.limit as u64,
+        in_flight: err.in_flight as u64,
+    }
+}
+
+// global and route caps protect the gateway itself -> 503,
+// the ip cap is the client's own fault -> 429
+fn acquire_concurrency(
+    state: &AppState,
+    route: &str,
+    ip: IpAddr,
+) -> Result<ConcurrencyPermits, ConcurrencyHttpError> {
+    let global = state.global_concurrency.try_acquire(()).map_err(|err| {
+        inc_concurrency_rejected(state, &state.metrics.global_concurrency_rejected);
+        tracing::warn!(limiter = "global_concurrency", decision = "denied");
+        concurrency_http_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "global_concurrency_limited",
+            err,
+        )
+    })?;
+
+    let route = state
+        .route_concurrency
+        .try_acquire(route.to_string())
+        .map_err(|err| {
+            inc_concurrency_rejected(state, &state.metrics.route_concurrency_rejected);
+            tracing::warn!(limiter = "route_concurrency", decision = "denied");
+            concurrency_http_error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "route_concurrency_limited",
+                err,
+            )
+        })?;
+
+    let ip = state.ip_concurrency.try_acquire(ip).map_err(|err| {
+        inc_concurrency_rejected(state, &state.metrics.ip_concurrency_rejected);
+        tracing::warn!(limiter = "ip_concurrency", decision = "denied");
+        concurrency_http_error(StatusCode::TOO_MANY_REQUESTS, "ip_concurrency_limited", err)
+    })?;
+
+    Ok((global, route, ip))
+}
+
+// keeps the queue depth gauge right even if the client goes away mid-wait
+struct QueueDepthGuard<'a>(&'a AtomicU64);
+
+impl<'a> QueueDepthGuard<'a> {
+    fn new(gauge: &'a AtomicU64) -> Self {
+        gauge.fetch_add(1, Ordering::Relaxed);
+        Self(gauge)
+    }
+}
+
+impl Drop for QueueDepthGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// order of the tiers in the composite check
+const GLOBAL_TIER: usize = 0;
+const ROUTE_TIER: usize = 1;
+const IP_TIER: usize = 2;
+const TIERS: [usize; 3] = [GLOBAL_TIER, ROUTE_TIER, IP_TIER];
+pub const TIER_NAMES: [&str; 3] = ["global", "route", "ip"];
+
+// Keys of the route and ip tiers, rendered from their templates once per request.
+// Overrides match the route name and the client address, never the rendered key,
+// which can carry any header or query value the client sends
+pub struct TierKeys {
+    pub route: ResolvedKey<String>,
+    pub ip: ResolvedKey<String>,
+}
+
+// a template without a value for the request falls back to the tier's own key
+pub fn tier_keys(
+    state: &AppState,
+    req: &Request<Body>,
+    full_path: &str,
+    route: &str,
+    ip: IpAddr,
+) -> TierKeys {
+    let context = KeyContext::new(req)
+        .with_ip(ip)
+        .with_route(route)
+        .with_path(full_path);
+
+    TierKeys {
+        route: ResolvedKey::new(
+            state
+                .config
+                .route_key_template
+                .render(&context)
+                .unwrap_or_else(|| format!("route:{route}")),
+            state.route_overrides.lookup(&route.to_string()),
+        ),
+        ip: ResolvedKey::new(
+            state
+                .config
+                .ip_key_template
+                .render(&context)
+                .unwrap_or_else(|| format!("ip:{}", ip.to_canonical())),
+            state.ip_overrides.lookup(&ip),
+        ),
+    }
+}
+
+// the global tier has a single key, its top keys are the client addresses spending it
+fn record_top_keys(
+    state: &AppState,
+    keys: &TierKeys,
+    ip: IpAddr,
+    decision: Decision,
+    tiers: &[usize],
+) {
+    let Some(top_keys) = &state.top_keys else {
+        return;
+    };
+
+    for tier in tiers {
+        match *tier {
+            GLOBAL_TIER => top_keys[GLOBAL_TIER].record(&ip.to_canonical().to_string(), decision),
+            ROUTE_TIER => top_keys[ROUTE_TIER].record(&keys.route.key, decision),
+            _ => top_keys[IP_TIER].record(&keys.ip.key, decision),
+        }
+    }
+}
+
+fn is_dry_run(state: &AppState, tier: usize) -> bool {
+    match tier {
+        GLOBAL_TIER => state.config.global_dry_run,
+        ROUTE_TIER => state.config.route_dry_run,
+        _ => state.config.ip_dry_run,
+    }
+}
+
+// evaluates one tier on its own, outside the composite check
+fn check_tier(
+    state: &AppState,
+    tier: usize,
+    keys: &TierKeys,
+    cost: u128,
+    now: Instant,
+) -> Result<BucketState, RateLimitError> {
+    match tier {
+        GLOBAL_TIER => state.global_limiter.check_with_cost((), now, cost),
+        ROUTE_TIER => state
+            .route_limiter
+            .check_with_cost(keys.route.clone(), now, cost),
+        _ => state.ip_limiter.check_with_cost(keys.ip.clone(), now, cost),
+    }
+}
+
+// None when the tier has no candidate policy configured
+fn check_shadow(
+    state: &AppState,
+    tier: usize,
+    keys: &TierKeys,
+    cost: u128,
+    now: Instant,
+) -> Option<Result<BucketState, RateLimitError>> {
+    match tier {
+        GLOBAL_TIER => state
+            .global_shadow
+            .as_ref()
+            .map(|shadow| shadow.check_with_cost((), now, cost)),
+        ROUTE_TIER => state
+            .route_shadow
+            .as_ref()
+            .map(|shadow| shadow.check_with_cost(keys.route.key.clone(), now, cost)),
+        _ => state
+            .ip_shadow
+            .as_ref()
+            .map(|shadow| shadow.check_with_cost(keys.ip.key.clone(), now, cost)),
+    }
+}
+
+fn inc_dry_run_denied(state: &AppState, tier: usize) {
+    let counter = match tier {
+        GLOBAL_TIER => &state.metrics.dry_run_global_denied,
+        ROUTE_TIER => &state.metrics.dry_run_route_denied,
+        _ => &state.metrics.dry_run_ip_denied,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+fn inc_shadow_denied(state: &AppState, tier: usize) {
+    let counter = match tier {
+        GLOBAL_TIER => &state.metrics.shadow_global_denied,
+        ROUTE_TIER => &state.metrics.shadow_route_denied,
+        _ => &state.metrics.shadow_ip_denied,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+// lists the tiers that would have denied, e.g. "global,ip"
+fn attach_would_deny_header(response: &mut Response<Body>, name: &'static str, tiers: &[&str]) {
+    if tiers.is_empty() {
+        return;
+    }
+    response.headers_mut().insert(
+        HeaderName::from_static(name),
+        HeaderValue::from_str(&tiers.join(",")).unwrap(),
+    );
+}
+
+// Dry-run tiers are charged like enforced ones so their headers show what
+// enforcement would look like, a would-be denial is only logged and counted
+fn observe_dry_run(
+    state: &AppState,
+    keys: &TierKeys,
+    cost: u128,
+    now: Instant,
+    snapshots: &mut Vec<BucketState>,
+) -> Vec<&'static str> {
+    let mut denied = Vec::new();
+
+    for tier in TIERS.into_iter().filter(|tier| is_dry_run(state, *tier)) {
+        match check_tier(state, tier, keys, cost, now) {
+            Ok(snapshot) => snapshots.push(snapshot),
+            Err(err) => {
+                inc_dry_run_denied(state, tier);
+                tracing::warn!(limiter = TIER_NAMES[tier], decision = "dry_run_denied");
+                denied.push(TIER_NAMES[tier]);
+                snapshots.push(err.snapshot);
+            }
+        }
+    }
+
+    denied
+}
+
+// gives back what an allowed request was charged, dry-run tiers in `uncharged`
+// (they would have denied, or were never checked) were never charged
+fn refund_tiers(state: &AppState, keys: &TierKeys, ip: IpAddr, cost: u128, uncharged: &[&str]) {
+    let now = state.clock.now();
+
+    for tier in TIERS {
+        if is_dry_run(state, tier) && uncharged.contains(&TIER_NAMES[tier]) {
+            continue;
+        }
+        match tier {
+            GLOBAL_TIER => state.global_limiter.refund(&(), now, cost),
+            ROUTE_TIER => state.route_limiter.refund(&keys.route, now, cost),
+            _ => state.ip_limiter.refund(&keys.ip, now, cost),
+        }
+    }
+
+    if let Some(quota) = &state.quota_limiter {
+        quota.refund(&ip, cost);
+    }
+}
+
+// candidate policies never touch the response headers besides the would-deny list
+fn observe_shadow(
+    state: &AppState,
+    keys: &TierKeys,
+    cost: u128,
+    now: Instant,
+) -> Vec<&'static str> {
+    let mut denied = Vec::new();
+
+    for tier in TIERS {
+        if let Some(Err(err)) = check_shadow(state, tier, keys, cost, now) {
+            inc_shadow_denied(state, tier);
+            tracing::warn!(limiter = TIER_NAMES[tier], decision = "shadow_denied");
+            denied.push(TIER_NAMES[tier]);
+        }
+    }
+
+    denied
+}
+
+// a place in one tier's queue, the key type differs per tier
+enum TierSlot {
+    Global(QueueSlot<()>),
+    Keyed(QueueSlot<String>),
+}
+
+impl TierSlot {
+    async fn wait(&mut self) {
+        match self {
+            TierSlot::Global(slot) => slot.wait().await,
+            TierSlot::Keyed(slot) => slot.wait().await,
+        }
+    }
+}
+
+fn enqueue_tier(
+    state: &AppState,
+    tier: usize,
+    keys: &TierKeys,
+    retry_after: Duration,
+) -> Result<TierSlot, ShapeError> {
+    Ok(match tier {
+        GLOBAL_TIER => TierSlot::Global(state.global_shaper.enqueue((), retry_after)?),
+        ROUTE_TIER => TierSlot::Keyed(
+            state
+                .route_shaper
+                .enqueue(keys.route.key.clone(), retry_after)?,
+        ),
+        _ => TierSlot::Keyed(state.ip_shaper.enqueue(keys.ip.key.clone(), retry_after)?),
+    })
+}
+
+// the first enforced tier that already has requests queued for this request's key
+fn queued_tier(state: &AppState, enforced: &[usize], keys: &TierKeys) -> Option<usize> {
+    enforced.iter().copied().find(|tier| match *tier {
+        GLOBAL_TIER => state.global_shaper.depth(&()) > 0,
+        ROUTE_TIER => state.route_shaper.depth(&keys.route.key) > 0,
+        _ => state.ip_shaper.depth(&keys.ip.key) > 0,
+    })
+}
+
+fn peek_tier(state: &AppState, tier: usize, keys: &TierKeys, now: Instant) -> BucketState {
+    match tier {
+        GLOBAL_TIER => state.global_limiter.peek(&(), now),
+        ROUTE_TIER => state.route_limiter.peek(&keys.route, now),
+        _ => state.ip_limiter.peek(&keys.ip, now),
+    }
+}
+
+// all tiers are checked as one unit so a tier that denies doesn't leave the
+// others charged. In shaping mode a denied request joins the FIFO queue of the
+// tier that denied it, only the head of the queue checks again and sleeps until
+// the tiers refill, so requests leave in order at the refill rate. It only gets
+// the 429 when the queue is full or it would wait longer than the max wait
+async fn check_tiers(
+    state: &AppState,
+    keys: &TierKeys,
+    cost: u128,
+) -> Result<Vec<BucketState>, TierDenied> {
+    // dry-run tiers stay out of the composite, they can never deny
+    let enforced: Vec<usize> = TIERS
+        .into_iter()
+        .filter(|tier| !is_dry_run(state, *tier))
+        .collect();
+
+    let check = |now| {
+        enforced
+            .iter()
+            .fold(CompositeCheck::new(), |composite, tier| match *tier {
+                GLOBAL_TIER => composite.tier(&state.global_limiter, ()),
+                ROUTE_TIER => composite.tier(&state.route_limiter, keys.route.clone()),
+                _ => composite.tier(&state.ip_limiter, keys.ip.clone()),
+            })
+            .check(now, cost)
+            .map_err(|mut denied| {
+                denied.tier = enforced[denied.tier];
+                denied
+            })
+    };
+
+    let now = state.clock.now();
+    // every tier's shaper is built with the same max wait
+    let max_wait = state.global_shaper.max_wait();
+    let queued = match state.config.shaping_enabled {
+        true => queued_tier(state, &enforced, keys),
+        false => None,
+    };
+
+    let mut denied = match queued {
+        // others are already waiting on the key, the next token is theirs.
+        // Whoever gets in drains the queue ahead within the max wait
+        Some(tier) => TierDenied {
+            tier,
+            error: RateLimitError {
+                kind: RateLimitErrorKind::Limited,
+                retry_after: max_wait,
+                snapshot: peek_tier(state, tier, keys, now),
+            },
+        },
+        None => match check(now) {
+            Ok(snapshots) => return Ok(snapshots),
+            // waiting only helps a limited request, not one that costs more than
+            // the bucket holds or one denied because the store is down
+            Err(denied)
+                if !state.config.shaping_enabled
+                    || denied.error.kind != RateLimitErrorKind::Limited =>
+            {
+                return Err(denied);
+            }
+            Err(denied) => denied,
+        },
+    };
+
+    let mut slot = match enqueue_tier(state, denied.tier, keys, denied.error.retry_after) {
+        Ok(slot) => slot,
+        Err(reason) => {
+            state
+                .metrics
+                .shaping_rejected
+                .fetch_add(1, Ordering::Relaxed);
+            tracing::debug!(?reason, "shaping queue rejected request");
+            return Err(denied);
+        }
+    };
+
+    state
+        .metrics
+        .shaped_requests
+        .fetch_add(1, Ordering::Relaxed);
+    let _depth = QueueDepthGuard::new(&state.metrics.shaping_queue_depth);
+    let started = state.clock.now();
+    let elapsed = || state.clock.now().saturating_duration_since(started);
+
+    let result = async {
+        // everyone queued earlier leaves first
+        if tokio::time::timeout(max_wait, slot.wait()).await.is_err() {
+            tracing::debug!(reason = ?ShapeError::WaitTooLong, "shaping wait exceeded");
+            return Err(denied);
+        }
+
+        loop {
+            match check(state.clock.now()) {
+                Ok(snapshots) => return Ok(snapshots),
+                Err(next) => denied = next,
+            }
+            if denied.error.kind != RateLimitErrorKind::Limited {
+                return Err(denied);
+            }
+            if elapsed().saturating_add(denied.error.retry_after) > max_wait {
+                tracing::debug!(reason = ?ShapeError::WaitTooLong, "shaping wait exceeded");
+                return Err(denied);
+            }
+
+            tokio::time::sleep(denied.error.retry_after.max(MIN_SHAPING_SLEEP)).await;
+        }
+    }
+    .await;
+
+    state
+        .metrics
+        .shaping_wait_ms_total
+        .fetch_add(elapsed().as_millis() as u64, Ordering::Relaxed);
+    if result.is_err() {
+        state
+            .metrics
+            .shaping_rejected
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    // the slot is dropped on return, the next request in line starts checking
+    result
+}
+
 pub async fn rate_limit_middleware(
     State(state): State<AppState>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
@@ -92,76 +645,166 @@ pub async fn rate_limit_middleware(
     state.metrics.total_requests.fetch_add(1, Ordering::Relaxed);
 
     let ip = addr.ip();
-    let route = path
-        .trim_start_matches("/api/")
+
+    // the cost table is keyed by the full path, before the /api nest strips it
+    let full_path = req
+        .extensions()
+        .get::<OriginalUri>()
+        .map(|uri| uri.path())
+        .unwrap_or(path);
+
+    // first segment under /api, taken from the full path since the nest
+    // already stripped the prefix from `path`
+    let route = full_path
+        .strip_prefix("/api/")
+        .unwrap_or(full_path.trim_start_matches('/'))
         .split('/')
         .next()
+        .filter(|segment| !segment.is_empty())
         .unwrap_or("root")
         .to_string();
 
-    let now = Instant::now();
+    let cost = state.config.route_cost(req.method().as_str(), full_path);
+    let keys = tier_keys(&state, &req, full_path, &route, ip);
 
     let span = tracing::info_span!(
         "request",
         ip = %ip,
-        route = %route
+        route = %route,
+        cost = cost
     );
-    let _enter = span.enter();
+    // the span follows the request across the shaping wait
+    limit_request(state, ip, route, cost, keys, req, next)
+        .instrument(span)
+        .await
+}
 
-    let global_snapshot = match state.global_limiter.check((), now) {
-        Ok(snapshot) => snapshot,
-        Err(err) => {
-            inc_global_limit(&state);
-            tracing::warn!(limiter = "global", decision = "denied");
+async fn limit_request(
+    state: AppState,
+    ip: IpAddr,
+    route: String,
+    cost: u128,
+    keys: TierKeys,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    // the quota goes first so an exhausted client never touches the rate limit buckets
+    let quota = match state
+        .quota_limiter
+        .as_ref()
+        .map(|quota| quota.check(ip, cost))
+    {
+        None => None,
+        Some(Ok(snapshot)) => Some(snapshot),
+        Some(Err(err)) => {
+            state
+                .metrics
+                .total_rate_limited
+                .fetch_add(1, Ordering::Relaxed);
+            state.metrics.quota_exceeded.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!(limiter = "quota", decision = "denied");
             let (mut response, snapshot) = build_rate_limit_response(err);
             attach_headers(&mut response, &snapshot);
+            attach_quota_headers(&mut response, &state, &snapshot);
+            attach_cost_header(&mut response, cost);
             return response;
         }
     };
 
-    let route_snapshot = match state.route_limiter.check(route, now) {
-        Ok(snapshot) => snapshot,
-        Err(err) => {
-            inc_route_limit(&state);
-            tracing::warn!(limiter = "route", decision = "denied");
-            let (mut response, snapshot) = build_rate_limit_response(err);
+    let mut snapshots = match check_tiers(&state, &keys, cost).await {
+        Ok(snapshots) => snapshots,
+        Err(denied) => {
+            record_top_keys(&state, &keys, ip, Decision::Denied, &[denied.tier]);
+            // a rate limited request doesn't count against the quota
+            if let Some(quota) = &state.quota_limiter {
+                quota.refund(&ip, cost);
+            }
+
+            let limiter = match denied.tier {
+                GLOBAL_TIER => {
+                    inc_global_limit(&state);
+                    "global"
+                }
+                ROUTE_TIER => {
+                    inc_route_limit(&state);
+                    "route"
+                }
+                _ => {
+                    inc_ip_limit(&state);
+                    "ip"
+                }
+            };
+            tracing::warn!(limiter, decision = "denied");
+            let (mut response, snapshot) = build_rate_limit_response(denied.error);
             attach_headers(&mut response, &snapshot);
+            attach_cost_header(&mut response, cost);
             return response;
         }
     };
 
-    let ip_snapshot = match state.ip_limiter.check(ip, now) {
-        Ok(snapshot) => {
-            tracing::info!(decision = "allowed");
-            snapshot
-        }
+    // in-flight caps are taken once the tiers let the request through, so a
+    // request waiting in a shaping queue doesn't hold them. One they reject gets
+    // its charge back, the dry-run tiers haven't been checked yet
+    let permits = match acquire_concurrency(&state, &route, ip) {
+        Ok(permits) => permits,
         Err(err) => {
-            inc_ip_limit(&state);
-            tracing::warn!(limiter = "ip", decision = "denied");
-            let (mut response, snapshot) = build_rate_limit_response(err);
-            attach_headers(&mut response, &snapshot);
-            return response;
+            let unchecked: Vec<&str> = TIERS
+                .into_iter()
+                .filter(|tier| is_dry_run(&state, *tier))
+                .map(|tier| TIER_NAMES[tier])
+                .collect();
+            refund_tiers(&state, &keys, ip, cost, &unchecked);
+            return err.into_response();
         }
     };
+    tracing::info!(decision = "allowed");
+    record_top_keys(&state, &keys, ip, Decision::Allowed, &TIERS);
+
+    if let Some(quota) = &quota {
+        snapshots.push(BucketState {
+            limit: quota.limit,
+            remaining: quota.remaining,
+            reset_after: quota.reset_after,
+        });
+    }
+
+    let now = state.clock.now();
+    let dry_run_denied = observe_dry_run(&state, &keys, cost, now, &mut snapshots);
+    let shadow_denied = observe_shadow(&state, &keys, cost, now);
 
     state.metrics.total_allowed.fetch_add(1, Ordering::Relaxed);
 
     let mut response = next.run(req).await;
 
+    // the upstream failed, don't bill the client for it
+    if state.config.refund_on_upstream_error && response.status().is_server_error() {
+        refund_tiers(&state, &keys, ip, cost, &dry_run_denied);
+        state
+            .metrics
+            .refunded_requests
+            .fetch_add(1, Ordering::Relaxed);
+        tracing::debug!(status = %response.status(), "charge refunded");
+    }
+
     // attach the header of the snapshot that have least remaining
-    let effective_snapshot = {
-        let mut snapshots = [&global_snapshot, &route_snapshot, &ip_snapshot];
-        snapshots.sort_by_key(|s| s.remaining);
-        snapshots[0]
-    };
+    let effective_snapshot = snapshots.iter().min_by_key(|s| s.remaining).unwrap();
 
     //add effective snapshot headers
     attach_headers(&mut response, effective_snapshot);
+    attach_cost_header(&mut response, cost);
+    if let Some(quota) = &quota {
+        attach_quota_headers(&mut response, &state, quota);
+    }
+    attach_would_deny_header(&mut response, "x-ratelimit-dry-run-denied", &dry_run_denied);
+    attach_would_deny_header(&mut response, "x-ratelimit-shadow-denied", &shadow_denied);
+
+    // permits are released once the body has been fully streamed (or dropped)
+    let response = response.map(|body| Body::new(PermitBody::new(body, permits)));
 
     tracing::info!(
         limiter = "all",
         decision = "allowed",
         remaining = effective_snapshot.remaining
     );
-    return response;
+    response
 }